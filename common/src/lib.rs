@@ -0,0 +1,139 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::io::{Read, Write};
+
+/// Gzips a WAL payload for storage.
+pub fn compress_payload(payload: &str) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload.as_bytes())?;
+    encoder.finish()
+}
+
+/// Restores a WAL payload stored by `compress_payload`.
+pub fn decompress_payload(bytes: &[u8]) -> std::io::Result<String> {
+    let mut payload = String::new();
+    GzDecoder::new(bytes).read_to_string(&mut payload)?;
+    Ok(payload)
+}
+
+/// Order of the fields of a JSON object payload, and so of the Parquet
+/// columns they add, from `FIELD_ORDER`. The querier and the persister apply
+/// the same one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FieldOrder {
+    /// Sorted by name, whatever order the client sent them in.
+    #[default]
+    Alphabetical,
+    /// The order of the keys in the payload.
+    AsReceived,
+}
+
+impl FieldOrder {
+    pub fn parse(s: &str) -> Result<FieldOrder, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "alphabetical" => Ok(FieldOrder::Alphabetical),
+            "as_received" => Ok(FieldOrder::AsReceived),
+            other => Err(format!("FIELD_ORDER: expected 'alphabetical' or 'as_received', got '{}'", other)),
+        }
+    }
+
+    /// Puts fields keyed by name in this order.
+    pub fn apply<T>(self, fields: &mut [(String, T)]) {
+        if self == FieldOrder::Alphabetical {
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+    }
+}
+
+/// A payload value that is not a number.
+#[derive(Debug, PartialEq)]
+pub struct InvalidValue(pub String);
+
+impl std::fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to parse value '{}'", self.0)
+    }
+}
+
+impl std::error::Error for InvalidValue {}
+
+/// Parses a payload as a JSON array of numbers, such as `[1, 2.5]`, or else as
+/// comma-separated numbers, such as `1, 2.5`.
+pub fn parse_payload(s: &str) -> Result<Vec<f64>, InvalidValue> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(vec![]);
+    }
+    if s.starts_with('[') {
+        if let Ok(values) = serde_json::from_str::<Vec<serde_json::Value>>(s) {
+            return values.iter()
+                .map(|v| v.as_f64().ok_or_else(|| InvalidValue(v.to_string())))
+                .collect();
+        }
+    }
+    s.split(',')
+        .map(|f| f.trim())
+        .map(|f| f.parse::<f64>().map_err(|_| InvalidValue(f.to_string())))
+        .collect()
+}
+
+/// Parses a payload of a JSON object of numbers, such as `{"cpu": 0.5}`, into
+/// its values and their field names in the field order, or else any other
+/// payload into unnamed values. This is how the persister reads WAL payloads.
+pub fn parse_fields(s: &str, order: FieldOrder) -> Result<(Vec<f64>, Option<Vec<String>>), InvalidValue> {
+    if s.trim_start().starts_with('{') {
+        if let Ok(fields) = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(s) {
+            let mut fields: Vec<(String, serde_json::Value)> = fields.into_iter().collect();
+            order.apply(&mut fields);
+            let mut values = vec![];
+            for (_, value) in &fields {
+                values.push(value.as_f64().ok_or_else(|| InvalidValue(value.to_string()))?);
+            }
+            return Ok((values, Some(fields.into_iter().map(|(name, _)| name).collect())));
+        }
+    }
+    Ok((parse_payload(s)?, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_round_trip() {
+        let payload = "1.5, 2.5, 3.5, ".repeat(100);
+        let compressed = compress_payload(&payload).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decompress_payload(&compressed).unwrap(), payload);
+
+        assert!(decompress_payload(b"not gzip").is_err());
+    }
+
+    #[test]
+    fn test_parse_payload() {
+        assert_eq!(parse_payload("[1,2,3]").unwrap(), vec![1.0, 2.0, 3.0]);
+        assert_eq!(parse_payload(" [1.5, -2e3] ").unwrap(), vec![1.5, -2000.0]);
+        assert_eq!(parse_payload("1, 2, 3").unwrap(), vec![1.0, 2.0, 3.0]);
+        assert_eq!(parse_payload("1,2,3").unwrap(), vec![1.0, 2.0, 3.0]);
+        assert_eq!(parse_payload("  ").unwrap(), Vec::<f64>::new());
+        assert_eq!(parse_payload("1,x,3"), Err(InvalidValue("x".to_string())));
+        assert_eq!(parse_payload(r#"[1, "two"]"#), Err(InvalidValue(r#""two""#.to_string())));
+        assert_eq!(parse_payload("[1, 2"), Err(InvalidValue("[1".to_string())));
+    }
+
+    #[test]
+    fn test_field_order() {
+        let fields = || vec![("mem".to_string(), 1), ("cpu".to_string(), 2)];
+        let mut sorted = fields();
+        FieldOrder::Alphabetical.apply(&mut sorted);
+        assert_eq!(sorted, vec![("cpu".to_string(), 2), ("mem".to_string(), 1)]);
+        let mut received = fields();
+        FieldOrder::AsReceived.apply(&mut received);
+        assert_eq!(received, fields());
+
+        assert_eq!(FieldOrder::parse("As_Received").unwrap(), FieldOrder::AsReceived);
+        assert!(FieldOrder::parse("random").unwrap_err().starts_with("FIELD_ORDER"));
+    }
+}
@@ -1,76 +1,825 @@
 use chrono::{Utc, DateTime};
+use common::{parse_fields, FieldOrder, InvalidValue};
 
-use duckdb::{params, Connection, Result};
+use duckdb::types::{TimeUnit, Value};
+use duckdb::{appender_params_from_iter, params, Connection};
 
 use itertools::Itertools;
 
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
 use sqlx::Row;
 use sqlx::sqlite::SqlitePool;
 
+use std::collections::HashMap;
 use std::env;
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use fs2::FileExt;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PersisterError {
+    #[error("no records to merge")]
+    NoRecords,
+    #[error("path is not valid UTF-8: {0:?}")]
+    InvalidPath(PathBuf),
+    #[error("WAL row {row_id}: failed to parse value '{value}'")]
+    PayloadParse { row_id: String, value: String },
+    #[error("failed to parse value '{0}'")]
+    InvalidValue(String),
+    #[error("WAL row {row_id}: invalid time '{time}'")]
+    InvalidTime { row_id: String, time: String },
+    #[error("WAL row {row_id}: payload has no values")]
+    EmptyPayload { row_id: String },
+    #[error("WAL row {row_id}: failed to decompress the payload: {source}")]
+    Decompress { row_id: String, source: std::io::Error },
+    #[error("WAL row {row_id}: payload does not match '{pattern}'")]
+    PayloadMismatch { row_id: String, pattern: String },
+    #[error("failed to move the merged file into {path:?}: {source}")]
+    Rename { path: PathBuf, source: std::io::Error },
+    #[error("refusing to overwrite {path:?}: it belongs to identity {found:?}")]
+    UnexpectedFile { path: PathBuf, found: Option<String> },
+    #[error("failed to stamp {path:?} with its identity: {source}")]
+    Stamp { path: PathBuf, source: std::io::Error },
+    #[error("not ready: {0}")]
+    NotReady(String),
+    #[error("failed to create the directory of {path:?}: {source}")]
+    CreateDir { path: PathBuf, source: std::io::Error },
+    #[error("record at {time} has more than one field named {name:?}")]
+    DuplicateField { time: DateTime<Utc>, name: String },
+    #[error("failed to lock {path:?}: {source}")]
+    Lock { path: PathBuf, source: std::io::Error },
+    #[error(transparent)]
+    DuckDb(#[from] duckdb::Error),
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+}
+
+pub type Result<T, E = PersisterError> = std::result::Result<T, E>;
+
+#[derive(Clone)]
 pub struct Record {
     pub destination: String,
     pub time: DateTime<Utc>,
     pub values: Vec<f64>,
+    /// Column name of each value; values without one are written to `fN`
+    /// after their position.
+    pub field_names: Option<Vec<String>>,
+    /// The producer the querier tagged the WAL row with, written to a text
+    /// `source` column beside `time`.
+    pub source: Option<String>,
+}
+
+impl Record {
+    fn field_name(&self, i: usize) -> String {
+        self.field_names.as_ref().and_then(|names| names.get(i)).cloned().unwrap_or_else(|| format!("f{}", i))
+    }
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Statements installing and loading `parquet` followed by each configured
+/// DuckDB extension.
+fn extension_statements(extensions: &[String]) -> Vec<(String, String)> {
+    let mut names = vec!["parquet"];
+    for e in extensions {
+        if !names.contains(&e.as_str()) {
+            names.push(e);
+        }
+    }
+    names.into_iter()
+        .map(|name| (name.to_string(), format!("INSTALL {}; LOAD {};", name, name)))
+        .collect()
 }
 
-pub fn merge_new_records(parquet_path: &str, new_records: Vec<Record>) -> Result<()> {
+fn open_connection(extensions: &[String]) -> Result<Connection> {
     let conn = Connection::open_in_memory()?;
-    conn.execute_batch("INSTALL parquet; LOAD parquet;")?;
+    for (name, sql) in extension_statements(extensions) {
+        if let Err(e) = conn.execute_batch(&sql) {
+            log::error!(extension = name.as_str(), error:% = e; "failed to load a DuckDB extension");
+            return Err(e.into());
+        }
+    }
+    Ok(conn)
+}
 
-    let fields =  match new_records.get(0) {
-        Some(first) => {
-            first.values.iter().fold(0, |acc, _| acc + 1)
-        },
-        None => {
-            // TODO must return an error
-            return Ok(());
+/// How destinations are opened and written.
+#[derive(Clone, Debug, Default)]
+pub struct MergeOptions {
+    /// DuckDB extensions loaded in addition to `parquet`.
+    pub extensions: Vec<String>,
+    /// How long to wait for another process to release a destination.
+    pub lock_timeout: Duration,
+    /// Directory receiving a DuckDB JSON profile for each merge.
+    pub profile_dir: Option<PathBuf>,
+    /// Logical type of the written `time` column.
+    pub timestamp_unit: TimestampUnit,
+    /// Codec compressing the written Parquet column chunks.
+    pub compression: Compression,
+    /// What happens to a record whose time is already present.
+    pub mode: MergeMode,
+    /// Identity stamped beside every written destination; when set, a merge
+    /// refuses to overwrite an existing file stamped with any other identity
+    /// or not stamped at all.
+    pub expected_identity: Option<String>,
+    /// Whether destinations are directories partitioned by `time`.
+    pub partition_by_date: bool,
+    /// Span of `time` each partition of a partitioned destination holds.
+    pub partition_granularity: PartitionGranularity,
+    /// Granularity of each project whose destinations are partitioned,
+    /// whether or not `partition_by_date` is set.
+    pub project_partitions: HashMap<String, PartitionGranularity>,
+    /// Most value columns new records may widen a destination to; values
+    /// that would need a column beyond it are dropped.
+    pub max_schema_width: Option<usize>,
+    /// What happens to a record with two fields of the same name.
+    pub duplicate_fields: DuplicateFields,
+    /// Whether destinations are a project's wide table, whose rows join the
+    /// records of all its schemas on `time` in columns named
+    /// `<schema>_<field>`.
+    pub wide: bool,
+}
+
+impl MergeOptions {
+    /// The granularity the destination, `<project>/<schema>`, is partitioned
+    /// at, if it is partitioned.
+    fn partitioning(&self, destination: &str) -> Option<PartitionGranularity> {
+        let project = Path::new(destination).iter().next().and_then(|p| p.to_str()).unwrap_or_default();
+        match self.project_partitions.get(project) {
+            Some(&granularity) => Some(granularity),
+            None => self.partition_by_date.then_some(self.partition_granularity),
+        }
+    }
+
+    /// These options with the destination's partitioning.
+    fn for_destination(&self, destination: &str) -> MergeOptions {
+        let partitioning = self.partitioning(destination);
+        MergeOptions {
+            partition_by_date: partitioning.is_some(),
+            partition_granularity: partitioning.unwrap_or(self.partition_granularity),
+            ..self.clone()
+        }
+    }
+}
+
+/// Destination of a project's records when `WIDE_TABLE` is enabled.
+const WIDE_TABLE: &str = "wide";
+
+/// Joins the records of each destination that share a time into one, with
+/// the values of all of them; a later value of a column replaces an earlier
+/// one.
+fn join_wide_records(records: Vec<Record>) -> Vec<Record> {
+    let mut joined: Vec<Record> = vec![];
+    let mut rows: HashMap<(String, DateTime<Utc>), usize> = HashMap::new();
+    for record in records {
+        let Some(&i) = rows.get(&(record.destination.clone(), record.time)) else {
+            rows.insert((record.destination.clone(), record.time), joined.len());
+            joined.push(record);
+            continue;
+        };
+        let row = &mut joined[i];
+        let mut names: Vec<String> = (0..row.values.len()).map(|i| row.field_name(i)).collect();
+        for (j, v) in record.values.iter().enumerate() {
+            let name = record.field_name(j);
+            match names.iter().position(|n| n.eq_ignore_ascii_case(&name)) {
+                Some(k) => row.values[k] = *v,
+                None => {
+                    names.push(name);
+                    row.values.push(*v);
+                }
+            }
+        }
+        row.field_names = Some(names);
+        if record.source.is_some() {
+            row.source = record.source;
+        }
+    }
+    joined
+}
+
+/// How a merge treats a record whose field names repeat, ignoring case.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicateFields {
+    /// The merge fails.
+    #[default]
+    Error,
+    /// Each repeat is renamed `<name>_1`, `<name>_2` and so on.
+    Suffix,
+}
+
+/// Applies the policy to the field names of each record, so no two values of
+/// a record land in the same column.
+fn resolve_duplicate_fields(records: Vec<Record>, policy: DuplicateFields) -> Result<Vec<Record>> {
+    records.into_iter().map(|mut record| {
+        let Some(names) = record.field_names.take() else {
+            return Ok(record);
+        };
+        // The first field of each name keeps it, and a repeat takes the
+        // first suffix no field of the record has.
+        let taken = |n: &str, names: &[Option<String>]| names.iter().flatten().any(|r| r.eq_ignore_ascii_case(n));
+        let mut resolved: Vec<Option<String>> = vec![];
+        for name in &names {
+            resolved.push((!taken(name, &resolved)).then(|| name.clone()));
+        }
+        for (i, name) in names.iter().enumerate() {
+            if resolved[i].is_some() {
+                continue;
+            }
+            if policy == DuplicateFields::Error {
+                return Err(PersisterError::DuplicateField { time: record.time, name: name.clone() });
+            }
+            resolved[i] = (1..).map(|n| format!("{}_{}", name, n)).find(|n| !taken(n, &resolved));
+        }
+        record.field_names = Some(resolved.into_iter().flatten().collect());
+        Ok(record)
+    }).collect()
+}
+
+fn identity_path(parquet_path: &str) -> String {
+    format!("{}.identity", parquet_path)
+}
+
+/// Fails unless the existing destination carries `expected`'s stamp.
+fn check_identity(parquet_path: &str, expected: &str) -> Result<()> {
+    if !Path::new(parquet_path).exists() {
+        return Ok(());
+    }
+    let found = std::fs::read_to_string(identity_path(parquet_path)).ok().map(|s| s.trim().to_string());
+    if found.as_deref() == Some(expected) {
+        Ok(())
+    } else {
+        Err(PersisterError::UnexpectedFile { path: PathBuf::from(parquet_path), found })
+    }
+}
+
+/// How a merge treats a record with the same `time` as an existing row or as
+/// another new record.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MergeMode {
+    /// The latest record wins.
+    #[default]
+    Replace,
+    /// The existing row, or the earliest new record, wins.
+    Skip,
+    /// The merge fails.
+    Error,
+}
+
+impl MergeMode {
+    /// Statement moving the staged records into the table. In a `wide` table
+    /// a record and the row of its time are two parts of the same row, so
+    /// the winner only takes the columns it has a value in from the other.
+    fn insert_sql(self, table: &str, staging: &str, columns: &[String], wide: bool) -> String {
+        let dedup = |order: &str| format!(
+            "INSERT INTO {} SELECT * FROM {} QUALIFY row_number() OVER (PARTITION BY time ORDER BY rowid {}) = 1",
+            table, staging, order,
+        );
+        let update = |value: &dyn Fn(&String) -> String| columns.iter().map(|c| format!("{} = {}", c, value(c))).join(", ");
+        match self {
+            MergeMode::Replace if !columns.is_empty() && wide => format!(
+                "{} ON CONFLICT (time) DO UPDATE SET {}",
+                dedup("DESC"),
+                update(&|c| format!("COALESCE(excluded.{}, {})", c, c)),
+            ),
+            MergeMode::Replace if !columns.is_empty() => format!(
+                "{} ON CONFLICT (time) DO UPDATE SET {}",
+                dedup("DESC"),
+                update(&|c| format!("excluded.{}", c)),
+            ),
+            MergeMode::Skip if !columns.is_empty() && wide => format!(
+                "{} ON CONFLICT (time) DO UPDATE SET {}",
+                dedup("ASC"),
+                update(&|c| format!("COALESCE({}, excluded.{})", c, c)),
+            ),
+            MergeMode::Replace | MergeMode::Skip => format!("{} ON CONFLICT DO NOTHING", dedup("ASC")),
+            MergeMode::Error => format!("INSERT INTO {} SELECT * FROM {}", table, staging),
+        }
+    }
+}
+
+/// Unit of the `time` column in written Parquet files, emitted as the
+/// `TIMESTAMP_MICROS` or `TIMESTAMP_MILLIS` logical type.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimestampUnit {
+    #[default]
+    Micros,
+    Millis,
+}
+
+impl TimestampUnit {
+    fn column_type(self) -> &'static str {
+        match self {
+            TimestampUnit::Micros => "TIMESTAMP",
+            TimestampUnit::Millis => "TIMESTAMP_MS",
+        }
+    }
+}
+
+/// Span of `time` a partition holds, naming its hive-style directory
+/// `hour=2023-01-01T05`, `date=2023-01-01` or `month=2023-01`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PartitionGranularity {
+    Hour,
+    #[default]
+    Day,
+    Month,
+}
+
+impl PartitionGranularity {
+    /// The partition column, derived from `time` on write.
+    fn column(self) -> &'static str {
+        match self {
+            PartitionGranularity::Hour => "hour",
+            PartitionGranularity::Day => "date",
+            PartitionGranularity::Month => "month",
+        }
+    }
+
+    /// Format of the partition of a time, for both chrono and DuckDB's
+    /// `strftime`.
+    fn format(self) -> &'static str {
+        match self {
+            PartitionGranularity::Hour => "%Y-%m-%dT%H",
+            PartitionGranularity::Day => "%Y-%m-%d",
+            PartitionGranularity::Month => "%Y-%m",
+        }
+    }
+
+    fn partition(self, time: DateTime<Utc>) -> String {
+        time.format(self.format()).to_string()
+    }
+}
+
+/// The column the existing partitions of a destination are named by, which
+/// differs from the configured one after its granularity changed.
+fn partition_column(parquet_path: &str) -> Option<String> {
+    std::fs::read_dir(parquet_path).ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .find_map(|e| e.file_name().to_str().and_then(|n| n.split_once('=')).map(|(column, _)| column.to_string()))
+}
+
+/// Parquet compression codec: zstd suits cold archives, uncompressed files
+/// are the cheapest to read back.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compression {
+    Snappy,
+    #[default]
+    Zstd,
+    Uncompressed,
+}
+
+impl Compression {
+    fn codec(self) -> &'static str {
+        match self {
+            Compression::Snappy => "snappy",
+            Compression::Zstd => "zstd",
+            Compression::Uncompressed => "uncompressed",
+        }
+    }
+}
+
+/// Profile file for a merge into the destination, unique per merge.
+fn profile_path(dir: &Path, parquet_path: &str, now: DateTime<Utc>) -> PathBuf {
+    let name: String = parquet_path.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    dir.join(format!("merge-{}-{}.json", name.trim_matches('_'), now.timestamp_micros()))
+}
+
+/// Exclusive advisory lock on a destination's `.lock` file, released on drop.
+struct DestinationLock(File);
+
+impl Drop for DestinationLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+fn lock_destination(parquet_path: &str, timeout: Duration) -> std::io::Result<DestinationLock> {
+    let lock_path = format!("{}.lock", parquet_path);
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+    let started = Instant::now();
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(DestinationLock(file)),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                let waited = started.elapsed();
+                if waited >= timeout {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("timed out after {:?} waiting for {}", timeout, lock_path),
+                    ));
+                }
+                std::thread::sleep((timeout - waited).min(Duration::from_millis(50)));
+            }
+            Err(e) => return Err(e),
         }
+    }
+}
+
+/// Records resolved against another record with the same time since the
+/// process started, over every destination.
+static CONFLICTS_RESOLVED: AtomicU64 = AtomicU64::new(0);
+
+pub fn conflicts_resolved() -> u64 {
+    CONFLICTS_RESOLVED.load(Ordering::Relaxed)
+}
+
+/// Caps the DuckDB connections persist cycles hold open at once at
+/// `DUCKDB_MAX_CONNS`, whatever `PERSIST_CONCURRENCY` is, and tracks the most
+/// that were ever open.
+pub struct ConnectionLimit {
+    max: Option<usize>,
+    permits: Arc<tokio::sync::Semaphore>,
+    open: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        let permits = max.unwrap_or(tokio::sync::Semaphore::MAX_PERMITS);
+        ConnectionLimit { max, permits: Arc::new(tokio::sync::Semaphore::new(permits)), open: AtomicUsize::new(0), peak: AtomicUsize::new(0) }
+    }
+
+    /// Waits for a free connection, which is held until the slot is dropped.
+    async fn acquire(self: &Arc<Self>) -> ConnectionSlot {
+        let permit = self.permits.clone().acquire_owned().await.expect("the connection semaphore is never closed");
+        let open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(open, Ordering::SeqCst);
+        ConnectionSlot { limit: self.clone(), _permit: permit }
+    }
+
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+}
+
+/// One of the connections a `ConnectionLimit` allows.
+struct ConnectionSlot {
+    limit: Arc<ConnectionLimit>,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limit.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Merges into the destination on a connection of its own.
+pub fn merge_standalone(parquet_path: &str, new_records: Vec<Record>, options: &MergeOptions) -> Result<u64> {
+    let conn = open_connection(&options.extensions)?;
+    merge_new_records(&conn, parquet_path, new_records, options)
+}
+
+/// Merges the records into the destination using `conn`, which must have the
+/// configured extensions loaded and may be reused across destinations.
+/// Returns how many records had the time of an existing row or of another
+/// new record, and were resolved by the merge mode. Every merge rewrites the
+/// whole destination sorted by time, so records older than its newest row
+/// land in time order rather than after the existing rows.
+pub fn merge_new_records(conn: &Connection, parquet_path: &str, new_records: Vec<Record>, options: &MergeOptions) -> Result<u64> {
+    if new_records.is_empty() {
+        return Err(PersisterError::NoRecords);
+    }
+    let new_records = resolve_duplicate_fields(new_records, options.duplicate_fields)?;
+
+    // A new project's directory is created by its first merge.
+    if let Some(dir) = Path::new(parquet_path).parent() {
+        std::fs::create_dir_all(dir).map_err(|source| PersisterError::CreateDir { path: PathBuf::from(parquet_path), source })?;
+    }
+    let _lock = lock_destination(parquet_path, options.lock_timeout)
+        .map_err(|source| PersisterError::Lock { path: PathBuf::from(parquet_path), source })?;
+    if let Some(identity) = &options.expected_identity {
+        check_identity(parquet_path, identity)?;
+    }
+    if let Some(dir) = &options.profile_dir {
+        let path = profile_path(dir, parquet_path, Utc::now());
+        log::debug!(destination = parquet_path, profile:% = path.display(); "profiling the merge");
+        let path = path.to_str().ok_or_else(|| PersisterError::InvalidPath(path.clone()))?.replace('\'', "''");
+        conn.execute_batch(&format!("PRAGMA enable_profiling='json'; PRAGMA profiling_output='{}';", path))?;
+    }
+
+    // The table has the existing file's columns followed by any the new
+    // records add, so no column is dropped and values land in the column of
+    // their name whatever its position.
+    // A partitioned destination is a directory of hive-style subdirectories
+    // whose partition column is derived from `time` on write. Its existing
+    // partitions are rewritten at the configured granularity.
+    let granularity = options.partition_granularity;
+    let (source, derived) = if options.partition_by_date {
+        let column = partition_column(parquet_path).unwrap_or_else(|| granularity.column().to_string());
+        (format!("read_parquet('{}/*/*.parquet', hive_partitioning=1)", parquet_path), format!("time, {}", column))
+    } else {
+        (format!("read_parquet('{}')", parquet_path), "time".to_string())
+    };
+    let exists = Path::exists(Path::new(parquet_path));
+    let mut reserved: Vec<&str> = vec!["time"];
+    if options.partition_by_date {
+        reserved.push(granularity.column());
+    }
+    let names: Vec<String> = if exists {
+        let sql = format!("DESCRIBE SELECT * FROM {}", source);
+        conn.prepare(&sql)?.query_map([], |row| row.get::<_, String>(0))?.collect::<std::result::Result<Vec<_>, _>>()?
+    } else {
+        vec![]
     };
+    // The text `source` column follows `time` once any record of the
+    // destination named its producer.
+    let had_source = names.iter().any(|n| n == "source");
+    let sourced = had_source || new_records.iter().any(|r| r.source.is_some());
+    if sourced {
+        reserved.push("source");
+    }
+    let mut columns: Vec<String> = names.into_iter()
+        .filter(|n| !reserved.contains(&n.as_str()) && !derived.split(", ").any(|d| d == n))
+        .collect();
+    let existing = columns.len();
+    let mut dropped: Vec<String> = vec![];
+    for record in &new_records {
+        for i in 0..record.values.len() {
+            let name = record.field_name(i);
+            if reserved.iter().any(|r| r.eq_ignore_ascii_case(&name)) {
+                return Err(PersisterError::InvalidValue(name));
+            }
+            if columns.iter().chain(&dropped).any(|c| c.eq_ignore_ascii_case(&name)) {
+                continue;
+            }
+            if options.max_schema_width.is_some_and(|max| columns.len() >= max) {
+                dropped.push(name);
+            } else {
+                columns.push(name);
+            }
+        }
+    }
+    if !dropped.is_empty() {
+        log::warn!(destination = parquet_path, max_schema_width = options.max_schema_width.unwrap_or_default(), columns = dropped.join(",").as_str();
+            "dropping values of columns beyond the maximum schema width");
+    }
+    let quoted: Vec<String> = columns.iter().map(|c| quote_identifier(c)).collect();
 
     let table = "tmp";
-    let sql = if Path::exists(Path::new(parquet_path)) {
-        println!("{} was found. Load the Parquet file.", parquet_path);
-        format!("CREATE TEMP TABLE {} AS SELECT * FROM read_parquet('{}')", table, parquet_path)
+    let staging = "new_records";
+    conn.execute_batch(&format!("DROP TABLE IF EXISTS {}; DROP TABLE IF EXISTS {};", table, staging))?;
+    let definitions: String = quoted.iter().map(|c| format!(", {} DOUBLE", c)).collect();
+    let source_definition = if sourced { ", source VARCHAR" } else { "" };
+    conn.execute(&format!("CREATE TABLE {} ( time TIMESTAMP PRIMARY KEY{}{} )", table, source_definition, definitions), params![])?;
+    if exists {
+        log::debug!(destination = parquet_path; "loading the existing Parquet file");
+        let names: String = had_source.then(|| ", source".to_string()).into_iter()
+            .chain(quoted[..existing].iter().map(|c| format!(", {}", c)))
+            .collect();
+        conn.execute(&format!(
+            "INSERT INTO {} (time{}) SELECT CAST(time AS TIMESTAMP), * EXCLUDE ({}) FROM {}
+             QUALIFY row_number() OVER (PARTITION BY time) = 1",
+            table, names, derived, source,
+        ), params![])?;
     } else {
-        println!("{} does not exit. Define a new table.", parquet_path);
-        let mut columns = "time TIMESTAMP PRIMARY KEY".to_string();
-        for i in 0..fields {
-            columns += &format!(", f{} DOUBLE", i);
-        }
-        format!("CREATE TEMP TABLE {} ( {} )", table, columns)
+        log::debug!(destination = parquet_path; "Parquet file does not exist, defining a new table");
+    }
+
+    conn.execute(&format!("CREATE TABLE {} AS SELECT * FROM {} LIMIT 0", staging, table), params![])?;
+    append_records(conn, staging, &columns, sourced, &new_records)?;
+    let conflicts: u64 = conn.query_row(&format!(
+        "SELECT COUNT(*) - COUNT(DISTINCT time) + (SELECT COUNT(DISTINCT time) FROM {staging} WHERE time IN (SELECT time FROM {table}))
+         FROM {staging}",
+    ), [], |row| row.get(0))?;
+    let updated: Vec<String> = sourced.then(|| "source".to_string()).into_iter().chain(quoted.iter().cloned()).collect();
+    conn.execute(&options.mode.insert_sql(table, staging, &updated, options.wide), params![])?;
+
+    // Written beside the destination and renamed over it, so an interrupted
+    // COPY never leaves a truncated destination behind.
+    let tmp_path = tmp_path(parquet_path);
+    let (date, partition) = if options.partition_by_date {
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        (format!(", strftime(time, '{}') AS {}", granularity.format(), granularity.column()), format!(", PARTITION_BY ({})", granularity.column()))
+    } else {
+        (String::new(), String::new())
     };
+    let sql = &format!(
+        "COPY (SELECT CAST(time AS {}) AS time, * EXCLUDE (time){} FROM {} ORDER BY time ASC) TO '{}' (FORMAT 'parquet', COMPRESSION '{}'{})",
+        options.timestamp_unit.column_type(), date, table, tmp_path, options.compression.codec(), partition,
+    );
+    let copied = conn.execute(sql, params![]);
+    conn.execute_batch(&format!("DROP TABLE {}; DROP TABLE {};", table, staging))?;
+    if let Err(e) = copied {
+        let _ = if options.partition_by_date { std::fs::remove_dir_all(&tmp_path) } else { std::fs::remove_file(&tmp_path) };
+        return Err(e.into());
+    }
+    // A directory cannot be renamed over a non-empty one, so the previous
+    // partitions are moved aside, the new ones take their place and only
+    // then are the previous ones removed. An interrupted swap leaves the
+    // previous partitions for `remove_stale_tmp_files` to restore.
+    let old_path = old_path(parquet_path);
+    if options.partition_by_date && exists {
+        let _ = std::fs::remove_dir_all(&old_path);
+        std::fs::rename(parquet_path, &old_path)
+            .map_err(|source| PersisterError::Rename { path: PathBuf::from(parquet_path), source })?;
+    }
+    std::fs::rename(&tmp_path, parquet_path)
+        .map_err(|source| PersisterError::Rename { path: PathBuf::from(parquet_path), source })?;
+    if options.partition_by_date && exists {
+        std::fs::remove_dir_all(&old_path)
+            .map_err(|source| PersisterError::Rename { path: PathBuf::from(parquet_path), source })?;
+    }
+    if let Some(identity) = &options.expected_identity {
+        std::fs::write(identity_path(parquet_path), identity)
+            .map_err(|source| PersisterError::Stamp { path: PathBuf::from(parquet_path), source })?;
+    }
 
-    conn.execute(&sql, params![])?;
+    if conflicts > 0 {
+        log::info!(destination = parquet_path, conflicts = conflicts, mode:? = options.mode; "resolved records with an already present time");
+        CONFLICTS_RESOLVED.fetch_add(conflicts, Ordering::Relaxed);
+    }
+    Ok(conflicts)
+}
 
-    let sql = compose_insert_query(table, fields, new_records);
-    conn.execute(&sql, params![])?;
+/// The file a merge is written to before it replaces `parquet_path`. The
+/// leading dot keeps it apart from destinations, whose names never start
+/// with one.
+fn tmp_path(parquet_path: &str) -> String {
+    let path = Path::new(parquet_path);
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.tmp", name)).to_string_lossy().into_owned()
+}
 
-    let sql = &format!("COPY (SELECT * FROM {} ORDER BY time ASC) TO '{}' (FORMAT 'parquet')", table, parquet_path);
-    conn.execute(&sql, params![])?;
+/// Where a partitioned merge moves the previous partitions while the new ones
+/// take their place.
+fn old_path(parquet_path: &str) -> String {
+    let path = Path::new(parquet_path);
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.old", name)).to_string_lossy().into_owned()
+}
 
-    Ok(())
+fn is_tmp_path(path: &Path) -> bool {
+    path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with('.') && n.ends_with(".tmp"))
 }
 
-fn compose_insert_query(table: &str, fields: usize, records: Vec<Record>) -> String {
-    let sql = &format!("INSERT INTO {} VALUES", table);
+/// The destination whose previous partitions are at `path`, if it is one.
+fn old_destination(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?.strip_prefix('.')?.strip_suffix(".old")?;
+    (!name.is_empty()).then(|| path.with_file_name(name))
+}
 
-    let rows: Vec<String> = records.iter().map(|record| {
-        let colls: Vec<String> = (0..fields).map(|i| {
-            if let Some(v) = record.values.get(i) {
-                format!("{}", v)
+/// Cleans up after merges that were interrupted under the root, returning how
+/// many paths were removed or restored. Previous partitions moved aside by a
+/// swap that never finished are restored when their destination is missing;
+/// its rows are still in the WAL, so the next cycle merges them again. The
+/// temporary files and partition directories, which may be incomplete, and
+/// previous partitions already replaced are removed.
+fn remove_stale_tmp_files(root: &Path) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(root)? {
+        let path = entry?.path();
+        if let Some(destination) = path.is_dir().then(|| old_destination(&path)).flatten() {
+            if destination.exists() {
+                log::warn!(path:% = path.display(); "removing replaced partitions");
+                std::fs::remove_dir_all(&path)?;
             } else {
-                "NULL".to_string()
+                log::warn!(path:% = path.display(), destination:% = destination.display(); "restoring the partitions of an interrupted merge");
+                std::fs::rename(&path, &destination)?;
             }
-        }).collect();
-        let time = record.time.format("%Y-%m-%d %H:%M:%S%.3f");
-        format!("('{}', {})", time, colls.join(", "))
-    }).collect();
+            removed += 1;
+        }
+    }
+    for entry in std::fs::read_dir(root)? {
+        let path = entry?.path();
+        if path.is_dir() && is_tmp_path(&path) {
+            log::warn!(path:% = path.display(); "removing a stale temporary directory");
+            std::fs::remove_dir_all(&path)?;
+            removed += 1;
+        } else if path.is_dir() {
+            removed += remove_stale_tmp_files(&path)?;
+        } else if is_tmp_path(&path) {
+            log::warn!(path:% = path.display(); "removing a stale temporary file");
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// How many data roots must accept a destination's write for it to succeed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteQuorum {
+    All,
+    AtLeast(usize),
+}
+
+/// Merges each destination's records into its Parquet file under every data
+/// root, logging the outcome per file, and returns the outcome per
+/// destination. A destination fails when fewer roots than the quorum could be
+/// written.
+fn persist_groups(
+    conn: &Connection,
+    roots: &[String],
+    quorum: WriteQuorum,
+    options: &MergeOptions,
+    groups: HashMap<String, Vec<Record>>,
+) -> Vec<(String, Result<()>)> {
+    let required = match quorum {
+        WriteQuorum::All => roots.len(),
+        WriteQuorum::AtLeast(n) => n.min(roots.len()),
+    };
+
+    let mut outcomes = vec![];
+    for (destination, records) in groups {
+        let rows = records.len();
+        let mut written = 0;
+        let mut last_error = None;
+        for root in roots {
+            let path = Path::new(root).join(&destination).to_string_lossy().into_owned();
+            // Merges create project directories but never a missing root,
+            // which is more likely an unmounted volume than a new one.
+            let merged = if Path::new(root).is_dir() {
+                merge_new_records(conn, &path, records.clone(), &options.for_destination(&destination))
+            } else {
+                Err(PersisterError::NotReady(format!("data root '{}' is not a directory", root)))
+            };
+            match merged {
+                Ok(_) => {
+                    log::info!(destination = path.as_str(), rows = rows; "merged records");
+                    written += 1;
+                }
+                Err(e) => {
+                    log::error!(destination = path.as_str(), rows = rows, error:% = e; "failed to merge records");
+                    last_error = Some(e);
+                }
+            }
+        }
+        let outcome = match last_error {
+            Some(e) if written < required => Err(e),
+            _ => Ok(()),
+        };
+        outcomes.push((destination, outcome));
+    }
+    outcomes
+}
+
+/// Runs `persist_groups` on up to `concurrency` blocking tasks, each with a
+/// DuckDB connection of its own taken from the connection limit. Every
+/// destination goes to exactly one task, so no two tasks ever write the same
+/// file.
+async fn persist_groups_concurrently(
+    config: &PersistConfig,
+    concurrency: usize,
+    groups: HashMap<String, Vec<Record>>,
+) -> Result<Vec<(String, Result<()>)>> {
+    let workers = concurrency.clamp(1, groups.len().max(1));
+    let mut buckets: Vec<HashMap<String, Vec<Record>>> = (0..workers).map(|_| HashMap::new()).collect();
+    for (i, (destination, records)) in groups.into_iter().enumerate() {
+        buckets[i % workers].insert(destination, records);
+    }
+    let tasks = buckets.into_iter().map(|bucket| {
+        let roots = config.data_roots.clone();
+        let quorum = config.quorum;
+        let options = config.merge.clone();
+        let connections = config.connections.clone();
+        async move {
+            let slot = connections.acquire().await;
+            tokio::task::spawn_blocking(move || {
+                let _slot = slot;
+                let conn = open_connection(&options.extensions)?;
+                Ok(persist_groups(&conn, &roots, quorum, &options, bucket))
+            }).await
+        }
+    });
+    let mut results = futures::stream::iter(tasks).buffer_unordered(workers);
+    let mut outcomes = vec![];
+    while let Some(result) = results.next().await {
+        let result: Result<Vec<_>> = result.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        outcomes.extend(result?);
+    }
+    Ok(outcomes)
+}
 
-    format!("{} {}", sql, rows.join(", "))
+/// Appends records to the table as typed values, each in the column of its
+/// field name, padding the columns a record has no value for with `NULL` and
+/// dropping values without a column. A `sourced` table has the record's
+/// source after its time.
+fn append_records(conn: &Connection, table: &str, columns: &[String], sourced: bool, records: &[Record]) -> Result<()> {
+    let mut appender = conn.appender(table)?;
+    for record in records {
+        let time = Value::Timestamp(TimeUnit::Microsecond, record.time.timestamp_micros());
+        let source = sourced.then(|| record.source.clone().map_or(Value::Null, Value::Text));
+        let mut values = vec![Value::Null; columns.len()];
+        for (i, v) in record.values.iter().enumerate() {
+            let name = record.field_name(i);
+            if let Some(column) = columns.iter().position(|c| c.eq_ignore_ascii_case(&name)) {
+                values[column] = Value::Double(*v);
+            }
+        }
+        appender.append_row(appender_params_from_iter(std::iter::once(time).chain(source).chain(values)))?;
+    }
+    appender.flush();
+    Ok(())
 }
 
 #[cfg(test)]
@@ -92,19 +841,25 @@ mod tests {
                 destination: "".to_string(),
                 time: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
                 values: vec![1.0, 2.0, 3.0],
+                field_names: None,
+                source: None,
             },
             Record{
                 destination: "".to_string(),
                 time: Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap(),
                 values: vec![4.0, 5.0, 6.0],
+                field_names: None,
+                source: None,
             },
             Record{
                 destination: "".to_string(),
                 time: Utc.with_ymd_and_hms(2023, 1, 3, 0, 0, 0).unwrap(),
                 values: vec![7.0, 8.0, 9.0],
+                field_names: None,
+                source: None,
             },
         ];
-        let _ = merge_new_records(parquet, records).unwrap();
+        let _ = merge_standalone(parquet, records, &MergeOptions::default()).unwrap();
 
         let conn = Connection::open_in_memory().unwrap();
         conn.execute_batch("INSTALL parquet; LOAD parquet;").unwrap();
@@ -125,110 +880,2365 @@ mod tests {
         assert_eq!(result, "1 2 3, 4 5 6, 7 8 9, ");
 
         std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(format!("{}.lock", parquet)).unwrap();
     }
 
     #[test]
-    fn test_compose_insert_query() {
-        let sql = compose_insert_query("foo", 0,  vec![]);
-        assert_eq!(sql, "INSERT INTO foo VALUES ");
+    fn test_append_records() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE foo (time TIMESTAMP PRIMARY KEY, f0 DOUBLE, f1 DOUBLE, f2 DOUBLE)").unwrap();
+        let record = |day, values: Vec<f64>| Record {
+            destination: "".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, day, 0, 0, 0).unwrap(),
+            values,
+            field_names: None,
+            source: None,
+        };
+        let columns: Vec<String> = ["f0", "f1", "f2"].iter().map(|c| c.to_string()).collect();
+        append_records(&conn, "foo", &columns, false, &[
+            record(1, vec![1.0, 2.5, f64::MAX]),
+            record(2, vec![f64::NAN, f64::INFINITY, f64::NEG_INFINITY]),
+            record(3, vec![-1234.125]),
+            record(4, vec![0.1, 0.2, 0.3, 0.4]),
+        ]).unwrap();
+
+        let mut stmt = conn.prepare("SELECT CAST(time AS VARCHAR), f0, f1, f2 FROM foo ORDER BY time").unwrap();
+        type Row = (String, Option<f64>, Option<f64>, Option<f64>);
+        let rows: Vec<Row> = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows[0], ("2023-01-01 00:00:00".to_string(), Some(1.0), Some(2.5), Some(f64::MAX)));
+        assert!(rows[1].1.unwrap().is_nan());
+        assert_eq!((rows[1].2, rows[1].3), (Some(f64::INFINITY), Some(f64::NEG_INFINITY)));
+        assert_eq!((rows[2].1, rows[2].2, rows[2].3), (Some(-1234.125), None, None));
+        assert_eq!((rows[3].1, rows[3].2, rows[3].3), (Some(0.1), Some(0.2), Some(0.3)));
+    }
+
+    struct CapturingLogger {
+        records: std::sync::Mutex<Vec<(log::Level, Option<String>)>>,
+    }
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
 
-        let sql = compose_insert_query("foo", 1,  vec![]);
-        assert_eq!(sql, "INSERT INTO foo VALUES ");
+        fn log(&self, record: &log::Record) {
+            let destination = record.key_values().get("destination".into()).map(|v| v.to_string());
+            self.records.lock().unwrap().push((record.level(), destination));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger { records: std::sync::Mutex::new(vec![]) };
 
-        let sql = compose_insert_query("foo", 3,  vec![
+    #[test]
+    fn test_persist_groups_logs_errors() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let root = std::env::temp_dir().join("zeta-no-such-dir").to_str().unwrap().to_string();
+        let destination = Path::new(&root).join("data.parquet").to_str().unwrap().to_string();
+        let groups = HashMap::from([("data.parquet".to_string(), vec![
             Record{
-                destination: "".to_string(),
+                destination: "data.parquet".to_string(),
                 time: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
-                values: vec![1.0, 2.0, 3.0],
+                values: vec![1.0],
+                field_names: None,
+                source: None,
             },
+        ])]);
+        assert!(persist_groups(&open_connection(&[]).unwrap(), &[root], WriteQuorum::All, &MergeOptions::default(), groups)[0].1.is_err());
+
+        let records = LOGGER.records.lock().unwrap();
+        assert!(records.iter().any(|(level, d)| *level == log::Level::Error && d.as_deref() == Some(destination.as_str())));
+    }
+
+    #[test]
+    fn test_persist_groups_share_connection() {
+        let base = std::env::temp_dir().join(format!("zeta-shared-conn-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+        let groups: HashMap<String, Vec<Record>> = (0..5).map(|i| {
+            let destination = format!("{}.parquet", i);
+            (destination.clone(), vec![Record {
+                destination,
+                time: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+                values: vec![i as f64; i + 1],
+                field_names: None,
+                source: None,
+            }])
+        }).collect();
+
+        let conn = open_connection(&[]).unwrap();
+        let roots = vec![base.to_str().unwrap().to_string()];
+        let outcomes = persist_groups(&conn, &roots, WriteQuorum::All, &MergeOptions::default(), groups);
+        assert_eq!(outcomes.len(), 5);
+        assert!(outcomes.iter().all(|(_, r)| r.is_ok()));
+        for i in 0..5 {
+            let sql = format!("SELECT COUNT(*) - 1 FROM parquet_schema('{}')", base.join(format!("{}.parquet", i)).to_str().unwrap());
+            assert_eq!(conn.query_row(&sql, [], |row| row.get::<_, i64>(0)).unwrap(), i as i64 + 2);
+        }
+        let tables: i64 = conn.query_row("SELECT COUNT(*) FROM duckdb_tables()", [], |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_persist_groups_fan_out() {
+        let base = std::env::temp_dir().join("zeta-test-fan-out");
+        let roots: Vec<String> = ["a", "b"].iter().map(|r| {
+            let root = base.join(r);
+            if root.exists() {
+                std::fs::remove_dir_all(&root).unwrap();
+            }
+            std::fs::create_dir_all(&root).unwrap();
+            root.to_str().unwrap().to_string()
+        }).collect();
+        let missing = base.join("missing").to_str().unwrap().to_string();
+
+        let groups = || HashMap::from([("data.parquet".to_string(), vec![
             Record{
-                destination: "".to_string(),
-                time: Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap(),
+                destination: "data.parquet".to_string(),
+                time: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
                 values: vec![1.0, 2.0],
+                field_names: None,
+                source: None,
             },
-            Record{
-                destination: "".to_string(),
-                time: Utc.with_ymd_and_hms(2023, 1, 3, 0, 0, 0).unwrap(),
-                values: vec![1.0, 2.0, 3.0, 4.0],
-            },
-        ]);
-        assert_eq!(sql, "INSERT INTO foo VALUES ('2023-01-01 00:00:00.000', 1, 2, 3), ('2023-01-02 00:00:00.000', 1, 2, NULL), ('2023-01-03 00:00:00.000', 1, 2, 3)");
+        ])]);
+
+        let outcomes = persist_groups(&open_connection(&[]).unwrap(), &roots, WriteQuorum::All, &MergeOptions::default(), groups());
+        assert!(outcomes[0].1.is_ok());
+        for root in &roots {
+            assert!(Path::new(root).join("data.parquet").exists());
+        }
+
+        let with_missing = vec![roots[0].clone(), missing];
+        assert!(persist_groups(&open_connection(&[]).unwrap(), &with_missing, WriteQuorum::All, &MergeOptions::default(), groups())[0].1.is_err());
+        assert!(persist_groups(&open_connection(&[]).unwrap(), &with_missing, WriteQuorum::AtLeast(1), &MergeOptions::default(), groups())[0].1.is_ok());
+
+        std::fs::remove_dir_all(&base).unwrap();
     }
-}
 
+    #[test]
+    fn test_parse_write_quorum() {
+        assert_eq!(parse_write_quorum("all").unwrap(), WriteQuorum::All);
+        assert_eq!(parse_write_quorum("2").unwrap(), WriteQuorum::AtLeast(2));
+        assert!(parse_write_quorum("0").is_err());
+        assert!(parse_write_quorum("most").is_err());
+    }
 
-async fn load_wal() -> Result<()> {
-    let data_root = &get_data_root();
-    let root_path = Path::new(data_root);
-    let db_url = if let Some(path) = root_path.join("wal.sqlite").to_str() {
-        format!("sqlite://{}", path)
-    } else {
-        // TODO must return an error
-        return Ok(());
-    };
-    let pool = SqlitePool::connect(&db_url).await.map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("Database connection error: {}", e))
-    }).unwrap();
+    async fn create_test_wal(root: &Path, rows: &[(&str, &str, &str)]) {
+        let db_url = format!("sqlite://{}?mode=rwc", root.join("wal.sqlite").to_str().unwrap());
+        let pool = SqlitePool::connect(&db_url).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE wal (
+                 project_id TEXT NOT NULL,
+                 time       DATETIME NOT NULL,
+                 created_at DATETIME NOT NULL,
+                 payload    TEXT NOT NULL,
+                 schema     TEXT NOT NULL,
+                 compressed INTEGER NOT NULL DEFAULT 0,
+                 attempts   INTEGER NOT NULL DEFAULT 0
+             )"
+        ).execute(&pool).await.unwrap();
+        for (i, (project_id, schema, payload)) in rows.iter().enumerate() {
+            let timestamp = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, i as u32).unwrap().to_rfc3339();
+            sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES (?1, ?2, ?3, ?4, ?5)")
+                .bind(project_id)
+                .bind(&timestamp)
+                .bind(&timestamp)
+                .bind(payload)
+                .bind(schema)
+                .execute(&pool).await.unwrap();
+        }
+        pool.close().await;
+    }
 
-    let new_rows: Vec<Record> = vec![];
-    let mut rows = sqlx::query("SELECT * FROM wal").fetch(&pool);
-    while let Some(row) = rows.try_next().await? {
-        let id: String = row.try_get("project_id")?;
-        let schema: String = row.try_get("schema")?;
-        let joined = root_path.join(id).join(schema);
-        let parquet_path = if let Some(path) = joined.to_str() {
-            path
-        } else {
-            // TODO must return an error
-            return Ok(());
-        };
-
-        // TODO must generate new_rows from the payload
-        let payload: String = row.try_get("payload")?;
-        let str_vals: Vec<&str> = payload.split(",").map(|f| f.trim()).collect();
-        let mut values: Vec<f64> = vec![];
-        for val in str_vals {
-            match val.parse::<f64>() {
-                Ok(v) => {
-                    values.push(v);
-                }
-                Err(_) => {
-                    // TODO show the error and dispose the row
-                    return Ok(());
-                }
-            }
+    fn test_config(root: &std::path::Path) -> PersistConfig {
+        let root = root.to_str().unwrap().to_string();
+        PersistConfig {
+            data_root: root.clone(),
+            data_roots: vec![root],
+            quorum: WriteQuorum::All,
+            allow_empty_records: false,
+            merge: MergeOptions::default(),
+            preprocess: HashMap::new(),
+            merge_window: None,
+            concurrency: 1,
+            connections: Arc::new(ConnectionLimit::new(None)),
+            max_attempts: None,
+            field_order: FieldOrder::default(),
+            reconcile_window: Duration::from_secs(DEFAULT_RECONCILE_WINDOW_SECS),
         }
-        let record = Record{
-            destination: parquet_path.to_string(),
-            time: "a",
-            values,
-        };
-        new_rows.push(record);
     }
 
-    let new_row_groups = new_rows.into_iter().into_group_map_by(|r| r.destination);
+    fn test_root(name: &str) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(name);
+        if root.exists() {
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_record_time() {
+        let root = test_root("zeta-test-time");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        create_test_wal(&root, &[("p", "data.parquet", "1, 2")]).await;
+        let pool = SqlitePool::connect(&format!("sqlite://{}", root.join("wal.sqlite").to_str().unwrap())).await.unwrap();
+        sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('p', 'yesterday', 'yesterday', '3, 4', 'data.parquet')")
+            .execute(&pool).await.unwrap();
+        pool.close().await;
+
+        let config = test_config(&root);
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
 
-    for (k, v) in new_row_groups {
-        merge_new_records(&k, v)?
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT CAST(time AS VARCHAR) FROM read_parquet('{}')", root.join("p").join("data.parquet").to_str().unwrap());
+        let times: Vec<String> = conn.prepare(&sql).unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(times, vec!["2023-01-01 00:00:00"]);
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn test_load_wal_default_schema() {
+        let root = test_root("zeta-test-default-schema");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        create_test_wal(&root, &[("p", "", "1, 2")]).await;
 
-fn get_data_root() -> String {
-     env::var("DATA_ROOT").unwrap_or_else(|_| env::current_dir().unwrap().to_str().unwrap().to_string())
-}
+        let config = test_config(&root);
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert!(root.join("p").join("default").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let data_root = get_data_root();
-    let pool = SqlitePool::connect("sqlite::memory:").await.map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("Database connection error: {}", e))
-    })?;
+    #[tokio::test]
+    async fn test_load_wal_empty_records() {
+        for (allow_empty_records, expected) in [(false, 1), (true, 2)] {
+            let root = test_root(&format!("zeta-test-empty-{}", allow_empty_records));
+            std::fs::create_dir_all(root.join("p")).unwrap();
+            create_test_wal(&root, &[("p", "data.parquet", "1, 2"), ("p", "data.parquet", "  ")]).await;
 
-    loop {
-        load_wal().await?;
+            let config = PersistConfig { allow_empty_records, ..test_config(&root) };
+            assert_eq!(load_wal(&config).await.unwrap(), expected);
 
-        std::thread::sleep(std::time::Duration::from_secs(10));
+            let conn = Connection::open_in_memory().unwrap();
+            let sql = format!("SELECT COUNT(*) FROM read_parquet('{}')", root.join("p").join("data.parquet").to_str().unwrap());
+            let count: i64 = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+            assert_eq!(count, expected as i64);
+
+            std::fs::remove_dir_all(&root).unwrap();
+        }
     }
+
+    #[tokio::test]
+    async fn test_load_wal_skips_bad_rows() {
+        let root = test_root("zeta-test-bad-rows");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        create_test_wal(&root, &[
+            ("p", "data.parquet", "1, 2"),
+            ("p", "data.parquet", "3, x"),
+            ("p", "data.parquet", "5, 6"),
+        ]).await;
+
+        let config = test_config(&root);
+        assert_eq!(load_wal(&config).await.unwrap(), 2);
+
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT SUM(f0) FROM read_parquet('{}')", root.join("p").join("data.parquet").to_str().unwrap());
+        let sum: f64 = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+        assert_eq!(sum, 6.0);
+
+        // The bad row leaves the WAL for the dead letters instead of being
+        // re-read every cycle.
+        let pool = open_wal(&config).await.unwrap();
+        let wal: i64 = sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool).await.unwrap().get(0);
+        let dead = sqlx::query("SELECT payload, error FROM dead_letters").fetch_all(&pool).await.unwrap();
+        pool.close().await;
+        assert_eq!(wal, 0);
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].get::<String, _>("payload"), "3, x");
+        assert!(dead[0].get::<String, _>("error").contains("'x'"));
+        assert_eq!(load_wal(&config).await.unwrap(), 0);
+
+        let missing = PersistConfig { data_root: root.join("missing").to_str().unwrap().to_string(), ..config };
+        assert!(matches!(load_wal(&missing).await, Err(PersisterError::Sqlx(_))));
+        assert!(matches!(merge_standalone("unused.parquet", vec![], &MergeOptions::default()), Err(PersisterError::NoRecords)));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_deletes_merged_rows() {
+        let root = test_root("zeta-test-delete-merged");
+        std::fs::create_dir_all(root.join("q")).unwrap();
+        std::fs::write(root.join("q").join("data.parquet"), "not parquet").unwrap();
+        create_test_wal(&root, &[
+            ("p", "data.parquet", "1, 2"),
+            ("p", "data.parquet", "3, 4"),
+            ("q", "data.parquet", "5, 6"),
+        ]).await;
+
+        let config = test_config(&root);
+        let count = |project: &str| {
+            let conn = Connection::open_in_memory().unwrap();
+            let sql = format!("SELECT COUNT(*) FROM read_parquet('{}')", root.join(project).join("data.parquet").to_str().unwrap());
+            conn.query_row(&sql, [], |row| row.get::<_, i64>(0)).unwrap()
+        };
+
+        // `p` has no directory yet and gets one; `q`'s existing file is
+        // corrupt, so its merge fails and its row is kept.
+        assert!(load_wal(&config).await.is_err());
+        assert_eq!(count("p"), 2);
+        let pool = open_wal(&config).await.unwrap();
+        let left: Vec<String> = sqlx::query("SELECT project_id FROM wal").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| row.get("project_id")).collect();
+        assert_eq!(left, vec!["q"]);
+        pool.close().await;
+
+        std::fs::remove_file(root.join("q").join("data.parquet")).unwrap();
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert_eq!(load_wal(&config).await.unwrap(), 0);
+        assert_eq!((count("p"), count("q")), (2, 1));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let root = test_root("zeta-test-reconcile");
+        create_test_wal(&root, &[
+            ("p", "data.parquet", "1, 2"),
+            ("p", "data.parquet", "3, 4"),
+            ("q", "data.parquet", "5, 6"),
+        ]).await;
+        let config = test_config(&root);
+        assert_eq!(load_wal(&config).await.unwrap(), 3);
+        let path = |project: &str| root.join(project).join("data.parquet").to_str().unwrap().to_string();
+        let reconciled = reconcile(&config, Utc::now()).await.unwrap();
+        assert_eq!(reconciled, vec![
+            Reconciliation { path: path("p"), deleted: 2, missing: vec![] },
+            Reconciliation { path: path("q"), deleted: 1, missing: vec![] },
+        ]);
+
+        // The WAL row at 00:00:01 was deleted but its persist is lost.
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "COPY (SELECT * FROM read_parquet('{p}') WHERE time < TIMESTAMP '2023-01-01 00:00:01') TO '{p}.tmp' (FORMAT 'parquet')",
+            p = path("p"),
+        )).unwrap();
+        std::fs::rename(format!("{}.tmp", path("p")), path("p")).unwrap();
+        let reconciled = reconcile(&config, Utc::now()).await.unwrap();
+        assert_eq!(reconciled[0], Reconciliation {
+            path: path("p"),
+            deleted: 2,
+            missing: vec![Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 1).unwrap()],
+        });
+        assert!(reconciled[1].missing.is_empty());
+
+        // Deletes older than the window are no longer compared.
+        let later = Utc::now() + chrono::Duration::from_std(config.reconcile_window).unwrap() + chrono::Duration::seconds(1);
+        assert!(reconcile(&config, later).await.unwrap().is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_source() {
+        let root = test_root("zeta-test-source");
+        create_test_wal(&root, &[
+            ("p", "data.parquet", "1"),
+            ("p", "data.parquet", "2"),
+            ("p", "data.parquet", "3"),
+        ]).await;
+        let config = test_config(&root);
+        let pool = open_wal(&config).await.unwrap();
+        sqlx::query("ALTER TABLE wal ADD COLUMN source TEXT").execute(&pool).await.unwrap();
+        sqlx::query("UPDATE wal SET source = CASE payload WHEN '1' THEN 'sensor-a' WHEN '2' THEN 'sensor-b' END").execute(&pool).await.unwrap();
+        pool.close().await;
+
+        assert_eq!(load_wal(&config).await.unwrap(), 3);
+        let parquet = root.join("p").join("data.parquet").to_str().unwrap().to_string();
+        let read = || {
+            let conn = Connection::open_in_memory().unwrap();
+            conn.prepare(&format!("SELECT source, f0 FROM read_parquet('{}') ORDER BY time", parquet)).unwrap()
+                .query_map([], |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, f64>(1)?))).unwrap()
+                .map(|r| r.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![(Some("sensor-a".to_string()), 1.0), (Some("sensor-b".to_string()), 2.0), (None, 3.0)];
+        assert_eq!(read(), expected);
+
+        // A later merge without sources keeps the column and its values.
+        let record = Record {
+            destination: "data.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap(),
+            values: vec![4.0],
+            field_names: None,
+            source: None,
+        };
+        merge_standalone(&parquet, vec![record], &MergeOptions::default()).unwrap();
+        assert_eq!(read(), expected.into_iter().chain([(None, 4.0)]).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_wide_table() {
+        let root = test_root("zeta-test-wide");
+        create_test_wal(&root, &[
+            ("p", "a", "1, 2"),
+            ("p", "b", r#"{"temp": 3}"#),
+            ("q", "a", "4"),
+        ]).await;
+        // Schema b also reports at the first second of schema a, and a
+        // again, a cycle later, at b's.
+        let config = PersistConfig { merge: MergeOptions { wide: true, ..Default::default() }, ..test_config(&root) };
+        let pool = open_wal(&config).await.unwrap();
+        sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('p', '2023-01-01T00:00:00+00:00', '2023-01-01T00:00:00+00:00', '{\"temp\": 5}', 'b')")
+            .execute(&pool).await.unwrap();
+        pool.close().await;
+        assert_eq!(load_wal(&config).await.unwrap(), 4);
+        let pool = open_wal(&config).await.unwrap();
+        sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('p', '2023-01-01T00:00:01+00:00', '2023-01-01T00:00:01+00:00', '6, 7', 'a')")
+            .execute(&pool).await.unwrap();
+        pool.close().await;
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+
+        let names: Vec<String> = std::fs::read_dir(root.join("p")).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|n| !n.ends_with(".lock"))
+            .collect();
+        assert_eq!(names, vec![WIDE_TABLE]);
+        let conn = Connection::open_in_memory().unwrap();
+        let parquet = root.join("p").join(WIDE_TABLE);
+        let sql = format!("DESCRIBE SELECT * FROM read_parquet('{}')", parquet.display());
+        let columns: Vec<String> = conn.prepare(&sql).unwrap().query_map([], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(columns, vec!["time", "a_f0", "a_f1", "b_temp"]);
+        let sql = format!("SELECT a_f0, a_f1, b_temp FROM read_parquet('{}') ORDER BY time", parquet.display());
+        let rows: Vec<(Option<f64>, Option<f64>, Option<f64>)> = conn.prepare(&sql).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows, vec![(Some(1.0), Some(2.0), Some(5.0)), (Some(6.0), Some(7.0), Some(3.0))]);
+        assert!(root.join("q").join(WIDE_TABLE).exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_join_wide_records() {
+        let record = |destination: &str, second, names: &[&str], values: Vec<f64>| Record {
+            destination: destination.to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, second).unwrap(),
+            values,
+            field_names: Some(names.iter().map(|n| n.to_string()).collect()),
+            source: None,
+        };
+        let joined = join_wide_records(vec![
+            record("p/wide", 0, &["a_f0"], vec![1.0]),
+            record("p/wide", 0, &["b_f0", "a_f0"], vec![2.0, 3.0]),
+            record("p/wide", 1, &["a_f0"], vec![4.0]),
+            record("q/wide", 0, &["a_f0"], vec![5.0]),
+        ]);
+        let joined: Vec<_> = joined.iter().map(|r| (r.destination.as_str(), r.time.timestamp() % 60, r.field_names.clone().unwrap(), r.values.clone())).collect();
+        assert_eq!(joined, vec![
+            ("p/wide", 0, vec!["a_f0".to_string(), "b_f0".to_string()], vec![3.0, 2.0]),
+            ("p/wide", 1, vec!["a_f0".to_string()], vec![4.0]),
+            ("q/wide", 0, vec!["a_f0".to_string()], vec![5.0]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_field_order() {
+        for (order, expected) in [(FieldOrder::Alphabetical, ["time", "cpu", "mem"]), (FieldOrder::AsReceived, ["time", "mem", "cpu"])] {
+            let root = test_root(&format!("zeta-test-field-order-{:?}", order));
+            create_test_wal(&root, &[("p", "data.parquet", r#"{"mem": 1, "cpu": 2}"#)]).await;
+            let config = PersistConfig { field_order: order, ..test_config(&root) };
+            assert_eq!(load_wal(&config).await.unwrap(), 1);
+            // A later payload with the keys the other way round keeps the
+            // columns in place.
+            let pool = open_wal(&config).await.unwrap();
+            sqlx::query(r#"INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('p', '2023-01-01T01:00:00+00:00', '2023-01-01T01:00:00+00:00', '{"cpu": 4, "mem": 3}', 'data.parquet')"#)
+                .execute(&pool).await.unwrap();
+            pool.close().await;
+            assert_eq!(load_wal(&config).await.unwrap(), 1);
+
+            let conn = Connection::open_in_memory().unwrap();
+            let parquet = root.join("p").join("data.parquet").to_str().unwrap().to_string();
+            let columns: Vec<String> = conn.prepare(&format!("DESCRIBE SELECT * FROM read_parquet('{}')", parquet)).unwrap()
+                .query_map([], |row| row.get(0)).unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            assert_eq!(columns, expected, "{:?}", order);
+            let sums: (f64, f64) = conn.query_row(&format!("SELECT SUM(mem), SUM(cpu) FROM read_parquet('{}')", parquet), [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+            assert_eq!(sums, (4.0, 6.0));
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+        assert_eq!(parse_fields(r#"{"mem": 2, "cpu": 0.5}"#, FieldOrder::AsReceived).unwrap(), (vec![2.0, 0.5], Some(vec!["mem".to_string(), "cpu".to_string()])));
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_retries_failed_rows() {
+        let root = test_root("zeta-test-retry");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        std::fs::write(root.join("p").join("data.parquet"), "not parquet").unwrap();
+        create_test_wal(&root, &[("p", "data.parquet", "1, 2")]).await;
+        let config = PersistConfig { max_attempts: Some(3), ..test_config(&root) };
+        let attempts = || async {
+            let pool = open_wal(&config).await.unwrap();
+            let attempts: Vec<i64> = sqlx::query("SELECT attempts FROM wal").fetch_all(&pool).await.unwrap()
+                .iter().map(|row| row.get("attempts")).collect();
+            pool.close().await;
+            attempts
+        };
+
+        // The destination fails twice, below the threshold, then recovers.
+        assert!(load_wal(&config).await.is_err());
+        assert!(load_wal(&config).await.is_err());
+        assert_eq!(attempts().await, vec![2]);
+        std::fs::remove_file(root.join("p").join("data.parquet")).unwrap();
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert!(attempts().await.is_empty());
+
+        let pool = open_wal(&config).await.unwrap();
+        let dead = sqlx::query("SELECT COUNT(*) FROM dead_letters").fetch_one(&pool).await;
+        assert!(dead.is_err_and(|e| e.to_string().contains("no such table")));
+        pool.close().await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_dead_letters_exhausted_rows() {
+        let root = test_root("zeta-test-exhausted");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        std::fs::write(root.join("p").join("data.parquet"), "not parquet").unwrap();
+        create_test_wal(&root, &[("p", "data.parquet", "1, 2")]).await;
+        let config = PersistConfig { max_attempts: Some(2), ..test_config(&root) };
+
+        assert!(load_wal(&config).await.is_err());
+        let pool = open_wal(&config).await.unwrap();
+        let left = sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool).await.unwrap().get::<i64, _>(0);
+        assert_eq!(left, 1);
+        pool.close().await;
+
+        assert!(load_wal(&config).await.is_err());
+        let pool = open_wal(&config).await.unwrap();
+        let left = sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool).await.unwrap().get::<i64, _>(0);
+        assert_eq!(left, 0);
+        let dead = sqlx::query("SELECT payload, error FROM dead_letters").fetch_all(&pool).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].get::<String, _>("payload"), "1, 2");
+        assert!(!dead[0].get::<String, _>("error").is_empty());
+        pool.close().await;
+
+        // Nothing is left to fail on the next cycle.
+        assert_eq!(load_wal(&config).await.unwrap(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_skips_paused_projects() {
+        let root = test_root("zeta-test-paused");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        std::fs::create_dir_all(root.join("q")).unwrap();
+        create_test_wal(&root, &[
+            ("p", "data.parquet", "1, 2"),
+            ("q", "data.parquet", "3, 4"),
+        ]).await;
+        let config = test_config(&root);
+        let pool = open_wal(&config).await.unwrap();
+        sqlx::query("CREATE TABLE paused_projects (project_id TEXT PRIMARY KEY, paused_at DATETIME NOT NULL)").execute(&pool).await.unwrap();
+        sqlx::query("INSERT INTO paused_projects VALUES ('p', '2023-01-01T00:00:00+00:00')").execute(&pool).await.unwrap();
+
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert!(!root.join("p").join("data.parquet").exists());
+        assert!(root.join("q").join("data.parquet").exists());
+        let left: Vec<String> = sqlx::query("SELECT project_id FROM wal").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| row.get("project_id")).collect();
+        assert_eq!(left, vec!["p"]);
+
+        sqlx::query("DELETE FROM paused_projects").execute(&pool).await.unwrap();
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert!(root.join("p").join("data.parquet").exists());
+        pool.close().await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_concurrently() {
+        let root = test_root("zeta-test-concurrency");
+        let projects = ["p", "q", "r"];
+        for project in projects {
+            std::fs::create_dir_all(root.join(project)).unwrap();
+        }
+        create_test_wal(&root, &[
+            ("p", "data.parquet", "1, 2"),
+            ("q", "data.parquet", "3, 4"),
+            ("r", "data.parquet", "5, 6"),
+            ("p", "data.parquet", "7, 8"),
+        ]).await;
+        let config = PersistConfig { concurrency: 3, ..test_config(&root) };
+
+        assert_eq!(load_wal(&config).await.unwrap(), 4);
+        let conn = Connection::open_in_memory().unwrap();
+        let counts: Vec<i64> = projects.iter().map(|project| {
+            let sql = format!("SELECT COUNT(*) FROM read_parquet('{}')", root.join(project).join("data.parquet").to_str().unwrap());
+            conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+        }).collect();
+        assert_eq!(counts, vec![2, 1, 1]);
+        assert_eq!(load_wal(&config).await.unwrap(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_connection_limit() {
+        let root = test_root("zeta-test-connection-limit");
+        let projects = ["p", "q", "r", "s", "t", "u"];
+        let rows: Vec<(&str, &str, &str)> = projects.iter().map(|p| (*p, "data.parquet", "1, 2")).collect();
+        create_test_wal(&root, &rows).await;
+        let config = PersistConfig { concurrency: 6, connections: Arc::new(ConnectionLimit::new(Some(2))), ..test_config(&root) };
+
+        assert_eq!(load_wal(&config).await.unwrap(), 6);
+        assert!((1..=2).contains(&config.connections.peak()), "{}", config.connections.peak());
+        assert_eq!(config.connections.open.load(Ordering::SeqCst), 0);
+        for project in projects {
+            assert!(root.join(project).join("data.parquet").exists(), "{}", project);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_drains_on_shutdown() {
+        let root = test_root("zeta-test-shutdown");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        create_test_wal(&root, &[("p", "data.parquet", "1, 2")]).await;
+        let config = test_config(&root);
+        let hour = Duration::from_secs(3600);
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        // Wait out the first cycle, write a row during the long sleep, then
+        // signal shutdown; the loop must wake up early and persist that row.
+        let between_ticks = async {
+            let pool = open_wal(&config).await.unwrap();
+            let left = || sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool);
+            while left().await.unwrap().get::<i64, _>(0) > 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('p', ?1, ?1, '3, 4', 'data.parquet')")
+                .bind(Utc.with_ymd_and_hms(2023, 1, 1, 0, 1, 0).unwrap().to_rfc3339())
+                .execute(&pool).await.unwrap();
+            tx.send(()).unwrap();
+            pool
+        };
+        let shutdown = async { rx.await.unwrap() };
+        let run = run(&config, PollInterval::new(hour, hour, None, hour), shutdown);
+        let (_, pool) = tokio::time::timeout(Duration::from_secs(30), futures::future::join(run, between_ticks)).await
+            .expect("the loop did not exit after the shutdown signal");
+
+        assert_eq!(sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool).await.unwrap().get::<i64, _>(0), 0);
+        pool.close().await;
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT COUNT(*) FROM read_parquet('{}')", root.join("p").join("data.parquet").to_str().unwrap());
+        assert_eq!(conn.query_row(&sql, [], |row| row.get::<_, i64>(0)).unwrap(), 2);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_persists_on_request() {
+        let root = test_root("zeta-test-persist-request");
+        create_test_wal(&root, &[("p", "data.parquet", "1, 2")]).await;
+        let config = test_config(&root);
+        let pool = open_wal(&config).await.unwrap();
+        sqlx::query("CREATE TABLE persist_requests (project_id TEXT PRIMARY KEY, requested_at DATETIME NOT NULL)")
+            .execute(&pool).await.unwrap();
+        let hour = Duration::from_secs(3600);
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let count = || {
+            let conn = Connection::open_in_memory().unwrap();
+            let sql = format!("SELECT COUNT(*) FROM read_parquet('{}')", root.join("p").join("data.parquet").to_str().unwrap());
+            conn.query_row(&sql, [], |row| row.get::<_, i64>(0)).unwrap_or(0)
+        };
+
+        // After the first cycle, write a row and request a persist the way
+        // the querier does; it must be merged long before the hour is up.
+        let requested = async {
+            let left = || sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool);
+            while left().await.unwrap().get::<i64, _>(0) > 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('p', ?1, ?1, '3, 4', 'data.parquet')")
+                .bind(Utc.with_ymd_and_hms(2023, 1, 1, 0, 1, 0).unwrap().to_rfc3339())
+                .execute(&pool).await.unwrap();
+            sqlx::query("INSERT INTO persist_requests VALUES ('p', ?1)").bind(Utc::now().to_rfc3339()).execute(&pool).await.unwrap();
+            while count() < 2 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            tx.send(()).unwrap();
+        };
+        let shutdown = async { rx.await.unwrap() };
+        let run = run(&config, PollInterval::new(hour, hour, None, hour), shutdown);
+        tokio::time::timeout(Duration::from_secs(30), futures::future::join(run, requested)).await
+            .expect("the requested persist did not happen before the next cycle");
+
+        let requests: i64 = sqlx::query("SELECT COUNT(*) FROM persist_requests").fetch_one(&pool).await.unwrap().get(0);
+        assert_eq!(requests, 0);
+        pool.close().await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_merge_window() {
+        let record = |i: i64, destination: &str, time: &str| (i, Record {
+            destination: destination.to_string(),
+            time: DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc),
+            values: vec![i as f64],
+            field_names: None,
+            source: None,
+        });
+        let records = || vec![
+            record(1, "a", "2023-01-01T10:00:00Z"),
+            record(2, "a", "2023-01-01T11:00:00Z"),
+            record(3, "a", "2023-01-02T09:00:00Z"),
+            record(4, "a", "2023-01-02T11:30:00Z"),
+            record(5, "b", "2023-01-02T10:00:00Z"),
+        ];
+        let now = Utc.with_ymd_and_hms(2023, 1, 2, 12, 0, 0).unwrap();
+        let hour = Duration::from_secs(3600);
+        let ids = |(ready, held): (Vec<(i64, Record)>, usize)| (ready.iter().map(|(i, _)| *i).collect::<Vec<_>>(), held);
+
+        // The 2nd of January of `a` got a record half an hour ago, so all of
+        // that date waits, even its record from three hours ago.
+        let by_date = MergeOptions { partition_by_date: true, ..Default::default() };
+        assert_eq!(ids(split_merge_window(records(), hour, &by_date, now)), (vec![1, 2, 5], 2));
+        assert_eq!(ids(split_merge_window(records(), hour, &MergeOptions::default(), now)), (vec![5], 4));
+        assert_eq!(ids(split_merge_window(records(), Duration::from_secs(60), &MergeOptions::default(), now)), (vec![1, 2, 3, 4, 5], 0));
+        // Partitioned by hour, only the hour of that record waits.
+        let by_hour = MergeOptions { project_partitions: HashMap::from([("a".to_string(), PartitionGranularity::Hour)]), ..Default::default() };
+        assert_eq!(ids(split_merge_window(records(), hour, &by_hour, now)), (vec![1, 2, 3, 5], 1));
+
+        let root = test_root("zeta-test-merge-window");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        create_test_wal(&root, &[("p", "old.parquet", "1, 2")]).await;
+        let config = PersistConfig { merge_window: Some(hour), ..test_config(&root) };
+        let pool = open_wal(&config).await.unwrap();
+        let recent = Utc::now().to_rfc3339();
+        sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('p', ?1, ?1, '3, 4', 'new.parquet')")
+            .bind(&recent)
+            .execute(&pool).await.unwrap();
+
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert!(root.join("p").join("old.parquet").exists());
+        assert!(!root.join("p").join("new.parquet").exists());
+        let left: Vec<String> = sqlx::query("SELECT schema FROM wal").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| row.get("schema")).collect();
+        assert_eq!(left, vec!["new.parquet"]);
+        pool.close().await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_preprocess_steps() {
+        let trim = PreprocessStep::TrimPrefix("#".to_string());
+        assert_eq!(trim.apply("1", "#1, 2").unwrap(), "1, 2");
+        assert_eq!(trim.apply("1", "1, 2").unwrap(), "1, 2");
+
+        let drop = PreprocessStep::DropLastField;
+        assert_eq!(drop.apply("1", "1, 2, ff").unwrap(), "1, 2");
+        assert_eq!(drop.apply("1", "1").unwrap(), "");
+
+        let extract = PreprocessStep::RegexExtract(regex::Regex::new("v=([^;]*)").unwrap());
+        assert_eq!(extract.apply("1", "id=a;v=1, 2;ok").unwrap(), "1, 2");
+        assert!(matches!(extract.apply("7", "id=a"), Err(PersisterError::PayloadMismatch { row_id, .. }) if row_id == "7"));
+        let whole = PreprocessStep::RegexExtract(regex::Regex::new(r"[\d.]+, [\d.]+").unwrap());
+        assert_eq!(whole.apply("1", "raw 1.5, 2 end").unwrap(), "1.5, 2");
+
+        let pipelines = parse_preprocess(r#"{"p": [{"trim_prefix": ">"}, {"regex_extract": "v=(.*)"}, "drop_last_field"]}"#).unwrap();
+        assert_eq!(preprocess_payload(&pipelines["p"], "1", ">v=3, 4, 9f".to_string()).unwrap(), "3, 4");
+
+        assert!(parse_preprocess(r#"{"p": ["reverse"]}"#).unwrap_err().starts_with("PAYLOAD_PREPROCESS"));
+        assert!(parse_preprocess(r#"{"p": [{"regex_extract": "("}]}"#).unwrap_err().starts_with("PAYLOAD_PREPROCESS"));
+    }
+
+    #[tokio::test]
+    async fn test_load_wal_preprocess() {
+        let root = test_root("zeta-test-preprocess");
+        std::fs::create_dir_all(root.join("p")).unwrap();
+        std::fs::create_dir_all(root.join("q")).unwrap();
+        create_test_wal(&root, &[
+            ("p", "data.parquet", "#1, 2, c0"),
+            ("p", "data.parquet", "#3, 4, c1"),
+            ("q", "data.parquet", "5, 6"),
+        ]).await;
+
+        let config = PersistConfig {
+            preprocess: parse_preprocess(r##"{"p": [{"trim_prefix": "#"}, "drop_last_field"]}"##).unwrap(),
+            ..test_config(&root)
+        };
+        assert_eq!(load_wal(&config).await.unwrap(), 3);
+
+        let conn = Connection::open_in_memory().unwrap();
+        for (project, expected) in [("p", (4.0, 6.0)), ("q", (5.0, 6.0))] {
+            let sql = format!("SELECT SUM(f0), SUM(f1) FROM read_parquet('{}')", root.join(project).join("data.parquet").to_str().unwrap());
+            let sums: (f64, f64) = conn.query_row(&sql, [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+            assert_eq!(sums, expected);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_ready() {
+        let root = test_root("zeta-test-startup-wait");
+        std::fs::remove_dir_all(&root).unwrap();
+        let config = test_config(&root);
+        let poll = Duration::from_millis(20);
+        assert!(matches!(wait_until_ready(&config, Duration::ZERO, poll).await, Err(PersisterError::NotReady(_))));
+
+        std::fs::create_dir_all(&root).unwrap();
+        let appearing = root.clone();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            create_test_wal(&appearing, &[]).await;
+        });
+        let started = Instant::now();
+        wait_until_ready(&config, Duration::from_secs(10), poll).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        writer.await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_heartbeat() {
+        let root = test_root("zeta-test-heartbeat");
+        create_test_wal(&root, &[]).await;
+        let config = test_config(&root);
+        record_heartbeat(&config, 3, Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()).await.unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 10).unwrap();
+        record_heartbeat(&config, 5, now).await.unwrap();
+
+        let pool = open_wal(&config).await.unwrap();
+        let rows = sqlx::query("SELECT * FROM heartbeat").fetch_all(&pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<String, _>("last_cycle_at"), now.to_rfc3339());
+        assert_eq!(rows[0].get::<i64, _>("last_batch_rows"), 5);
+        pool.close().await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_extension_statements() {
+        assert_eq!(extension_statements(&[]), vec![
+            ("parquet".to_string(), "INSTALL parquet; LOAD parquet;".to_string()),
+        ]);
+
+        let extensions = parse_extensions("json, spatial, parquet").unwrap();
+        let statements: Vec<String> = extension_statements(&extensions).into_iter().map(|(_, sql)| sql).collect();
+        assert_eq!(statements, vec![
+            "INSTALL parquet; LOAD parquet;",
+            "INSTALL json; LOAD json;",
+            "INSTALL spatial; LOAD spatial;",
+        ]);
+
+        assert!(parse_extensions("json; DROP TABLE tmp").is_err());
+        assert!(open_connection(&["no_such_extension".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_destination_lock() {
+        let dir = std::env::temp_dir().join(format!("zeta-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let records = |hour| vec![Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values: vec![1.0],
+            field_names: None,
+            source: None,
+        }];
+        merge_standalone(&parquet, records(0), &MergeOptions::default()).unwrap();
+        let before = std::fs::read(&parquet).unwrap();
+
+        let held = lock_destination(&parquet, Duration::ZERO).unwrap();
+        let options = MergeOptions { lock_timeout: Duration::from_millis(200), ..Default::default() };
+        let started = Instant::now();
+        assert!(merge_standalone(&parquet, records(1), &options).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(std::fs::read(&parquet).unwrap(), before);
+
+        drop(held);
+        merge_standalone(&parquet, records(1), &options).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_profiling() {
+        let dir = std::env::temp_dir().join(format!("zeta-profile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let profiles = dir.join("profiles");
+        std::fs::create_dir_all(&profiles).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let records = vec![Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+            values: vec![1.0],
+            field_names: None,
+            source: None,
+        }];
+        let options = MergeOptions { profile_dir: Some(profiles.clone()), ..Default::default() };
+        merge_standalone(&parquet, records, &options).unwrap();
+
+        let files: Vec<PathBuf> = std::fs::read_dir(&profiles).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("merge-") && name.ends_with(".json"), "{}", name);
+        assert!(std::fs::read_to_string(&files[0]).unwrap().contains("COPY"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_widest_record() {
+        let dir = std::env::temp_dir().join(format!("zeta-widest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let record = |hour, values: Vec<f64>| Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values,
+            field_names: None,
+            source: None,
+        };
+        merge_standalone(&parquet, vec![record(0, vec![1.0, 2.0]), record(1, vec![3.0, 4.0, 5.0, 6.0])], &MergeOptions::default()).unwrap();
+        merge_standalone(&parquet, vec![record(2, vec![7.0])], &MergeOptions::default()).unwrap();
+        merge_standalone(&parquet, vec![record(3, vec![8.0, 9.0, 10.0, 11.0, 12.0])], &MergeOptions::default()).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT COALESCE(f3, -1), COALESCE(f4, -1) FROM read_parquet('{}') ORDER BY time", parquet);
+        let rows: Vec<(f64, f64)> = conn.prepare(&sql).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows, vec![(-1.0, -1.0), (6.0, -1.0), (-1.0, -1.0), (11.0, 12.0)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_fields() {
+        let dir = std::env::temp_dir().join(format!("zeta-duplicate-fields-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let record = Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+            values: vec![1.0, 2.0, 3.0, 4.0],
+            field_names: Some(vec!["temp".to_string(), "TEMP".to_string(), "temp_1".to_string(), "temp".to_string()]),
+            source: None,
+        };
+
+        let result = merge_standalone(&parquet, vec![record.clone()], &MergeOptions::default());
+        assert!(matches!(result, Err(PersisterError::DuplicateField { ref name, .. }) if name == "TEMP"), "{:?}", result.err());
+        assert!(!Path::new(&parquet).exists());
+
+        let options = MergeOptions { duplicate_fields: DuplicateFields::Suffix, ..Default::default() };
+        merge_standalone(&parquet, vec![record], &options).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT temp, TEMP_2, temp_1, temp_3 FROM read_parquet('{}')", parquet);
+        let row: (f64, f64, f64, f64) = conn.query_row(&sql, [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        assert_eq!(row, (1.0, 2.0, 3.0, 4.0));
+        let names: Vec<String> = conn.prepare(&format!("DESCRIBE SELECT * FROM read_parquet('{}')", parquet)).unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(names, vec!["time", "temp", "TEMP_2", "temp_1", "temp_3"]);
+
+        assert_eq!(parse_duplicate_fields("Suffix").unwrap(), DuplicateFields::Suffix);
+        assert!(parse_duplicate_fields("merge").unwrap_err().starts_with("DUPLICATE_FIELDS"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_widens_existing_file() {
+        let dir = std::env::temp_dir().join(format!("zeta-widen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let record = |hour, values: Vec<f64>| Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values,
+            field_names: None,
+            source: None,
+        };
+        merge_standalone(&parquet, vec![record(0, vec![1.0, 2.0]), record(1, vec![3.0, 4.0])], &MergeOptions::default()).unwrap();
+        merge_standalone(&parquet, vec![record(2, vec![5.0, 6.0, 7.0, 8.0])], &MergeOptions::default()).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT f0, f1, f2, f3 FROM read_parquet('{}') ORDER BY time", parquet);
+        type Row = (f64, f64, Option<f64>, Option<f64>);
+        let rows: Vec<Row> = conn.prepare(&sql).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows, vec![(1.0, 2.0, None, None), (3.0, 4.0, None, None), (5.0, 6.0, Some(7.0), Some(8.0))]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_schema_width() {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        let dir = std::env::temp_dir().join(format!("zeta-max-width-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let record = |hour, values: Vec<f64>| Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values,
+            field_names: None,
+            source: None,
+        };
+        let options = MergeOptions { max_schema_width: Some(3), ..Default::default() };
+        let columns = || {
+            let conn = Connection::open_in_memory().unwrap();
+            let sql = format!("SELECT COUNT(*) - 1 FROM parquet_schema('{}') WHERE name <> 'duckdb_schema'", parquet);
+            conn.query_row(&sql, [], |row| row.get::<_, i64>(0)).unwrap()
+        };
+
+        merge_standalone(&parquet, vec![record(0, vec![1.0, 2.0])], &options).unwrap();
+        merge_standalone(&parquet, vec![record(1, vec![3.0, 4.0, 5.0])], &options).unwrap();
+        assert_eq!(columns(), 3);
+        assert!(!LOGGER.records.lock().unwrap().iter().any(|(level, d)| *level == log::Level::Warn && d.as_deref() == Some(parquet.as_str())));
+
+        merge_standalone(&parquet, vec![record(2, vec![6.0, 7.0, 8.0, 9.0, 10.0])], &options).unwrap();
+        assert_eq!(columns(), 3);
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT f0, f2 FROM read_parquet('{}') WHERE f0 = 6", parquet);
+        assert_eq!(conn.query_row(&sql, [], |row| Ok((row.get::<_, f64>(0)?, row.get::<_, f64>(1)?))).unwrap(), (6.0, 8.0));
+        assert!(LOGGER.records.lock().unwrap().iter().any(|(level, d)| *level == log::Level::Warn && d.as_deref() == Some(parquet.as_str())));
+
+        // A file already wider than the cap keeps its columns.
+        merge_standalone(&parquet, vec![record(3, vec![11.0])], &MergeOptions { max_schema_width: Some(1), ..Default::default() }).unwrap();
+        assert_eq!(columns(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_field_names() {
+        let dir = std::env::temp_dir().join(format!("zeta-field-names-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let record = |hour, values: Vec<f64>, names: Option<&[&str]>| Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values,
+            field_names: names.map(|names| names.iter().map(|n| n.to_string()).collect()),
+            source: None,
+        };
+        merge_standalone(&parquet, vec![record(0, vec![0.5, 128.0], Some(&["cpu", "mem"]))], &MergeOptions::default()).unwrap();
+        // Aligned by name, not position, with a new column for `disk`.
+        merge_standalone(&parquet, vec![record(1, vec![256.0, 0.25, 3.0], Some(&["mem", "cpu", "disk"]))], &MergeOptions::default()).unwrap();
+        merge_standalone(&parquet, vec![record(2, vec![0.75], Some(&["cpu"]))], &MergeOptions::default()).unwrap();
+        assert!(matches!(
+            merge_standalone(&parquet, vec![record(3, vec![1.0], Some(&["time"]))], &MergeOptions::default()),
+            Err(PersisterError::InvalidValue(name)) if name == "time"
+        ));
+
+        let conn = Connection::open_in_memory().unwrap();
+        let columns: Vec<String> = conn.prepare(&format!("DESCRIBE SELECT * FROM read_parquet('{}')", parquet)).unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(columns, vec!["time", "cpu", "mem", "disk"]);
+        let sql = format!("SELECT cpu, mem, disk FROM read_parquet('{}') ORDER BY time", parquet);
+        let rows: Vec<(f64, Option<f64>, Option<f64>)> = conn.prepare(&sql).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows, vec![(0.5, Some(128.0), None), (0.25, Some(256.0), Some(3.0)), (0.75, None, None)]);
+
+        assert_eq!(parse_fields(r#"{"mem": 2, "cpu": 0.5}"#, FieldOrder::Alphabetical).unwrap(), (vec![0.5, 2.0], Some(vec!["cpu".to_string(), "mem".to_string()])));
+        assert_eq!(parse_fields("1, 2", FieldOrder::Alphabetical).unwrap(), (vec![1.0, 2.0], None));
+        assert!(matches!(parse_fields(r#"{"cpu": "high"}"#, FieldOrder::Alphabetical), Err(InvalidValue(v)) if v == r#""high""#));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unexpected_file() {
+        let dir = std::env::temp_dir().join(format!("zeta-identity-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let parquet = dir.join("a.parquet").to_string_lossy().into_owned();
+        let records = |hour| vec![Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values: vec![1.0],
+            field_names: None,
+            source: None,
+        }];
+        let identity = |id: &str| MergeOptions { expected_identity: Some(id.to_string()), ..Default::default() };
+
+        merge_standalone(&parquet, records(0), &identity("a")).unwrap();
+        merge_standalone(&parquet, records(1), &identity("a")).unwrap();
+        let before = std::fs::read(&parquet).unwrap();
+
+        let result = merge_standalone(&parquet, records(2), &identity("b"));
+        assert!(matches!(result, Err(PersisterError::UnexpectedFile { found: Some(ref f), .. }) if f == "a"));
+        assert_eq!(std::fs::read(&parquet).unwrap(), before);
+
+        std::fs::remove_file(format!("{}.identity", parquet)).unwrap();
+        assert!(matches!(merge_standalone(&parquet, records(2), &identity("a")), Err(PersisterError::UnexpectedFile { found: None, .. })));
+        merge_standalone(&parquet, records(2), &MergeOptions::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_partition_granularity() {
+        let root = test_root("zeta-test-partition-granularity");
+        let rows: Vec<(&str, &str, &str)> = ["hourly", "daily", "monthly", "plain"].iter().flat_map(|&p| [(p, "data", "1"), (p, "data", "2")]).collect();
+        create_test_wal(&root, &rows).await;
+        // A second hour, day and month for each project.
+        let pool = open_wal(&test_config(&root)).await.unwrap();
+        for p in ["hourly", "daily", "monthly", "plain"] {
+            sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES (?1, '2023-02-02T05:00:00+00:00', '2023-02-02T05:00:00+00:00', '3', 'data')")
+                .bind(p).execute(&pool).await.unwrap();
+        }
+        pool.close().await;
+        let project_partitions = parse_partition_granularities("hourly=hour, daily=Day,monthly=month").unwrap();
+        let config = PersistConfig { merge: MergeOptions { project_partitions, ..Default::default() }, ..test_config(&root) };
+        assert_eq!(load_wal(&config).await.unwrap(), 12);
+
+        let partitions = |project: &str| -> Vec<String> {
+            std::fs::read_dir(root.join(project).join("data")).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .sorted()
+                .collect()
+        };
+        assert_eq!(partitions("hourly"), vec!["hour=2023-01-01T00", "hour=2023-02-02T05"]);
+        assert_eq!(partitions("daily"), vec!["date=2023-01-01", "date=2023-02-02"]);
+        assert_eq!(partitions("monthly"), vec!["month=2023-01", "month=2023-02"]);
+        assert!(root.join("plain").join("data").is_file());
+
+        // Every record is read back, and a later merge keeps them.
+        let conn = Connection::open_in_memory().unwrap();
+        let count = |project: &str| -> i64 {
+            let sql = format!("SELECT COUNT(*) FROM read_parquet('{}/*/*.parquet', hive_partitioning=1)", root.join(project).join("data").display());
+            conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+        };
+        let pool = open_wal(&config).await.unwrap();
+        sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('monthly', '2023-01-05T00:00:00+00:00', '2023-01-05T00:00:00+00:00', '4', 'data')")
+            .execute(&pool).await.unwrap();
+        pool.close().await;
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert_eq!(partitions("monthly"), vec!["month=2023-01", "month=2023-02"]);
+        assert_eq!(count("monthly"), 4);
+
+        // Changing a destination's granularity repartitions it on its next merge.
+        let pool = open_wal(&config).await.unwrap();
+        sqlx::query("INSERT INTO wal (project_id, time, created_at, payload, schema) VALUES ('hourly', '2023-02-02T06:00:00+00:00', '2023-02-02T06:00:00+00:00', '4', 'data')")
+            .execute(&pool).await.unwrap();
+        pool.close().await;
+        let config = PersistConfig { merge: MergeOptions { project_partitions: parse_partition_granularities("hourly=month").unwrap(), ..Default::default() }, ..test_config(&root) };
+        assert_eq!(load_wal(&config).await.unwrap(), 1);
+        assert_eq!(partitions("hourly"), vec!["month=2023-01", "month=2023-02"]);
+        assert_eq!(count("hourly"), 4);
+
+        assert!(parse_partition_granularities("p=week").is_err());
+        assert!(parse_partition_granularities("p").is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_partition_by_date() {
+        let dir = std::env::temp_dir().join(format!("zeta-partitioned-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let destination = dir.join("a").to_string_lossy().into_owned();
+        let record = |day, hour, values: Vec<f64>| Record {
+            destination: "a".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, day, hour, 0, 0).unwrap(),
+            values,
+            field_names: None,
+            source: None,
+        };
+        let options = MergeOptions { partition_by_date: true, ..Default::default() };
+        merge_standalone(&destination, vec![record(1, 0, vec![1.0]), record(1, 12, vec![2.0]), record(2, 0, vec![3.0])], &options).unwrap();
+        merge_standalone(&destination, vec![record(2, 6, vec![4.0, 5.0]), record(3, 0, vec![6.0])], &options).unwrap();
+
+        let mut partitions: Vec<String> = std::fs::read_dir(&destination).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        partitions.sort();
+        assert_eq!(partitions, vec!["date=2023-01-01", "date=2023-01-02", "date=2023-01-03"]);
+        assert!(!Path::new(&tmp_path(&destination)).exists());
+
+        let conn = Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT CAST(date AS VARCHAR), COUNT(*), SUM(f0), COUNT(f1) FROM read_parquet('{}/*/*.parquet', hive_partitioning=1) GROUP BY date ORDER BY date", destination);
+        type Row = (String, i64, f64, i64);
+        let rows: Vec<Row> = conn.prepare(&sql).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows, vec![
+            ("2023-01-01".to_string(), 2, 3.0, 0),
+            ("2023-01-02".to_string(), 2, 7.0, 1),
+            ("2023-01-03".to_string(), 1, 6.0, 0),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_write() {
+        let dir = std::env::temp_dir().join(format!("zeta-interrupted-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("p")).unwrap();
+        let parquet = dir.join("p").join("a.parquet").to_string_lossy().into_owned();
+        let records = |hour| vec![Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values: vec![1.0],
+            field_names: None,
+            source: None,
+        }];
+        merge_standalone(&parquet, records(0), &MergeOptions::default()).unwrap();
+
+        // A directory in the way of the temporary file makes the COPY fail
+        // part way through the merge.
+        let tmp = tmp_path(&parquet);
+        assert!(tmp.ends_with("/p/.a.parquet.tmp"));
+        std::fs::create_dir(&tmp).unwrap();
+        assert!(merge_standalone(&parquet, records(1), &MergeOptions::default()).is_err());
+        std::fs::remove_dir(&tmp).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        let count = |conn: &Connection| conn.query_row(&format!("SELECT COUNT(*) FROM read_parquet('{}')", parquet), [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count(&conn), 1);
+
+        // A destination merely named like a temporary file is kept.
+        let named_tmp = dir.join("p").join("x.tmp").to_string_lossy().into_owned();
+        merge_standalone(&named_tmp, records(0), &MergeOptions::default()).unwrap();
+        std::fs::write(&tmp, b"PAR1 truncated").unwrap();
+        assert_eq!(remove_stale_tmp_files(&dir).unwrap(), 1);
+        assert!(!Path::new(&tmp).exists());
+        assert!(Path::new(&named_tmp).exists());
+        assert_eq!(count(&conn), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_partition_swap() {
+        let dir = std::env::temp_dir().join(format!("zeta-interrupted-swap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let destination = dir.join("a").to_string_lossy().into_owned();
+        let record = |day| Record {
+            destination: "a".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, day, 0, 0, 0).unwrap(),
+            values: vec![day as f64],
+            field_names: None,
+            source: None,
+        };
+        let options = MergeOptions { partition_by_date: true, ..Default::default() };
+        merge_standalone(&destination, vec![record(1), record(2)], &options).unwrap();
+        assert!(!Path::new(&old_path(&destination)).exists());
+        let conn = Connection::open_in_memory().unwrap();
+        let sum = |conn: &Connection| conn.query_row(
+            &format!("SELECT SUM(f0) FROM read_parquet('{}/*/*.parquet', hive_partitioning=1)", destination), [], |row| row.get::<_, f64>(0),
+        ).unwrap();
+
+        // Interrupted after the previous partitions were moved aside and
+        // before the new ones took their place.
+        std::fs::rename(&destination, old_path(&destination)).unwrap();
+        std::fs::create_dir_all(Path::new(&tmp_path(&destination)).join("date=2023-01-03")).unwrap();
+        std::fs::write(Path::new(&tmp_path(&destination)).join("date=2023-01-03").join("data_0.parquet"), b"PAR1 truncated").unwrap();
+        assert_eq!(remove_stale_tmp_files(&dir).unwrap(), 2);
+        assert!(!Path::new(&old_path(&destination)).exists());
+        assert!(!Path::new(&tmp_path(&destination)).exists());
+        assert_eq!(sum(&conn), 3.0);
+
+        // Interrupted after the swap, before the previous ones were removed.
+        merge_standalone(&destination, vec![record(3)], &options).unwrap();
+        std::fs::create_dir_all(Path::new(&old_path(&destination)).join("date=2023-01-01")).unwrap();
+        assert_eq!(remove_stale_tmp_files(&dir).unwrap(), 1);
+        assert!(!Path::new(&old_path(&destination)).exists());
+        assert_eq!(sum(&conn), 6.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_modes() {
+        let dir = std::env::temp_dir().join(format!("zeta-merge-modes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let record = |hour, value| Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values: vec![value],
+            field_names: None,
+            source: None,
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        let values = |parquet: &str| -> Vec<f64> {
+            conn.prepare(&format!("SELECT f0 FROM read_parquet('{}') ORDER BY time", parquet)).unwrap()
+                .query_map([], |row| row.get(0)).unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        };
+
+        for (mode, expected) in [(MergeMode::Replace, Some(vec![2.0, 4.0])), (MergeMode::Skip, Some(vec![1.0, 3.0])), (MergeMode::Error, None)] {
+            let parquet = dir.join(format!("{:?}.parquet", mode)).to_string_lossy().into_owned();
+            let options = MergeOptions { mode, ..Default::default() };
+            merge_standalone(&parquet, vec![record(0, 1.0)], &options).unwrap();
+
+            let before = conflicts_resolved();
+            let result = merge_standalone(&parquet, vec![record(0, 2.0), record(1, 3.0), record(1, 4.0)], &options);
+            match expected {
+                Some(expected) => {
+                    // One record shares its time with the file, and two with
+                    // each other.
+                    assert_eq!(result.unwrap(), 2, "{:?}", mode);
+                    assert!(conflicts_resolved() - before >= 2);
+                    assert_eq!(values(&parquet), expected, "{:?}", mode);
+                }
+                None => {
+                    assert!(matches!(result, Err(PersisterError::DuckDb(_))));
+                    assert_eq!(values(&parquet), vec![1.0]);
+                }
+            }
+        }
+
+        assert_eq!(parse_merge_mode("Skip").unwrap(), MergeMode::Skip);
+        assert!(parse_merge_mode("upsert").unwrap_err().starts_with("MERGE_MODE"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_timestamp_unit() {
+        let dir = std::env::temp_dir().join(format!("zeta-timestamp-unit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let records = |hour| vec![Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.with_ymd_and_hms(2023, 1, 1, hour, 0, 0).unwrap(),
+            values: vec![1.0],
+            field_names: None,
+            source: None,
+        }];
+        let conn = Connection::open_in_memory().unwrap();
+        for (unit, expected) in [(TimestampUnit::Micros, "TIMESTAMP_MICROS"), (TimestampUnit::Millis, "TIMESTAMP_MILLIS")] {
+            let parquet = dir.join(format!("{:?}.parquet", unit)).to_string_lossy().into_owned();
+            let options = MergeOptions { timestamp_unit: unit, ..Default::default() };
+            merge_standalone(&parquet, records(0), &options).unwrap();
+            merge_standalone(&parquet, records(1), &options).unwrap();
+
+            let sql = format!("SELECT converted_type FROM parquet_schema('{}') WHERE name = 'time'", parquet);
+            let converted: String = conn.query_row(&sql, [], |row| row.get(0)).unwrap();
+            assert_eq!(converted, expected);
+            let sql = format!("SELECT COUNT(*) FROM read_parquet('{}')", parquet);
+            assert_eq!(conn.query_row(&sql, [], |row| row.get::<_, i64>(0)).unwrap(), 2);
+        }
+
+        assert_eq!(parse_timestamp_unit(" Millis ").unwrap(), TimestampUnit::Millis);
+        assert!(parse_timestamp_unit("nanos").unwrap_err().starts_with("PARQUET_TIMESTAMP_UNIT"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let dir = std::env::temp_dir().join(format!("zeta-compression-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let records: Vec<Record> = (0..100).map(|i| Record {
+            destination: "a.parquet".to_string(),
+            time: Utc.timestamp_opt(1_672_531_200 + i, 0).unwrap(),
+            values: vec![1.0, i as f64],
+            field_names: None,
+            source: None,
+        }).collect();
+        let conn = Connection::open_in_memory().unwrap();
+        for (compression, expected) in [
+            (Compression::Snappy, "SNAPPY"),
+            (Compression::Zstd, "ZSTD"),
+            (Compression::Uncompressed, "UNCOMPRESSED"),
+        ] {
+            let parquet = dir.join(format!("{:?}.parquet", compression)).to_string_lossy().into_owned();
+            let options = MergeOptions { compression, ..Default::default() };
+            merge_standalone(&parquet, records.clone(), &options).unwrap();
+
+            let sql = format!("SELECT DISTINCT compression FROM parquet_metadata('{}') WHERE path_in_schema = 'f1'", parquet);
+            assert_eq!(conn.query_row(&sql, [], |row| row.get::<_, String>(0)).unwrap(), expected);
+            let sql = format!("SELECT COUNT(*), SUM(f1) FROM read_parquet('{}')", parquet);
+            let (count, sum) = conn.query_row(&sql, [], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, f64>(1)?))).unwrap();
+            assert_eq!((count, sum), (100, 4950.0));
+        }
+
+        assert_eq!(MergeOptions::default().compression, Compression::Zstd);
+        assert_eq!(parse_compression(" ZSTD ").unwrap(), Compression::Zstd);
+        assert_eq!(parse_compression("none").unwrap(), Compression::Uncompressed);
+        assert!(parse_compression("gzip2").unwrap_err().starts_with("PARQUET_COMPRESSION"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        let secs = Duration::from_secs;
+        let mut interval = PollInterval::new(secs(1), secs(40), Some(100), secs(10));
+        assert_eq!(interval.next(10), secs(20));
+        assert_eq!(interval.next(0), secs(40));
+        assert_eq!(interval.next(0), secs(40));
+        assert_eq!(interval.next(100), secs(40));
+        assert_eq!(interval.next(500), secs(20));
+        assert_eq!(interval.next(500), secs(10));
+        assert_eq!(interval.next(500), secs(5));
+        assert_eq!(interval.next(500), Duration::from_millis(2500));
+        assert_eq!(interval.next(500), Duration::from_millis(1250));
+        assert_eq!(interval.next(500), secs(1));
+        assert_eq!(interval.next(500), secs(1));
+
+        let mut fixed = PollInterval::new(secs(1), secs(60), None, secs(10));
+        assert_eq!(fixed.next(0), secs(10));
+        assert_eq!(fixed.next(1000), secs(10));
+    }
+
+    #[test]
+    fn test_poll_interval_from() {
+        let secs = Duration::from_secs;
+        assert_eq!(poll_interval_from(secs(5), None, None, None).unwrap().next(0), secs(5));
+        let interval = poll_interval_from(secs(5), None, None, Some(100)).unwrap();
+        assert_eq!((interval.min, interval.max, interval.target_rows), (secs(1), secs(60), Some(100)));
+        assert!(poll_interval_from(secs(10), Some(90), None, None).unwrap_err().starts_with("PERSIST_INTERVAL_MIN_SECS"));
+        assert!(poll_interval_from(secs(10), Some(0), None, None).unwrap_err().starts_with("PERSIST_INTERVAL_MIN_SECS"));
+    }
+
+    #[test]
+    fn test_parse_poll_interval() {
+        assert_eq!(parse_poll_interval(None), Duration::from_secs(10));
+        assert_eq!(parse_poll_interval(Some(" 5 ")), Duration::from_secs(5));
+        for garbage in ["soon", "0", "-3", ""] {
+            assert_eq!(parse_poll_interval(Some(garbage)), Duration::from_secs(10), "{:?}", garbage);
+        }
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let root = std::env::temp_dir();
+        std::env::set_var("DATA_ROOT", &root);
+        assert_eq!(validate_config().unwrap()[0], format!("DATA_ROOT: {}", root.to_str().unwrap()));
+
+        std::env::set_var("WRITE_QUORUM", "2");
+        assert!(validate_config().unwrap_err().starts_with("WRITE_QUORUM"));
+        std::env::remove_var("WRITE_QUORUM");
+
+        std::env::set_var("PERSIST_CONCURRENCY", "0");
+        assert!(validate_config().unwrap_err().starts_with("PERSIST_CONCURRENCY"));
+        std::env::remove_var("PERSIST_CONCURRENCY");
+
+        std::env::set_var("DUCKDB_MAX_CONNS", "0");
+        assert!(validate_config().unwrap_err().starts_with("DUCKDB_MAX_CONNS"));
+        std::env::set_var("DUCKDB_MAX_CONNS", "2");
+        assert!(validate_config().unwrap().contains(&"DUCKDB_MAX_CONNS: 2".to_string()));
+        std::env::remove_var("DUCKDB_MAX_CONNS");
+
+        std::env::set_var("FAIL_ON_UNEXPECTED_FILE", "true");
+        assert!(validate_config().unwrap_err().starts_with("DESTINATION_IDENTITY"));
+        std::env::set_var("DESTINATION_IDENTITY", "writer-a");
+        assert!(validate_config().unwrap().contains(&"FAIL_ON_UNEXPECTED_FILE: writer-a".to_string()));
+        std::env::remove_var("FAIL_ON_UNEXPECTED_FILE");
+        std::env::remove_var("DESTINATION_IDENTITY");
+
+        std::env::set_var("DATA_ROOT", root.join("zeta-no-such-dir"));
+        assert!(validate_config().unwrap_err().starts_with("DATA_ROOT"));
+        std::env::remove_var("DATA_ROOT");
+    }
+}
+
+
+/// Settings for a persist cycle.
+pub struct PersistConfig {
+    /// Root holding `wal.sqlite`.
+    pub data_root: String,
+    /// Roots every destination is written to.
+    pub data_roots: Vec<String>,
+    pub quorum: WriteQuorum,
+    /// Whether payloads without any value are persisted as value-less rows.
+    pub allow_empty_records: bool,
+    pub merge: MergeOptions,
+    /// Preprocessing applied to the raw payloads of each project.
+    pub preprocess: HashMap<String, Vec<PreprocessStep>>,
+    /// How long a partition's newest record must be old before the partition
+    /// is merged; younger partitions wait in the WAL for late records.
+    pub merge_window: Option<Duration>,
+    /// How many destinations are merged at the same time.
+    pub concurrency: usize,
+    /// The DuckDB connections the merges may hold open at once.
+    pub connections: Arc<ConnectionLimit>,
+    /// How many failed persists a WAL row takes before it is dead-lettered;
+    /// `None` retries it forever.
+    pub max_attempts: Option<u32>,
+    /// Order of the fields of JSON object payloads.
+    pub field_order: FieldOrder,
+    /// How far back `--reconcile` compares the rows deleted from the WAL.
+    pub reconcile_window: Duration,
+}
+
+/// A step rewriting a raw payload before it is split into values.
+#[derive(Clone, Debug)]
+pub enum PreprocessStep {
+    /// Removes the prefix if the payload starts with it.
+    TrimPrefix(String),
+    /// Removes the last comma-separated field, such as a trailing checksum.
+    DropLastField,
+    /// Keeps the first capture group of the match, or the whole match when
+    /// the pattern has no group.
+    RegexExtract(regex::Regex),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PreprocessStepConfig {
+    TrimPrefix(String),
+    DropLastField,
+    RegexExtract(String),
+}
+
+impl PreprocessStep {
+    fn apply(&self, row_id: &str, payload: &str) -> Result<String> {
+        match self {
+            PreprocessStep::TrimPrefix(prefix) => Ok(payload.strip_prefix(prefix.as_str()).unwrap_or(payload).to_string()),
+            PreprocessStep::DropLastField => Ok(payload.rsplit_once(',').map(|(rest, _)| rest).unwrap_or("").to_string()),
+            PreprocessStep::RegexExtract(re) => re.captures(payload)
+                .and_then(|c| c.get(1).or_else(|| c.get(0)))
+                .map(|m| m.as_str().to_string())
+                .ok_or_else(|| PersisterError::PayloadMismatch { row_id: row_id.to_string(), pattern: re.as_str().to_string() }),
+        }
+    }
+}
+
+/// Runs `steps` over the payload of WAL row `row_id` in order.
+fn preprocess_payload(steps: &[PreprocessStep], row_id: &str, payload: String) -> Result<String> {
+    steps.iter().try_fold(payload, |payload, step| step.apply(row_id, &payload))
+}
+
+async fn open_wal(config: &PersistConfig) -> Result<SqlitePool> {
+    let wal_path = Path::new(&config.data_root).join("wal.sqlite");
+    let db_url = match wal_path.to_str() {
+        Some(path) => format!("sqlite://{}", path),
+        None => return Err(PersisterError::InvalidPath(wal_path)),
+    };
+    Ok(SqlitePool::connect(&db_url).await?)
+}
+
+/// Loads pending WAL rows into Parquet, returning how many rows were read.
+/// Rows that cannot be turned into a record are logged and skipped. The rows
+/// of each destination that was written are deleted from the WAL; those of a
+/// failed destination stay for the next cycle with one more attempt, until
+/// they reach `max_attempts` and are dead-lettered. The first failure is
+/// returned. Every row deleted as persisted is recorded in `persisted_rows`
+/// for `reconcile`, which keeps the last `reconcile_window` of them.
+async fn load_wal(config: &PersistConfig) -> Result<usize> {
+    let pool = open_wal(config).await?;
+
+    // The querier records paused projects; a WAL it has not initialized has
+    // no such table.
+    let paused: Vec<String> = match sqlx::query("SELECT project_id FROM paused_projects").fetch_all(&pool).await {
+        Ok(rows) => rows.iter().map(|row| row.try_get("project_id")).collect::<std::result::Result<_, _>>()?,
+        Err(sqlx::Error::Database(e)) if e.message().contains("no such table") => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    if !paused.is_empty() {
+        log::info!(projects = paused.join(",").as_str(); "skipping the WAL rows of paused projects");
+    }
+    let query = if paused.is_empty() {
+        "SELECT rowid, * FROM wal"
+    } else {
+        "SELECT rowid, * FROM wal WHERE project_id NOT IN (SELECT project_id FROM paused_projects)"
+    };
+
+    let mut pending: Vec<(i64, Record)> = vec![];
+    let mut dead: Vec<(i64, String)> = vec![];
+    let mut rows = sqlx::query(query).fetch(&pool);
+    while let Some(row) = rows.try_next().await? {
+        let row_id: i64 = row.try_get("rowid")?;
+        match wal_record(&row, config) {
+            Ok(record) => pending.push((row_id, record)),
+            Err(e) => {
+                log::error!(row_id = row_id, error:% = e; "moving a WAL row to the dead letters");
+                dead.push((row_id, e.to_string()));
+            }
+        }
+    }
+    drop(rows);
+    if !dead.is_empty() {
+        dead_letter_rows(&pool, &dead, Utc::now()).await?;
+    }
+
+    let pending = match config.merge_window {
+        Some(window) => {
+            let (ready, held) = split_merge_window(pending, window, &config.merge, Utc::now());
+            if held > 0 {
+                log::info!(rows = held, window_secs = window.as_secs(); "holding records of partitions still within the merge window");
+            }
+            ready
+        }
+        None => pending,
+    };
+    let mut new_rows: Vec<Record> = vec![];
+    let mut row_ids: HashMap<String, Vec<(i64, DateTime<Utc>)>> = HashMap::new();
+    for (row_id, record) in pending {
+        row_ids.entry(record.destination.clone()).or_default().push((row_id, record.time));
+        new_rows.push(record);
+    }
+
+    let row_count = new_rows.len();
+    let new_rows = if config.merge.wide { join_wide_records(new_rows) } else { new_rows };
+    let new_row_groups = new_rows.into_iter().into_group_map_by(|r| r.destination.clone());
+
+    let conflicts_before = conflicts_resolved();
+    let outcomes = persist_groups_concurrently(config, config.concurrency, new_row_groups).await?;
+    let conflicts = conflicts_resolved() - conflicts_before;
+    if conflicts > 0 {
+        log::info!(conflicts = conflicts, conflicts_resolved = conflicts_resolved(); "resolved conflicting records this cycle");
+    }
+    let mut first_error = None;
+    let mut exhausted: Vec<(i64, String)> = vec![];
+    sqlx::query(CREATE_PERSISTED_ROWS).execute(&pool).await?;
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+    for (destination, outcome) in outcomes {
+        match outcome {
+            Ok(()) => {
+                for (row_id, time) in row_ids.remove(&destination).unwrap_or_default() {
+                    sqlx::query("DELETE FROM wal WHERE rowid = ?1").bind(row_id).execute(&mut *tx).await?;
+                    sqlx::query("INSERT INTO persisted_rows (destination, time, persisted_at) VALUES (?1, ?2, ?3)")
+                        .bind(&destination).bind(time.to_rfc3339()).bind(now.to_rfc3339())
+                        .execute(&mut *tx).await?;
+                }
+            }
+            Err(e) => {
+                for (row_id, _) in row_ids.remove(&destination).unwrap_or_default() {
+                    let attempts = sqlx::query("UPDATE wal SET attempts = attempts + 1 WHERE rowid = ?1 RETURNING attempts")
+                        .bind(row_id)
+                        .fetch_one(&mut *tx).await;
+                    let attempts: i64 = match attempts {
+                        Ok(row) => row.try_get("attempts")?,
+                        // A WAL created before attempts were tracked retries forever.
+                        Err(sqlx::Error::Database(e)) if e.message().contains("no such column") => break,
+                        Err(e) => return Err(e.into()),
+                    };
+                    if config.max_attempts.is_some_and(|max| attempts >= max as i64) {
+                        exhausted.push((row_id, e.to_string()));
+                    }
+                }
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    sqlx::query("DELETE FROM persisted_rows WHERE persisted_at < ?1")
+        .bind(reconcile_since(config, now).to_rfc3339())
+        .execute(&mut *tx).await?;
+    tx.commit().await?;
+    if !exhausted.is_empty() {
+        log::error!(rows = exhausted.len(), max_attempts = config.max_attempts.unwrap_or_default(); "moving WAL rows out of attempts to the dead letters");
+        dead_letter_rows(&pool, &exhausted, Utc::now()).await?;
+    }
+    pool.close().await;
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(row_count),
+    }
+}
+
+/// Moves WAL rows that can never be persisted into the `dead_letters` table
+/// with the reason, so they are not re-read and re-logged every cycle.
+async fn dead_letter_rows(pool: &SqlitePool, rows: &[(i64, String)], now: DateTime<Utc>) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS dead_letters (
+             project_id       TEXT NOT NULL,
+             schema           TEXT,
+             time             DATETIME NOT NULL,
+             created_at       DATETIME NOT NULL,
+             payload          BLOB NOT NULL,
+             compressed       INTEGER NOT NULL DEFAULT 0,
+             error            TEXT NOT NULL,
+             dead_lettered_at DATETIME NOT NULL
+         )"
+    ).execute(pool).await?;
+    let mut tx = pool.begin().await?;
+    for (row_id, error) in rows {
+        sqlx::query(
+            "INSERT INTO dead_letters (project_id, schema, time, created_at, payload, compressed, error, dead_lettered_at)
+             SELECT project_id, schema, time, created_at, payload, compressed, ?2, ?3 FROM wal WHERE rowid = ?1"
+        ).bind(row_id).bind(error).bind(now.to_rfc3339()).execute(&mut *tx).await?;
+        sqlx::query("DELETE FROM wal WHERE rowid = ?1").bind(row_id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The rows `load_wal` deleted from the WAL as persisted, by destination and
+/// record time.
+const CREATE_PERSISTED_ROWS: &str = "CREATE TABLE IF NOT EXISTS persisted_rows (
+     destination  TEXT NOT NULL,
+     time         DATETIME NOT NULL,
+     persisted_at DATETIME NOT NULL
+ )";
+
+fn reconcile_since(config: &PersistConfig, now: DateTime<Utc>) -> DateTime<Utc> {
+    now - chrono::Duration::from_std(config.reconcile_window).unwrap_or(chrono::Duration::max_value())
+}
+
+/// How the rows deleted from the WAL for one destination compare with its
+/// Parquet file under one data root.
+#[derive(Debug, PartialEq)]
+pub struct Reconciliation {
+    pub path: String,
+    /// Distinct times of the rows deleted as persisted within the window.
+    pub deleted: usize,
+    /// Those of them the Parquet file has no row for, oldest first.
+    pub missing: Vec<DateTime<Utc>>,
+}
+
+/// Compares the rows deleted from the WAL as persisted within the last
+/// `reconcile_window` before `now` against the Parquet file of their
+/// destination under every data root. A WAL without `persisted_rows` has
+/// nothing to compare.
+async fn reconcile(config: &PersistConfig, now: DateTime<Utc>) -> Result<Vec<Reconciliation>> {
+    let pool = open_wal(config).await?;
+    let rows = sqlx::query("SELECT destination, time FROM persisted_rows WHERE persisted_at >= ?1")
+        .bind(reconcile_since(config, now).to_rfc3339())
+        .fetch_all(&pool).await;
+    pool.close().await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(sqlx::Error::Database(e)) if e.message().contains("no such table") => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    let mut deleted: HashMap<String, Vec<DateTime<Utc>>> = HashMap::new();
+    for row in rows {
+        let time: String = row.try_get("time")?;
+        let time = DateTime::parse_from_rfc3339(&time)
+            .map_err(|_| PersisterError::InvalidTime { row_id: "persisted_rows".to_string(), time: time.clone() })?;
+        deleted.entry(row.try_get("destination")?).or_default().push(time.with_timezone(&Utc));
+    }
+
+    let roots = config.data_roots.clone();
+    let options = config.merge.clone();
+    let slot = config.connections.acquire().await;
+    let reconciled = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        let conn = open_connection(&options.extensions)?;
+        let mut reconciled = vec![];
+        for (destination, mut times) in deleted.into_iter().sorted_by(|a, b| a.0.cmp(&b.0)) {
+            times.sort();
+            times.dedup();
+            for root in &roots {
+                let path = Path::new(root).join(&destination).to_string_lossy().into_owned();
+                let missing = missing_times(&conn, &path, &times, &options.for_destination(&destination))?;
+                reconciled.push(Reconciliation { path, deleted: times.len(), missing });
+            }
+        }
+        Ok(reconciled)
+    }).await;
+    reconciled.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+/// The sorted `times` the destination has no row for, comparing them at
+/// the precision its `time` column was written with.
+fn missing_times(conn: &Connection, parquet_path: &str, times: &[DateTime<Utc>], options: &MergeOptions) -> Result<Vec<DateTime<Utc>>> {
+    if !Path::new(parquet_path).exists() {
+        return Ok(times.to_vec());
+    }
+    conn.execute_batch("DROP TABLE IF EXISTS deleted; CREATE TABLE deleted (idx BIGINT, time TIMESTAMP);")?;
+    {
+        let mut appender = conn.appender("deleted")?;
+        for (i, time) in times.iter().enumerate() {
+            appender.append_row(appender_params_from_iter([
+                Value::BigInt(i as i64),
+                Value::Timestamp(TimeUnit::Microsecond, time.timestamp_micros()),
+            ]))?;
+        }
+    }
+    let source = if options.partition_by_date {
+        format!("read_parquet('{}/*/*.parquet', hive_partitioning=1)", parquet_path)
+    } else {
+        format!("read_parquet('{}')", parquet_path)
+    };
+    let sql = format!(
+        "SELECT idx FROM deleted WHERE CAST(CAST(time AS {}) AS TIMESTAMP) NOT IN (SELECT CAST(time AS TIMESTAMP) FROM {}) ORDER BY idx",
+        options.timestamp_unit.column_type(), source,
+    );
+    let missing = conn.prepare(&sql)?.query_map([], |row| row.get::<_, i64>(0))?
+        .map(|idx| idx.map(|i| times[i as usize]))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    conn.execute_batch("DROP TABLE deleted;")?;
+    Ok(missing)
+}
+
+/// Keeps the records of partitions whose newest record is more than `window`
+/// before `now`, returning them with the number of records held back. A
+/// partition is a destination, or one partition of it when partitioned.
+fn split_merge_window(records: Vec<(i64, Record)>, window: Duration, options: &MergeOptions, now: DateTime<Utc>) -> (Vec<(i64, Record)>, usize) {
+    let partition = |r: &Record| (r.destination.clone(), options.partitioning(&r.destination).map(|g| g.partition(r.time)));
+    let mut newest: HashMap<_, DateTime<Utc>> = HashMap::new();
+    for (_, record) in &records {
+        let time = newest.entry(partition(record)).or_insert(record.time);
+        *time = (*time).max(record.time);
+    }
+    let window = chrono::Duration::from_std(window).unwrap_or(chrono::Duration::max_value());
+    let total = records.len();
+    let ready: Vec<(i64, Record)> = records.into_iter()
+        .filter(|(_, r)| now.signed_duration_since(newest[&partition(r)]) > window)
+        .collect();
+    let held = total - ready.len();
+    (ready, held)
+}
+
+/// Waits up to `wait` for every data root to exist and the WAL to open,
+/// checking every `poll`, so the persister can start before the shared
+/// storage is mounted. Returns the last failure once `wait` has passed.
+async fn wait_until_ready(config: &PersistConfig, wait: Duration, poll: Duration) -> Result<()> {
+    let started = Instant::now();
+    loop {
+        let missing = std::iter::once(&config.data_root).chain(&config.data_roots).find(|r| !Path::new(r).is_dir());
+        let result = match missing {
+            Some(root) => Err(PersisterError::NotReady(format!("data root '{}' is not a directory", root))),
+            None => open_wal(config).await.map(|_| ()),
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if started.elapsed() < wait => {
+                log::info!(error:% = e, waited_secs = started.elapsed().as_secs(); "waiting for storage to become ready");
+                tokio::time::sleep(poll).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Records a completed persist cycle in the WAL's single-row `heartbeat`
+/// table, which the querier reads to report persister health.
+async fn record_heartbeat(config: &PersistConfig, batch_rows: usize, now: DateTime<Utc>) -> Result<()> {
+    let pool = open_wal(config).await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS heartbeat (
+             id              INTEGER PRIMARY KEY CHECK (id = 1),
+             last_cycle_at   DATETIME NOT NULL,
+             last_batch_rows INTEGER NOT NULL
+         )"
+    ).execute(&pool).await?;
+    sqlx::query(
+        "INSERT INTO heartbeat (id, last_cycle_at, last_batch_rows) VALUES (1, ?1, ?2)
+         ON CONFLICT (id) DO UPDATE SET last_cycle_at = excluded.last_cycle_at, last_batch_rows = excluded.last_batch_rows"
+    )
+        .bind(now.to_rfc3339())
+        .bind(batch_rows as i64)
+        .execute(&pool).await?;
+    pool.close().await;
+    Ok(())
+}
+
+/// Builds the record for a WAL row, failing for a row that can never be
+/// persisted, such as a value-less row that is not allowed.
+fn wal_record(row: &sqlx::sqlite::SqliteRow, config: &PersistConfig) -> Result<Record> {
+    let row_id = row.try_get::<i64, _>("rowid")?.to_string();
+    let id: String = row.try_get("project_id")?;
+    let schema = row.try_get::<Option<String>, _>("schema").ok().flatten()
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| "default".to_string());
+    let joined = Path::new(&id).join(if config.merge.wide { WIDE_TABLE } else { &schema });
+    let parquet_path = match joined.to_str() {
+        Some(path) => path.to_string(),
+        None => return Err(PersisterError::InvalidPath(joined)),
+    };
+
+    let time: String = row.try_get("time")?;
+    let time = match DateTime::parse_from_rfc3339(&time) {
+        Ok(time) => time.with_timezone(&Utc),
+        Err(_) => return Err(PersisterError::InvalidTime { row_id, time }),
+    };
+
+    let compressed: i64 = row.try_get("compressed").unwrap_or(0);
+    let payload: String = if compressed == 0 {
+        row.try_get("payload")?
+    } else {
+        let bytes: Vec<u8> = row.try_get("payload")?;
+        common::decompress_payload(&bytes).map_err(|source| PersisterError::Decompress { row_id: row_id.clone(), source })?
+    };
+    let payload = match config.preprocess.get(&id) {
+        Some(steps) => preprocess_payload(steps, &row_id, payload)?,
+        None => payload,
+    };
+    if payload.trim().is_empty() && !config.allow_empty_records {
+        return Err(PersisterError::EmptyPayload { row_id });
+    }
+    let (values, field_names) = parse_fields(&payload, config.field_order)
+        .map_err(|InvalidValue(value)| PersisterError::PayloadParse { row_id, value })?;
+    let field_names = if config.merge.wide {
+        let name = |i: usize| field_names.as_ref().and_then(|names| names.get(i)).cloned().unwrap_or_else(|| format!("f{}", i));
+        Some((0..values.len()).map(|i| format!("{}_{}", schema, name(i))).collect())
+    } else {
+        field_names
+    };
+    Ok(Record {
+        destination: parquet_path,
+        time,
+        values,
+        field_names,
+        source: row.try_get::<Option<String>, _>("source").ok().flatten(),
+    })
+}
+
+/// Sleep between persist cycles. With a target batch size the interval
+/// doubles after a smaller batch and halves after a larger one, staying
+/// within `[min, max]`; without one it stays fixed.
+#[derive(Debug)]
+pub struct PollInterval {
+    min: Duration,
+    max: Duration,
+    target_rows: Option<usize>,
+    current: Duration,
+}
+
+impl PollInterval {
+    pub fn new(min: Duration, max: Duration, target_rows: Option<usize>, initial: Duration) -> Self {
+        PollInterval { min, max, target_rows, current: initial.clamp(min, max) }
+    }
+
+    /// Returns the sleep to use after a cycle that read `batch_rows` rows.
+    pub fn next(&mut self, batch_rows: usize) -> Duration {
+        if let Some(target) = self.target_rows {
+            if batch_rows < target {
+                self.current = (self.current * 2).min(self.max);
+            } else if batch_rows > target {
+                self.current = (self.current / 2).max(self.min);
+            }
+        }
+        self.current
+    }
+}
+
+
+fn get_data_root() -> String {
+     env::var("DATA_ROOT").unwrap_or_else(|_| env::current_dir().unwrap().to_str().unwrap().to_string())
+}
+
+/// Roots every destination is written to; `DATA_ROOTS` is comma-separated and
+/// defaults to `DATA_ROOT`.
+fn get_data_roots() -> Vec<String> {
+    match env::var("DATA_ROOTS") {
+        Ok(s) => s.split(',').map(|r| r.trim().to_string()).filter(|r| !r.is_empty()).collect(),
+        Err(_) => vec![get_data_root()],
+    }
+}
+
+fn parse_write_quorum(s: &str) -> std::result::Result<WriteQuorum, String> {
+    match s.trim() {
+        "all" => Ok(WriteQuorum::All),
+        n => match n.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(WriteQuorum::AtLeast(n)),
+            _ => Err(format!("WRITE_QUORUM: expected 'all' or a positive number, got '{}'", n)),
+        },
+    }
+}
+
+fn get_write_quorum() -> std::result::Result<WriteQuorum, String> {
+    match env::var("WRITE_QUORUM") {
+        Ok(s) => parse_write_quorum(&s),
+        Err(_) => Ok(WriteQuorum::All),
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str) -> std::result::Result<Option<T>, String> {
+    match env::var(name) {
+        Ok(s) => s.trim().parse::<T>().map(Some)
+            .map_err(|_| format!("{}: invalid value '{}'", name, s)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_extensions(s: &str) -> std::result::Result<Vec<String>, String> {
+    let extensions: Vec<String> = s.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect();
+    if let Some(e) = extensions.iter().find(|e| !e.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+        return Err(format!("DUCKDB_EXTENSIONS: invalid extension name '{}'", e));
+    }
+    Ok(extensions)
+}
+
+/// Parses `PAYLOAD_PREPROCESS`, a JSON object mapping project ids to their
+/// steps, e.g. `{"p1": [{"trim_prefix": "#"}, "drop_last_field"]}`.
+fn parse_preprocess(s: &str) -> std::result::Result<HashMap<String, Vec<PreprocessStep>>, String> {
+    let configs: HashMap<String, Vec<PreprocessStepConfig>> = serde_json::from_str(s)
+        .map_err(|e| format!("PAYLOAD_PREPROCESS: {}", e))?;
+    let mut pipelines = HashMap::new();
+    for (project_id, steps) in configs {
+        let mut pipeline = vec![];
+        for step in steps {
+            pipeline.push(match step {
+                PreprocessStepConfig::TrimPrefix(prefix) => PreprocessStep::TrimPrefix(prefix),
+                PreprocessStepConfig::DropLastField => PreprocessStep::DropLastField,
+                PreprocessStepConfig::RegexExtract(pattern) => PreprocessStep::RegexExtract(
+                    regex::Regex::new(&pattern).map_err(|e| format!("PAYLOAD_PREPROCESS: invalid pattern for '{}': {}", project_id, e))?
+                ),
+            });
+        }
+        pipelines.insert(project_id, pipeline);
+    }
+    Ok(pipelines)
+}
+
+fn parse_timestamp_unit(s: &str) -> std::result::Result<TimestampUnit, String> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "micros" => Ok(TimestampUnit::Micros),
+        "millis" => Ok(TimestampUnit::Millis),
+        other => Err(format!("PARQUET_TIMESTAMP_UNIT: expected 'micros' or 'millis', got '{}'", other)),
+    }
+}
+
+fn parse_compression(s: &str) -> std::result::Result<Compression, String> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "snappy" => Ok(Compression::Snappy),
+        "zstd" => Ok(Compression::Zstd),
+        "uncompressed" | "none" => Ok(Compression::Uncompressed),
+        other => Err(format!("PARQUET_COMPRESSION: expected 'snappy', 'zstd' or 'uncompressed', got '{}'", other)),
+    }
+}
+
+fn parse_duplicate_fields(s: &str) -> std::result::Result<DuplicateFields, String> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "error" => Ok(DuplicateFields::Error),
+        "suffix" => Ok(DuplicateFields::Suffix),
+        other => Err(format!("DUPLICATE_FIELDS: expected 'error' or 'suffix', got '{}'", other)),
+    }
+}
+
+fn parse_merge_mode(s: &str) -> std::result::Result<MergeMode, String> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "replace" => Ok(MergeMode::Replace),
+        "skip" => Ok(MergeMode::Skip),
+        "error" => Ok(MergeMode::Error),
+        other => Err(format!("MERGE_MODE: expected 'replace', 'skip' or 'error', got '{}'", other)),
+    }
+}
+
+/// Parses `PARTITION_GRANULARITY`, comma-separated `<project>=<granularity>`
+/// pairs such as `p1=hour,p2=month`.
+fn parse_partition_granularities(s: &str) -> std::result::Result<HashMap<String, PartitionGranularity>, String> {
+    let mut granularities = HashMap::new();
+    for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let (id, granularity) = pair.split_once('=')
+            .ok_or_else(|| format!("PARTITION_GRANULARITY: missing '=' in '{}'", pair))?;
+        let granularity = match granularity.trim().to_ascii_lowercase().as_str() {
+            "hour" => PartitionGranularity::Hour,
+            "day" => PartitionGranularity::Day,
+            "month" => PartitionGranularity::Month,
+            other => return Err(format!("PARTITION_GRANULARITY: expected 'hour', 'day' or 'month' for '{}', got '{}'", id.trim(), other)),
+        };
+        granularities.insert(id.trim().to_string(), granularity);
+    }
+    Ok(granularities)
+}
+
+/// `DESTINATION_IDENTITY` when `FAIL_ON_UNEXPECTED_FILE` is enabled.
+fn get_expected_identity() -> std::result::Result<Option<String>, String> {
+    if !parse_env::<bool>("FAIL_ON_UNEXPECTED_FILE")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    match env::var("DESTINATION_IDENTITY") {
+        Ok(s) if !s.trim().is_empty() => Ok(Some(s.trim().to_string())),
+        _ => Err("DESTINATION_IDENTITY: required when FAIL_ON_UNEXPECTED_FILE is enabled".to_string()),
+    }
+}
+
+fn get_persist_config() -> std::result::Result<PersistConfig, String> {
+    let extensions = match env::var("DUCKDB_EXTENSIONS") {
+        Ok(s) => parse_extensions(&s)?,
+        Err(_) => vec![],
+    };
+    let lock_timeout = Duration::from_secs(parse_env::<u64>("LOCK_TIMEOUT_SECS")?.unwrap_or(30));
+    let profile_dir = env::var("DUCKDB_PROFILE_DIR").ok().filter(|d| !d.trim().is_empty()).map(PathBuf::from);
+    let timestamp_unit = match env::var("PARQUET_TIMESTAMP_UNIT") {
+        Ok(s) => parse_timestamp_unit(&s)?,
+        Err(_) => TimestampUnit::default(),
+    };
+    let compression = match env::var("PARQUET_COMPRESSION") {
+        Ok(s) => parse_compression(&s)?,
+        Err(_) => Compression::default(),
+    };
+    let mode = match env::var("MERGE_MODE") {
+        Ok(s) => parse_merge_mode(&s)?,
+        Err(_) => MergeMode::default(),
+    };
+    Ok(PersistConfig {
+        data_root: get_data_root(),
+        data_roots: get_data_roots(),
+        quorum: get_write_quorum()?,
+        allow_empty_records: parse_env::<bool>("ALLOW_EMPTY_RECORDS")?.unwrap_or(false),
+        merge: MergeOptions { extensions, lock_timeout, profile_dir, timestamp_unit, compression, mode,
+            expected_identity: get_expected_identity()?,
+            partition_by_date: parse_env::<bool>("PARTITION_BY_DATE")?.unwrap_or(false),
+            partition_granularity: PartitionGranularity::default(),
+            project_partitions: match env::var("PARTITION_GRANULARITY") {
+                Ok(s) => parse_partition_granularities(&s)?,
+                Err(_) => HashMap::new(),
+            },
+            max_schema_width: parse_env::<usize>("MAX_SCHEMA_WIDTH")?,
+            duplicate_fields: match env::var("DUPLICATE_FIELDS") {
+                Ok(s) => parse_duplicate_fields(&s)?,
+                Err(_) => DuplicateFields::default(),
+            },
+            wide: parse_env::<bool>("WIDE_TABLE")?.unwrap_or(false),
+        },
+        preprocess: match env::var("PAYLOAD_PREPROCESS") {
+            Ok(s) if !s.trim().is_empty() => parse_preprocess(&s)?,
+            _ => HashMap::new(),
+        },
+        merge_window: parse_env::<u64>("MERGE_WINDOW")?.filter(|&s| s > 0).map(Duration::from_secs),
+        concurrency: match parse_env::<usize>("PERSIST_CONCURRENCY")? {
+            Some(0) => return Err("PERSIST_CONCURRENCY: must be positive".to_string()),
+            n => n.unwrap_or(1),
+        },
+        connections: match parse_env::<usize>("DUCKDB_MAX_CONNS")? {
+            Some(0) => return Err("DUCKDB_MAX_CONNS: must be positive".to_string()),
+            max => Arc::new(ConnectionLimit::new(max)),
+        },
+        max_attempts: parse_env::<u32>("MAX_ATTEMPTS")?.filter(|&n| n > 0),
+        field_order: match env::var("FIELD_ORDER") {
+            Ok(s) => FieldOrder::parse(&s)?,
+            Err(_) => FieldOrder::default(),
+        },
+        reconcile_window: match parse_env::<u64>("RECONCILE_WINDOW_SECS")? {
+            Some(0) => return Err("RECONCILE_WINDOW_SECS: must be positive".to_string()),
+            secs => Duration::from_secs(secs.unwrap_or(DEFAULT_RECONCILE_WINDOW_SECS)),
+        },
+    })
+}
+
+const DEFAULT_RECONCILE_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sleep between persist cycles from `PERSIST_INTERVAL_SECS`, falling back to
+/// the default with a warning when the value is not a positive number.
+fn poll_interval() -> Duration {
+    parse_poll_interval(env::var("PERSIST_INTERVAL_SECS").ok().as_deref())
+}
+
+fn parse_poll_interval(value: Option<&str>) -> Duration {
+    match value {
+        Some(s) => match s.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                log::warn!(value = s, default_secs = DEFAULT_POLL_INTERVAL.as_secs(); "ignoring an invalid PERSIST_INTERVAL_SECS");
+                DEFAULT_POLL_INTERVAL
+            }
+        },
+        None => DEFAULT_POLL_INTERVAL,
+    }
+}
+
+fn get_poll_interval() -> std::result::Result<PollInterval, String> {
+    poll_interval_from(
+        poll_interval(),
+        parse_env::<u64>("PERSIST_INTERVAL_MIN_SECS")?,
+        parse_env::<u64>("PERSIST_INTERVAL_MAX_SECS")?,
+        parse_env::<usize>("PERSIST_TARGET_ROWS")?,
+    )
+}
+
+/// Builds the adaptive interval from already-read settings; unset bounds
+/// default around `initial`.
+fn poll_interval_from(
+    initial: Duration,
+    min: Option<u64>,
+    max: Option<u64>,
+    target_rows: Option<usize>,
+) -> std::result::Result<PollInterval, String> {
+    let min = min.unwrap_or_else(|| initial.as_secs().min(1));
+    let max = max.unwrap_or_else(|| initial.as_secs().max(60));
+    if min == 0 {
+        return Err("PERSIST_INTERVAL_MIN_SECS: must be positive".to_string());
+    }
+    if min > max {
+        return Err(format!("PERSIST_INTERVAL_MIN_SECS: {} exceeds PERSIST_INTERVAL_MAX_SECS {}", min, max));
+    }
+    Ok(PollInterval::new(Duration::from_secs(min), Duration::from_secs(max), target_rows, initial))
+}
+
+/// Checks the environment, returning a summary of the effective values or an
+/// error naming the first invalid one.
+fn validate_config() -> std::result::Result<Vec<String>, String> {
+    let config = get_persist_config()?;
+    if !Path::new(&config.data_root).is_dir() {
+        return Err(format!("DATA_ROOT: '{}' is not a directory", config.data_root));
+    }
+    if config.data_roots.is_empty() {
+        return Err("DATA_ROOTS: no data root given".to_string());
+    }
+    if let Some(root) = config.data_roots.iter().find(|r| !Path::new(r).is_dir()) {
+        return Err(format!("DATA_ROOTS: '{}' is not a directory", root));
+    }
+    if let WriteQuorum::AtLeast(n) = config.quorum {
+        if n > config.data_roots.len() {
+            return Err(format!("WRITE_QUORUM: {} exceeds the {} configured data root(s)", n, config.data_roots.len()));
+        }
+    }
+    let interval = get_poll_interval()?;
+    Ok(vec![
+        format!("DATA_ROOT: {}", config.data_root),
+        format!("DATA_ROOTS: {}", config.data_roots.join(",")),
+        format!("WRITE_QUORUM: {:?}", config.quorum),
+        format!("ALLOW_EMPTY_RECORDS: {}", config.allow_empty_records),
+        format!("DUCKDB_EXTENSIONS: parquet{}", config.merge.extensions.iter().map(|e| format!(",{}", e)).collect::<String>()),
+        format!("LOCK_TIMEOUT_SECS: {}", config.merge.lock_timeout.as_secs()),
+        format!("DUCKDB_PROFILE_DIR: {}", config.merge.profile_dir.as_ref().map(|d| d.display().to_string()).unwrap_or_else(|| "unset".to_string())),
+        format!("PARQUET_TIMESTAMP_UNIT: {:?}", config.merge.timestamp_unit),
+        format!("PARQUET_COMPRESSION: {}", config.merge.compression.codec()),
+        format!("MERGE_MODE: {:?}", config.merge.mode),
+        format!("DUPLICATE_FIELDS: {:?}", config.merge.duplicate_fields),
+        format!("PARTITION_BY_DATE: {}", config.merge.partition_by_date),
+        format!("PARTITION_GRANULARITY: {}", if config.merge.project_partitions.is_empty() {
+            "unset".to_string()
+        } else {
+            config.merge.project_partitions.iter().sorted_by_key(|(id, _)| *id).map(|(id, g)| format!("{}={:?}", id, g)).join(",")
+        }),
+        format!("WIDE_TABLE: {}", config.merge.wide),
+        format!("MAX_SCHEMA_WIDTH: {}", config.merge.max_schema_width.map(|w| w.to_string()).unwrap_or_else(|| "unset".to_string())),
+        format!("PERSIST_CONCURRENCY: {}", config.concurrency),
+        format!("DUCKDB_MAX_CONNS: {}", config.connections.max.map(|m| m.to_string()).unwrap_or_else(|| "unset".to_string())),
+        format!("FIELD_ORDER: {:?}", config.field_order),
+        format!("MAX_ATTEMPTS: {}", config.max_attempts.map(|n| n.to_string()).unwrap_or_else(|| "unset".to_string())),
+        format!("RECONCILE_WINDOW_SECS: {}", config.reconcile_window.as_secs()),
+        format!("MERGE_WINDOW: {}", config.merge_window.map(|w| w.as_secs().to_string()).unwrap_or_else(|| "unset".to_string())),
+        format!("FAIL_ON_UNEXPECTED_FILE: {}", config.merge.expected_identity.as_deref().unwrap_or("disabled")),
+        format!("PAYLOAD_PREPROCESS: {}", if config.preprocess.is_empty() { "unset".to_string() } else { config.preprocess.keys().sorted().join(",") }),
+        format!("STARTUP_WAIT_SECS: {}", parse_env::<u64>("STARTUP_WAIT_SECS")?.unwrap_or(0)),
+        format!("PERSIST_INTERVAL_SECS: {}", interval.current.as_secs()),
+        format!("PERSIST_INTERVAL_MIN_SECS: {}", interval.min.as_secs()),
+        format!("PERSIST_INTERVAL_MAX_SECS: {}", interval.max.as_secs()),
+        format!("PERSIST_TARGET_ROWS: {}", interval.target_rows.map(|t| t.to_string()).unwrap_or_else(|| "unset".to_string())),
+    ])
+}
+
+/// Runs one persist cycle, recording the heartbeat when it succeeds, and
+/// returns the number of rows it read.
+async fn persist_cycle(config: &PersistConfig) -> usize {
+    match load_wal(config).await {
+        Ok(rows) => {
+            if let Err(e) = record_heartbeat(config, rows, Utc::now()).await {
+                log::error!(error:% = e; "failed to record the heartbeat");
+            }
+            rows
+        }
+        Err(e) => {
+            log::error!(error:% = e; "persist cycle failed");
+            0
+        }
+    }
+}
+
+/// How often the persister checks the WAL for persists the querier requested
+/// while it sleeps between cycles.
+const PERSIST_REQUEST_POLL: Duration = Duration::from_millis(500);
+
+/// Resolves once the querier has requested a persist, clearing the requests.
+/// A WAL without the `persist_requests` table never requests one.
+async fn persist_requested(config: &PersistConfig) {
+    loop {
+        tokio::time::sleep(PERSIST_REQUEST_POLL).await;
+        let taken = match open_wal(config).await {
+            Ok(pool) => {
+                let taken = sqlx::query("DELETE FROM persist_requests RETURNING project_id").fetch_all(&pool).await.map_err(PersisterError::from);
+                pool.close().await;
+                taken
+            }
+            Err(e) => Err(e),
+        };
+        match taken {
+            Ok(rows) if !rows.is_empty() => {
+                let projects: Vec<String> = rows.iter().filter_map(|row| row.try_get("project_id").ok()).collect();
+                log::info!(projects = projects.join(",").as_str(); "persisting early on request");
+                return;
+            }
+            Ok(_) => {}
+            Err(PersisterError::Sqlx(sqlx::Error::Database(e))) if e.message().contains("no such table") => {}
+            Err(e) => log::warn!(error:% = e; "failed to read the persist requests"),
+        }
+    }
+}
+
+/// Runs persist cycles until `shutdown` resolves, starting the next one early
+/// when the querier requests a persist. The signal is only acted on between
+/// cycles, so a merge in progress always finishes, and one last cycle drains
+/// what was written to the WAL meanwhile.
+async fn run(config: &PersistConfig, mut interval: PollInterval, shutdown: impl std::future::Future<Output = ()>) {
+    tokio::pin!(shutdown);
+    loop {
+        let rows = persist_cycle(config).await;
+        tokio::select! {
+            _ = tokio::time::sleep(interval.next(rows)) => {}
+            _ = persist_requested(config) => {}
+            _ = &mut shutdown => break,
+        }
+    }
+    log::info!("draining WAL before shutdown");
+    persist_cycle(config).await;
+}
+
+/// Resolves on SIGINT or, on unix, SIGTERM. The handlers are installed before
+/// this returns, so a signal arriving mid-cycle is not lost.
+#[cfg(unix)]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    Ok(async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+
+    if env::args().any(|a| a == "--validate-config") {
+        match validate_config() {
+            Ok(summary) => {
+                summary.iter().for_each(|line| println!("{}", line));
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Configuration error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let config = get_persist_config()?;
+    if env::args().any(|a| a == "--reconcile") {
+        let reconciled = reconcile(&config, Utc::now()).await?;
+        for r in &reconciled {
+            match r.missing.first() {
+                None => println!("ok {}: {} deleted row(s) all persisted", r.path, r.deleted),
+                Some(first) => println!("DIVERGED {}: {} of {} deleted row(s) missing, first at {}",
+                    r.path, r.missing.len(), r.deleted, first.to_rfc3339()),
+            }
+        }
+        let diverged = reconciled.iter().filter(|r| !r.missing.is_empty()).count();
+        println!("{} destination(s) checked, {} diverged", reconciled.len(), diverged);
+        std::process::exit(if diverged > 0 { 1 } else { 0 });
+    }
+    let interval = get_poll_interval()?;
+    let startup_wait = Duration::from_secs(parse_env::<u64>("STARTUP_WAIT_SECS")?.unwrap_or(0));
+    wait_until_ready(&config, startup_wait, Duration::from_secs(1)).await?;
+
+    for root in &config.data_roots {
+        if let Err(e) = remove_stale_tmp_files(Path::new(root)) {
+            log::error!(root = root.as_str(), error:% = e; "failed to remove stale temporary files");
+        }
+    }
+
+    run(&config, interval, shutdown_signal()?).await;
+    Ok(())
 }
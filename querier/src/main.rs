@@ -1,105 +1,5001 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use chrono::{DateTime, Utc};
+use common::FieldOrder;
 use futures::TryStreamExt;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use sqlx::Row;
-use sqlx::sqlite::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteRow};
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Seconds suggested to clients in `Retry-After` while the WAL backlog is large.
+const RETRY_AFTER_SECS: u64 = 10;
+
+/// Write quotas reset every 30 days unless `WRITE_QUOTA_WINDOW_SECS` is set.
+const DEFAULT_QUOTA_WINDOW_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Per-project decimation: only 1 of every N samples is written to the WAL.
+#[derive(Default)]
+struct Decimation {
+    factors: HashMap<String, u64>,
+    counters: Mutex<HashMap<String, u64>>,
+}
+
+impl Decimation {
+    fn new(factors: HashMap<String, u64>) -> Self {
+        Decimation { factors, counters: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns whether the next sample for the project should be kept.
+    fn accept(&self, project_id: &str) -> bool {
+        let n = match self.factors.get(project_id) {
+            Some(n) if *n > 1 => *n,
+            _ => return true,
+        };
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(project_id.to_string()).or_insert(0);
+        let keep = counter.is_multiple_of(n);
+        *counter += 1;
+        keep
+    }
+}
+
+/// Cached view of the WAL backlog size used to push back on ingest.
+struct Backlog {
+    soft_limit: Option<i64>,
+    hard_limit: Option<i64>,
+    ttl: Duration,
+    cached: Mutex<Option<(Instant, i64)>>,
+}
+
+enum BacklogTier {
+    Normal,
+    Soft,
+    Hard,
+}
+
+impl Backlog {
+    fn new(soft_limit: Option<i64>, hard_limit: Option<i64>, ttl: Duration) -> Self {
+        Backlog { soft_limit, hard_limit, ttl, cached: Mutex::new(None) }
+    }
+
+    async fn size(&self, db_pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+        if let Some((at, size)) = *self.cached.lock().unwrap() {
+            if at.elapsed() < self.ttl {
+                return Ok(size);
+            }
+        }
+        let size: i64 = sqlx::query("SELECT COUNT(*) AS n FROM wal")
+            .fetch_one(db_pool).await?
+            .try_get("n")?;
+        *self.cached.lock().unwrap() = Some((Instant::now(), size));
+        Ok(size)
+    }
+
+    async fn tier(&self, db_pool: &SqlitePool) -> Result<BacklogTier, sqlx::Error> {
+        if self.soft_limit.is_none() && self.hard_limit.is_none() {
+            return Ok(BacklogTier::Normal);
+        }
+        let size = self.size(db_pool).await?;
+        Ok(match (self.soft_limit, self.hard_limit) {
+            (_, Some(hard)) if size >= hard => BacklogTier::Hard,
+            (Some(soft), _) if size >= soft => BacklogTier::Soft,
+            _ => BacklogTier::Normal,
+        })
+    }
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Backlog::new(None, None, Duration::ZERO)
+    }
+}
+
+/// Per-project write quotas over fixed windows aligned to the Unix epoch.
+struct WriteQuota {
+    rows: HashMap<String, u64>,
+    bytes: HashMap<String, u64>,
+    window: Duration,
+}
+
+impl WriteQuota {
+    fn window_start(&self, now: DateTime<Utc>) -> i64 {
+        let secs = self.window.as_secs().max(1) as i64;
+        now.timestamp().div_euclid(secs) * secs
+    }
+
+    /// Seconds left in the window containing `now`.
+    fn remaining_secs(&self, now: DateTime<Utc>) -> u64 {
+        (self.window_start(now) + self.window.as_secs().max(1) as i64 - now.timestamp()) as u64
+    }
+
+    /// Counts a write of `rows` rows and `bytes` bytes against the project's
+    /// usage in the window containing `now`. Returns false, leaving the usage
+    /// unchanged, when the write would exceed either quota. The check and the
+    /// count are one statement, so concurrent writes cannot both take the
+    /// last of a quota.
+    async fn consume(&self, db_pool: &SqlitePool, project_id: &str, rows: u64, bytes: u64, now: DateTime<Utc>) -> Result<bool, sqlx::Error> {
+        let row_limit = self.rows.get(project_id);
+        let byte_limit = self.bytes.get(project_id);
+        if row_limit.is_none() && byte_limit.is_none() {
+            return Ok(true);
+        }
+        let limit = |l: Option<&u64>| l.map_or(i64::MAX, |l| (*l).min(i64::MAX as u64) as i64);
+        let (row_limit, byte_limit) = (limit(row_limit), limit(byte_limit));
+        if rows as i64 > row_limit || bytes as i64 > byte_limit {
+            return Ok(false);
+        }
+
+        // Usage of an earlier window is replaced rather than added to.
+        let charged = sqlx::query(
+            "INSERT INTO write_quotas (project_id, window_start, rows, bytes) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (project_id) DO UPDATE SET
+                 rows = excluded.rows + CASE WHEN window_start = excluded.window_start THEN rows ELSE 0 END,
+                 bytes = excluded.bytes + CASE WHEN window_start = excluded.window_start THEN bytes ELSE 0 END,
+                 window_start = excluded.window_start
+             WHERE excluded.rows + CASE WHEN window_start = excluded.window_start THEN rows ELSE 0 END <= ?5
+               AND excluded.bytes + CASE WHEN window_start = excluded.window_start THEN bytes ELSE 0 END <= ?6
+             RETURNING rows"
+        )
+            .bind(project_id)
+            .bind(self.window_start(now))
+            .bind(rows as i64)
+            .bind(bytes as i64)
+            .bind(row_limit)
+            .bind(byte_limit)
+            .fetch_optional(db_pool).await?;
+        Ok(charged.is_some())
+    }
+
+    /// Takes back what `consume` counted for a write that stored nothing
+    /// after all. A charge of an earlier window is already gone.
+    async fn refund(&self, db_pool: &SqlitePool, project_id: &str, rows: u64, bytes: u64, now: DateTime<Utc>) -> Result<(), sqlx::Error> {
+        if !self.rows.contains_key(project_id) && !self.bytes.contains_key(project_id) {
+            return Ok(());
+        }
+        sqlx::query("UPDATE write_quotas SET rows = max(rows - ?3, 0), bytes = max(bytes - ?4, 0) WHERE project_id = ?1 AND window_start = ?2")
+            .bind(project_id)
+            .bind(self.window_start(now))
+            .bind(rows as i64)
+            .bind(bytes as i64)
+            .execute(db_pool).await?;
+        Ok(())
+    }
+}
+
+impl Default for WriteQuota {
+    fn default() -> Self {
+        WriteQuota { rows: HashMap::new(), bytes: HashMap::new(), window: Duration::from_secs(DEFAULT_QUOTA_WINDOW_SECS) }
+    }
+}
+
+/// How old the persister's last heartbeat may be before it is reported stale.
+struct PersisterHealth {
+    stale_after: Duration,
+}
+
+impl Default for PersisterHealth {
+    fn default() -> Self {
+        PersisterHealth { stale_after: Duration::from_secs(60) }
+    }
+}
+
+/// How write requests are checked and stored.
+#[derive(Default)]
+struct IngestOptions {
+    /// Payloads longer than this many bytes are gzipped before being stored.
+    compress_threshold: Option<usize>,
+    /// Whether payloads without any value are accepted.
+    allow_empty_records: bool,
+    /// How far past the server's clock a supplied record time may be.
+    max_future_skew: Option<Duration>,
+    /// How far behind the server's clock a supplied record time may be.
+    max_past_skew: Option<Duration>,
+    /// How many projects may be registered; writes to any other are refused.
+    max_projects: Option<usize>,
+    /// Order the fields of a JSON object payload are stored in.
+    field_order: FieldOrder,
+    /// Per project, the schema name each client field name of a JSON object
+    /// payload is stored under.
+    field_aliases: HashMap<String, HashMap<String, String>>,
+    /// What happens to a field of a project with aliases that they neither
+    /// map nor name.
+    unknown_fields: UnknownFields,
+}
+
+/// How a project's field aliases treat a field they do not know.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum UnknownFields {
+    /// The field is stored as sent.
+    #[default]
+    Keep,
+    /// The record is refused.
+    Reject,
+    /// The field is dropped.
+    Ignore,
+}
+
+/// Projects whose writes ask the persister to persist right away rather than
+/// at its next poll, from `IMMEDIATE_PERSIST`.
+#[derive(Default)]
+struct ImmediatePersist(HashSet<String>);
+
+/// While set, every mutating endpoint answers 503 and queries keep working.
+/// Starts from `READ_ONLY` and is toggled with `PUT /admin/read-only`.
+#[derive(Default)]
+struct ReadOnly(AtomicBool);
+
+/// The app's `T`, or `T`'s default when the app was built without one.
+fn app_data_or_default<T: Default + 'static>(req: &HttpRequest) -> web::Data<T> {
+    req.app_data::<web::Data<T>>().cloned().unwrap_or_else(|| web::Data::new(T::default()))
+}
+
+fn reject_if_read_only(req: &HttpRequest) -> Option<HttpResponse> {
+    let read_only = req.app_data::<web::Data<ReadOnly>>().map(|r| r.0.load(Ordering::Relaxed)).unwrap_or(false);
+    if read_only {
+        Some(HttpResponse::ServiceUnavailable().body("read only"))
+    } else {
+        None
+    }
+}
+
+/// Caps the serialized size of query results at `MAX_RESPONSE_BYTES`. Once a
+/// result grows past it, reading stops and the query either fails with 413 or
+/// returns the rows read so far flagged as truncated.
+#[derive(Default)]
+struct ResponseLimit {
+    max_bytes: Option<usize>,
+    truncate: bool,
+}
+
+/// Where the persister archives WAL rows: `<root>/<project>/<schema>` Parquet
+/// files, or directories of `hour=...`, `date=...` or `month=...` partitions.
+/// Range reads return these
+/// rows along with the WAL's; without a root only the WAL is read.
+#[derive(Default)]
+struct ParquetArchive {
+    root: Option<PathBuf>,
+}
+
+/// Caps each DuckDB step of a query (archive read, rates, change filtering,
+/// stats, Parquet export) at `DUCKDB_QUERY_TIMEOUT_SECS`; a step still running
+/// then fails the request with 504. duckdb 0.8 has no way to interrupt a
+/// running statement, so the step is abandoned: its in-memory connection
+/// finishes on its blocking thread and the result is dropped.
+#[derive(Default)]
+struct QueryTimeout(Option<Duration>);
+
+/// Per-project API keys. Reads and writes of project data need a key for the
+/// project only when `required`; keys are managed with the admin token.
+#[derive(Default)]
+struct ApiKeys {
+    admin_token: Option<String>,
+    required: bool,
+}
+
+/// Signs URLs exporting a project's data as Parquet without an API key until
+/// they expire, `EXPORT_URL_TTL_SECS` after signing. Without
+/// `EXPORT_SIGNING_KEY` no URL is signed or accepted.
+struct ExportSigner {
+    key: Option<Vec<u8>>,
+    ttl: Duration,
+}
+
+impl Default for ExportSigner {
+    fn default() -> Self {
+        ExportSigner { key: None, ttl: DEFAULT_EXPORT_URL_TTL }
+    }
+}
+
+const DEFAULT_EXPORT_URL_TTL: Duration = Duration::from_secs(15 * 60);
+
+impl ExportSigner {
+    /// The HMAC of the path and the expiry, as Unix seconds.
+    fn mac(&self, path: &str, expires: i64) -> Option<Hmac<Sha256>> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_ref()?).expect("HMAC takes keys of any length");
+        mac.update(format!("{}\n{}", path, expires).as_bytes());
+        Some(mac)
+    }
+
+    fn sign(&self, path: &str, expires: i64) -> Option<String> {
+        self.mac(path, expires).map(|mac| hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Whether the signature was made for the path and expiry, which has not
+    /// passed at `now`.
+    fn verify(&self, path: &str, expires: i64, signature: &str, now: DateTime<Utc>) -> bool {
+        match (self.mac(path, expires), hex::decode(signature)) {
+            (Some(mac), Ok(signature)) => expires > now.timestamp() && mac.verify_slice(&signature).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// Where the WAL lives: `wal.sqlite` under `DATA_ROOT` (the working directory
+/// by default), shared with the persister, or an in-memory database when
+/// `WAL_IN_MEMORY` is set.
+enum WalLocation {
+    File(PathBuf),
+    Memory,
+}
+
+fn wal_location() -> Result<WalLocation, String> {
+    if parse_flag("WAL_IN_MEMORY")? {
+        return Ok(WalLocation::Memory);
+    }
+    let root = match std::env::var("DATA_ROOT") {
+        Ok(root) => PathBuf::from(root),
+        Err(_) => std::env::current_dir().map_err(|e| format!("DATA_ROOT: {}", e))?,
+    };
+    Ok(WalLocation::File(root.join("wal.sqlite")))
+}
+
+async fn connect_wal(location: &WalLocation) -> Result<SqlitePool, sqlx::Error> {
+    match location {
+        WalLocation::File(path) => {
+            let options = SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal);
+            SqlitePoolOptions::new().connect_with(options).await
+        }
+        // Every connection to `:memory:` opens a separate database.
+        WalLocation::Memory => SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await,
+    }
+}
 
 async fn initialize_database(db_pool: &SqlitePool) -> Result<Option<()>, sqlx::Error> {
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS wal (
              project_id TEXT NOT NULL,
+             schema     TEXT NOT NULL,
              time       DATETIME NOT NULL,
              created_at DATETIME NOT NULL,
-             payload    TEXT NOT NULL
+             payload    TEXT NOT NULL,
+             compressed INTEGER NOT NULL DEFAULT 0,
+             attempts   INTEGER NOT NULL DEFAULT 0,
+             source     TEXT
          )"
     ).execute(db_pool).await?;
+    // WALs created before rows were tagged with their producer gain the
+    // column; their rows have no source.
+    let tagged = sqlx::query("SELECT 1 FROM pragma_table_info('wal') WHERE name = 'source'").fetch_optional(db_pool).await?;
+    if tagged.is_none() {
+        sqlx::query("ALTER TABLE wal ADD COLUMN source TEXT").execute(db_pool).await?;
+    }
 
     sqlx::query(
         "CREATE INDEX IF NOT EXISTS idx_created_at ON wal (created_at)"
     ).execute(db_pool).await?;
 
-    return Ok(Some(()))
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sequences (
+             project_id TEXT PRIMARY KEY,
+             last_seq   INTEGER NOT NULL
+         )"
+    ).execute(db_pool).await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sequence_gaps (
+             project_id  TEXT NOT NULL,
+             from_seq    INTEGER NOT NULL,
+             to_seq      INTEGER NOT NULL,
+             detected_at DATETIME NOT NULL
+         )"
+    ).execute(db_pool).await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS api_keys (
+             id         INTEGER PRIMARY KEY AUTOINCREMENT,
+             project_id TEXT NOT NULL,
+             salt       TEXT NOT NULL,
+             key_hash   TEXT NOT NULL,
+             created_at DATETIME NOT NULL,
+             revoked_at DATETIME
+         )"
+    ).execute(db_pool).await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS heartbeat (
+             id              INTEGER PRIMARY KEY CHECK (id = 1),
+             last_cycle_at   DATETIME NOT NULL,
+             last_batch_rows INTEGER NOT NULL
+         )"
+    ).execute(db_pool).await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS write_quotas (
+             project_id   TEXT PRIMARY KEY,
+             window_start INTEGER NOT NULL,
+             rows         INTEGER NOT NULL,
+             bytes        INTEGER NOT NULL
+         )"
+    ).execute(db_pool).await?;
+
+    // Projects stay registered after the persister deletes their rows; a
+    // WAL from before the table existed registers the projects it holds.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS projects (
+             project_id    TEXT PRIMARY KEY,
+             registered_at DATETIME NOT NULL
+         )"
+    ).execute(db_pool).await?;
+    sqlx::query(
+        "INSERT OR IGNORE INTO projects (project_id, registered_at) SELECT DISTINCT project_id, ?1 FROM wal"
+    ).bind(Utc::now().to_rfc3339()).execute(db_pool).await?;
+
+    // Polled by the persister, which persists early and clears the table.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS persist_requests (
+             project_id   TEXT PRIMARY KEY,
+             requested_at DATETIME NOT NULL
+         )"
+    ).execute(db_pool).await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS idempotency_keys (
+             project_id TEXT NOT NULL,
+             key        TEXT NOT NULL,
+             status     INTEGER NOT NULL,
+             response   TEXT NOT NULL,
+             created_at DATETIME NOT NULL,
+             PRIMARY KEY (project_id, key)
+         )"
+    ).execute(db_pool).await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS paused_projects (
+             project_id TEXT PRIMARY KEY,
+             paused_at  DATETIME NOT NULL
+         )"
+    ).execute(db_pool).await?;
+
+    Ok(Some(()))
+}
+
+fn invalid_project_id() -> HttpResponse {
+    HttpResponse::BadRequest().body("project id must be a plain name of letters, digits, '_', '-' or '.'")
+}
+
+/// Schema used when a write does not name one.
+const DEFAULT_SCHEMA: &str = "default";
+
+/// Schemas name a Parquet file under the project's directory, so they are
+/// limited to a single plain path segment. Names with a leading dot are left
+/// to the persister's temporary files. Project ids name the directory and
+/// follow the same rule.
+fn valid_schema(schema: &str) -> bool {
+    !schema.is_empty()
+        && !schema.starts_with('.')
+        && schema.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// A payload's values keyed the way the persister names their Parquet
+/// columns: a JSON object's own keys, or else `f0`, `f1`, ... after the
+/// position of a JSON array element or comma-separated token. Values that are
+/// not numbers are `None`.
+fn payload_fields(payload: &str) -> Vec<(String, Option<f64>)> {
+    let payload = payload.trim();
+    if payload.is_empty() {
+        return vec![];
+    }
+    if payload.starts_with('{') {
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(payload) {
+            return fields.into_iter().map(|(name, value)| (name, value.as_f64())).collect();
+        }
+    }
+    let values: Vec<Option<f64>> = match serde_json::from_str(payload) {
+        Ok(serde_json::Value::Array(values)) if payload.starts_with('[') => values.iter().map(|v| v.as_f64()).collect(),
+        _ => payload.split(',').map(|v| v.trim().parse().ok()).collect(),
+    };
+    values.into_iter().enumerate().map(|(i, value)| (format!("f{}", i), value)).collect()
+}
+
+/// A JSON object payload with its keys in the field order, so the rows of a
+/// project name their columns in one order whatever order clients send the
+/// keys in. Any other payload, or one already in order, is stored as sent.
+fn order_payload_fields(payload: String, order: FieldOrder) -> String {
+    if !payload.trim_start().starts_with('{') {
+        return payload;
+    }
+    let Ok(fields) = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&payload) else {
+        return payload;
+    };
+    let received: Vec<(String, serde_json::Value)> = fields.into_iter().collect();
+    let mut ordered = received.clone();
+    order.apply(&mut ordered);
+    if ordered.iter().map(|(name, _)| name).eq(received.iter().map(|(name, _)| name)) {
+        return payload;
+    }
+    serde_json::Value::Object(ordered.into_iter().collect()).to_string()
+}
+
+/// A JSON object payload with its fields renamed after the aliases and the
+/// unknown ones handled per `unknown`. Any other payload, or one the aliases
+/// leave as it is, is stored as sent.
+fn alias_payload_fields(payload: String, aliases: &HashMap<String, String>, unknown: UnknownFields) -> Result<String, String> {
+    if !payload.trim_start().starts_with('{') {
+        return Ok(payload);
+    }
+    let Ok(fields) = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&payload) else {
+        return Ok(payload);
+    };
+    let mut renamed = serde_json::Map::new();
+    let mut changed = false;
+    for (name, value) in fields {
+        let alias = match aliases.get(&name) {
+            Some(alias) => alias.clone(),
+            None if aliases.values().any(|a| *a == name) => name.clone(),
+            None => match unknown {
+                UnknownFields::Keep => name.clone(),
+                UnknownFields::Reject => return Err(format!("unknown field {:?}", name)),
+                UnknownFields::Ignore => {
+                    changed = true;
+                    continue;
+                }
+            },
+        };
+        changed |= alias != name;
+        if renamed.insert(alias.clone(), value).is_some() {
+            return Err(format!("field {:?} is given more than once", alias));
+        }
+    }
+    Ok(if changed { serde_json::Value::Object(renamed).to_string() } else { payload })
+}
+
+/// The number of values in a payload, counted the way the persister splits
+/// it.
+fn field_count(payload: &str) -> usize {
+    payload_fields(payload).len()
 }
 
-async fn save_to_db(db_pool: &SqlitePool, project_id: String, payload: String) -> Result<Option<()>, sqlx::Error> {
+/// Writes a WAL row, or returns `None` when the project is new and the
+/// project limit is reached.
+async fn save_to_db(
+    db_pool: &SqlitePool,
+    project_id: String,
+    schema: &str,
+    source: Option<&str>,
+    payload: String,
+    options: &IngestOptions,
+) -> Result<Option<()>, sqlx::Error> {
     let timestamp = chrono::Utc::now().to_rfc3339();
-    sqlx::query("INSERT INTO wal (project_id, time, created_at, payload) VALUES (?1, ?2, ?3, ?4)")
-        .bind(project_id)
-        .bind(&timestamp)
-        .bind(&timestamp)
-        .bind(payload)
-        .execute(db_pool).await?;
+    let mut tx = db_pool.begin().await?;
+    if !register_project(&mut tx, &project_id, &timestamp, options).await? {
+        return Ok(None);
+    }
+    insert_wal_row(&mut *tx, (&project_id, schema, source), &timestamp, &timestamp, payload, options).await?;
+    tx.commit().await?;
 
-    return Ok(Some(()))
+    Ok(Some(()))
 }
 
-async fn dump_select_results(q :&str, pool: &SqlitePool) -> Result<(), sqlx::Error> {
-    let mut rows = sqlx::query(q).fetch(pool);
+/// The `Idempotency-Key` of a batch with the response it was answered with.
+struct Idempotency<'a> {
+    key: &'a str,
+    status: u16,
+    /// Builds the response from the WAL ids of the stored rows.
+    respond: &'a dyn Fn(&[i64]) -> serde_json::Value,
+}
 
-    while let Some(row) = rows.try_next().await? {
-        let id: String = row.try_get("project_id")?;
-        let p: String = row.try_get("payload")?;
-        println!("ID: {} {}", id, p);
+/// Writes each `(time, payload)` of a batch as its own WAL row, all or none,
+/// returning their WAL ids, or `None` when the project is new and the project
+/// limit is reached. A keyed batch records its response in the same
+/// transaction, so a key that is already recorded fails the write with a
+/// unique violation.
+async fn save_batch_to_db(
+    db_pool: &SqlitePool,
+    project_id: &str,
+    schema: &str,
+    source: Option<&str>,
+    rows: Vec<(DateTime<Utc>, String)>,
+    options: &IngestOptions,
+    idempotency: Option<Idempotency<'_>>,
+) -> Result<Option<Vec<i64>>, sqlx::Error> {
+    let created_at = chrono::Utc::now().to_rfc3339();
+    let mut tx = db_pool.begin().await?;
+    if !register_project(&mut tx, project_id, &created_at, options).await? {
+        return Ok(None);
+    }
+    let mut ids = Vec::with_capacity(rows.len());
+    for (time, payload) in rows {
+        ids.push(insert_wal_row(&mut *tx, (project_id, schema, source), &time.to_rfc3339(), &created_at, payload, options).await?);
+    }
+    if let Some(idempotency) = idempotency {
+        record_idempotency(&mut *tx, project_id, &idempotency, &ids, &created_at).await?;
     }
+    tx.commit().await?;
+    Ok(Some(ids))
+}
+
+async fn record_idempotency<'e, E: sqlx::Executor<'e, Database = sqlx::Sqlite>>(
+    executor: E,
+    project_id: &str,
+    idempotency: &Idempotency<'_>,
+    ids: &[i64],
+    now: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO idempotency_keys (project_id, key, status, response, created_at) VALUES (?1, ?2, ?3, ?4, ?5)")
+        .bind(project_id)
+        .bind(idempotency.key)
+        .bind(idempotency.status as i64)
+        .bind((idempotency.respond)(ids).to_string())
+        .bind(now)
+        .execute(executor).await?;
     Ok(())
 }
 
+/// The response recorded for a batch already written with this key.
+async fn recorded_response(db_pool: &SqlitePool, project_id: &str, key: &str) -> Result<Option<HttpResponse>, sqlx::Error> {
+    let row = sqlx::query("SELECT status, response FROM idempotency_keys WHERE project_id = ?1 AND key = ?2")
+        .bind(project_id)
+        .bind(key)
+        .fetch_optional(db_pool).await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let status = actix_web::http::StatusCode::from_u16(row.try_get::<i64, _>("status")? as u16)
+        .unwrap_or(actix_web::http::StatusCode::CREATED);
+    Ok(Some(HttpResponse::build(status).content_type("application/json").body(row.try_get::<String, _>("response")?)))
+}
 
-async fn get_project_data(
-    path: web::Path<String>,
-    query: web::Query<std::collections::HashMap<String, String>>,
-    db_pool: web::Data<SqlitePool>,
-) -> impl Responder {
-    let id = path.into_inner();
-    let q = query.get("q").cloned().unwrap_or_default();
+/// Whether the project is registered or there is room to register it.
+async fn admits_project<'e, E: sqlx::Executor<'e, Database = sqlx::Sqlite>>(
+    executor: E,
+    project_id: &str,
+    options: &IngestOptions,
+) -> Result<bool, sqlx::Error> {
+    let Some(max) = options.max_projects else {
+        return Ok(true);
+    };
+    let row = sqlx::query("SELECT EXISTS (SELECT 1 FROM projects WHERE project_id = ?1) AS known, COUNT(*) AS registered FROM projects")
+        .bind(project_id)
+        .fetch_one(executor).await?;
+    Ok(row.try_get::<bool, _>("known")? || (row.try_get::<i64, _>("registered")? as usize) < max)
+}
 
-    match dump_select_results(&q, &**db_pool).await {
-        Ok(_) => {}
-        Err(e) => {
-            log::error!("query error: {}", e);
+/// Registers the project in the write's transaction, so a project takes a
+/// slot of the limit only with its first stored row. Returns false when the
+/// project is new and the limit is reached.
+async fn register_project(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    project_id: &str,
+    now: &str,
+    options: &IngestOptions,
+) -> Result<bool, sqlx::Error> {
+    if !admits_project(&mut **tx, project_id, options).await? {
+        return Ok(false);
+    }
+    sqlx::query("INSERT OR IGNORE INTO projects (project_id, registered_at) VALUES (?1, ?2)")
+        .bind(project_id)
+        .bind(now)
+        .execute(&mut **tx).await?;
+    Ok(true)
+}
+
+/// Writes a WAL row of the project's schema, tagged with the producer's
+/// source if it named one.
+async fn insert_wal_row<'e, E: sqlx::Executor<'e, Database = sqlx::Sqlite>>(
+    executor: E,
+    (project_id, schema, source): (&str, &str, Option<&str>),
+    time: &str,
+    created_at: &str,
+    payload: String,
+    options: &IngestOptions,
+) -> Result<i64, sqlx::Error> {
+    let query = sqlx::query("INSERT INTO wal (project_id, schema, source, time, created_at, payload, compressed) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")
+        .bind(project_id)
+        .bind(schema)
+        .bind(source)
+        .bind(time)
+        .bind(created_at);
+    let query = match options.compress_threshold {
+        Some(threshold) if payload.len() > threshold => {
+            let compressed = common::compress_payload(&payload).map_err(sqlx::Error::Io)?;
+            query.bind(compressed).bind(1)
+        }
+        _ => query.bind(payload).bind(0),
+    };
+    Ok(query.execute(executor).await?.last_insert_rowid())
+}
+
+/// The time and payload of a batch record.
+type BatchRow = (DateTime<Utc>, String);
+
+/// Parses a JSON body, a record `{"time": "<RFC 3339>", "values": [1.0, 2.0]}`
+/// or an array of them, into the time and payload of each row or the reason
+/// the record is invalid. A record without a time is stamped with `now`.
+fn parse_batch(body: &[u8], options: &IngestOptions, now: DateTime<Utc>) -> Result<Vec<Result<BatchRow, String>>, String> {
+    let elements: Vec<serde_json::Value> = match serde_json::from_slice(body) {
+        Ok(serde_json::Value::Array(elements)) => elements,
+        Ok(element @ serde_json::Value::Object(_)) => vec![element],
+        Ok(_) => return Err("body must be a JSON record or an array of records".to_string()),
+        Err(e) => return Err(format!("body must be a JSON record or an array of records: {}", e)),
+    };
+    Ok(elements.iter().enumerate().map(|(i, element)| {
+        let time = match &element["time"] {
+            serde_json::Value::Null => now,
+            time => time.as_str()
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                .map(|t| t.with_timezone(&Utc))
+                .ok_or_else(|| format!("record {}: time must be an RFC 3339 string", i))?,
+        };
+        check_skew(i, time, options, now)?;
+        let values = element["values"].as_array()
+            .and_then(|values| values.iter().map(|v| v.as_f64()).collect::<Option<Vec<f64>>>())
+            .ok_or_else(|| format!("record {}: values must be an array of numbers", i))?;
+        if values.is_empty() && !options.allow_empty_records {
+            return Err(format!("record {}: no values", i));
+        }
+        Ok((time, values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")))
+    }).collect())
+}
+
+/// Refuses the time of record `i` when it is further from `now` than the
+/// configured skews allow.
+fn check_skew(i: usize, time: DateTime<Utc>, options: &IngestOptions, now: DateTime<Utc>) -> Result<(), String> {
+    if let Some(max) = options.max_future_skew {
+        if let Some(ahead) = time.signed_duration_since(now).to_std().ok().filter(|ahead| *ahead > max) {
+            return Err(format!("record {}: time {} is {}s in the future, more than {}s", i, time.to_rfc3339(), ahead.as_secs_f64(), max.as_secs()));
         }
     }
-    HttpResponse::Ok().body(id)
+    if let Some(max) = options.max_past_skew {
+        if let Some(behind) = now.signed_duration_since(time).to_std().ok().filter(|behind| *behind > max) {
+            return Err(format!("record {}: time {} is {}s in the past, more than {}s", i, time.to_rfc3339(), behind.as_secs_f64(), max.as_secs()));
+        }
+    }
+    Ok(())
 }
 
-async fn post_project_data(
-    path: web::Path<String>,
-    body: web::Bytes,
-    db_pool: web::Data<SqlitePool>,
-) -> impl Responder {
-    let id = path.into_inner();
-    let data = String::from_utf8(body.to_vec()).unwrap_or_default();
+/// Parses a CSV body into the time and payload of each row or the reason the
+/// row is invalid, like `parse_batch`. The header row names the columns: the
+/// time is read from `time_column` and every other column is a field of the
+/// row's JSON object payload, such as `{"cpu":0.5}`, leaving out empty cells.
+/// A row without a time is stamped with `now`.
+fn parse_csv_batch(body: &[u8], time_column: &str, options: &IngestOptions, now: DateTime<Utc>) -> Result<Vec<Result<BatchRow, String>>, String> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(body);
+    let header: Vec<String> = reader.headers().map_err(|e| format!("invalid CSV header: {}", e))?
+        .iter().map(String::from).collect();
+    if let Some(name) = header.iter().find(|name| name.is_empty()) {
+        return Err(format!("CSV header has an empty column name {:?}", name));
+    }
+    if let Some((_, name)) = header.iter().enumerate().find(|(i, name)| header[..*i].contains(name)) {
+        return Err(format!("CSV header names column {:?} twice", name));
+    }
+    let time_index = header.iter().position(|name| name == time_column)
+        .ok_or_else(|| format!("CSV header has no time column {:?}", time_column))?;
+    Ok(reader.records().enumerate().map(|(i, record)| {
+        let record = record.map_err(|e| format!("record {}: {}", i, e))?;
+        if record.len() != header.len() {
+            return Err(format!("record {}: expected {} fields, found {}", i, header.len(), record.len()));
+        }
+        let time = match &record[time_index] {
+            "" => now,
+            time => DateTime::parse_from_rfc3339(time)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|_| format!("record {}: time must be an RFC 3339 string", i))?,
+        };
+        check_skew(i, time, options, now)?;
+        let mut fields = serde_json::Map::new();
+        for (j, (name, value)) in header.iter().zip(record.iter()).enumerate() {
+            if j == time_index || value.is_empty() {
+                continue;
+            }
+            let value = value.parse::<f64>().ok().and_then(serde_json::Number::from_f64)
+                .ok_or_else(|| format!("record {}: {} must be a number, got {:?}", i, name, value))?;
+            fields.insert(name.clone(), serde_json::Value::Number(value));
+        }
+        if fields.is_empty() && !options.allow_empty_records {
+            return Err(format!("record {}: no values", i));
+        }
+        Ok((time, serde_json::Value::Object(fields).to_string()))
+    }).collect())
+}
+
+/// The response to a batch write: the number of accepted and dropped rows
+/// and, in best-effort mode, of rejected ones with the outcome of each row in
+/// input order. `outcomes` holds whether each valid row was kept or dropped by
+/// decimation, and `ids` the WAL ids of the kept ones.
+fn batch_response(outcomes: &[Result<bool, String>], ids: &[i64], best_effort: bool) -> serde_json::Value {
+    let accepted = outcomes.iter().filter(|outcome| matches!(outcome, Ok(true))).count();
+    let rejected = outcomes.iter().filter(|outcome| outcome.is_err()).count();
+    let dropped = outcomes.len() - accepted - rejected;
+    if !best_effort {
+        return serde_json::json!({ "accepted": accepted, "dropped": dropped });
+    }
+    let mut ids = ids.iter();
+    let results: Vec<serde_json::Value> = outcomes.iter().enumerate().map(|(index, outcome)| match outcome {
+        Ok(true) => serde_json::json!({ "index": index, "status": "accepted", "id": ids.next() }),
+        Ok(false) => serde_json::json!({ "index": index, "status": "dropped" }),
+        Err(reason) => serde_json::json!({ "index": index, "status": "rejected", "reason": reason }),
+    }).collect();
+    serde_json::json!({ "accepted": accepted, "rejected": rejected, "dropped": dropped, "results": results })
+}
+
+/// Records the client-assigned sequence number of a write, returning the range
+/// of sequence numbers skipped since the last one seen, if any.
+async fn track_sequence(db_pool: &SqlitePool, project_id: &str, seq: i64) -> Result<Option<(i64, i64)>, sqlx::Error> {
+    let last: Option<i64> = sqlx::query("SELECT last_seq FROM sequences WHERE project_id = ?1")
+        .bind(project_id)
+        .fetch_optional(db_pool).await?
+        .map(|row| row.try_get("last_seq")).transpose()?;
+
+    let gap = match last {
+        Some(last) if seq > last + 1 => Some((last + 1, seq - 1)),
+        _ => None,
+    };
+    if let Some((from, to)) = gap {
+        sqlx::query("INSERT INTO sequence_gaps (project_id, from_seq, to_seq, detected_at) VALUES (?1, ?2, ?3, ?4)")
+            .bind(project_id)
+            .bind(from)
+            .bind(to)
+            .bind(chrono::Utc::now().to_rfc3339())
+            .execute(db_pool).await?;
+    }
+
+    sqlx::query(
+        "INSERT INTO sequences (project_id, last_seq) VALUES (?1, ?2)
+         ON CONFLICT (project_id) DO UPDATE SET last_seq = MAX(last_seq, excluded.last_seq)"
+    )
+        .bind(project_id)
+        .bind(seq)
+        .execute(db_pool).await?;
+
+    Ok(gap)
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn hash_api_key(salt: &str, secret: &str) -> String {
+    hex::encode(Sha256::new().chain_update(salt).chain_update(secret).finalize())
+}
+
+/// Stores a new key for the project, returning its id and the key itself as
+/// `<id>.<secret>`. Only the salted hash of the secret is kept.
+async fn create_api_key(db_pool: &SqlitePool, project_id: &str) -> Result<(i64, String), sqlx::Error> {
+    let salt = random_hex(16);
+    let secret = random_hex(32);
+    let id = sqlx::query("INSERT INTO api_keys (project_id, salt, key_hash, created_at) VALUES (?1, ?2, ?3, ?4)")
+        .bind(project_id)
+        .bind(&salt)
+        .bind(hash_api_key(&salt, &secret))
+        .bind(chrono::Utc::now().to_rfc3339())
+        .execute(db_pool).await?
+        .last_insert_rowid();
+    Ok((id, format!("{}.{}", id, secret)))
+}
+
+/// Revokes a key, returning whether an active key with the id existed.
+async fn revoke_api_key(db_pool: &SqlitePool, id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE api_keys SET revoked_at = ?1 WHERE id = ?2 AND revoked_at IS NULL")
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(db_pool).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers().get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim())
+}
 
-    let result  = save_to_db(&**db_pool, id, data).await;
-    match result {
-        Ok(_) => {
-            HttpResponse::Created().finish()
+/// Checks the request's key against the project, returning the rejection:
+/// 401 without a valid, unrevoked key and 403 for another project's key.
+async fn authorize(req: &HttpRequest, db_pool: &SqlitePool, project_id: &str) -> Option<HttpResponse> {
+    let required = req.app_data::<web::Data<ApiKeys>>().map(|k| k.required).unwrap_or(false);
+    if !required {
+        return None;
+    }
+    let unauthorized = || Some(HttpResponse::Unauthorized().body("a valid API key is required"));
+    let (id, secret) = match bearer_token(req).and_then(|key| key.split_once('.')) {
+        Some((id, secret)) => match id.parse::<i64>() {
+            Ok(id) => (id, secret),
+            Err(_) => return unauthorized(),
         },
+        None => return unauthorized(),
+    };
+
+    let row = sqlx::query("SELECT project_id, salt, key_hash FROM api_keys WHERE id = ?1 AND revoked_at IS NULL")
+        .bind(id)
+        .fetch_optional(db_pool).await;
+    let row = match row {
+        Ok(Some(row)) => row,
+        Ok(None) => return unauthorized(),
         Err(e) => {
             log::error!("{}", e);
-            HttpResponse::InternalServerError().body("Failed to persist a write request")
+            return Some(HttpResponse::InternalServerError().body("Failed to check the API key"));
         }
+    };
+    if hash_api_key(&row.get::<String, _>("salt"), secret) != row.get::<String, _>("key_hash") {
+        return unauthorized();
     }
+    if row.get::<String, _>("project_id") != project_id {
+        return Some(HttpResponse::Forbidden().body("the API key is not authorized for this project"));
+    }
+    None
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init();
+fn authorize_admin(req: &HttpRequest) -> Option<HttpResponse> {
+    let keys = app_data_or_default::<ApiKeys>(req);
+    match (&keys.admin_token, bearer_token(req)) {
+        (Some(admin), Some(token)) if admin == token => None,
+        _ => Some(HttpResponse::Unauthorized().body("a valid admin token is required")),
+    }
+}
 
-    let pool = SqlitePool::connect("sqlite::memory:").await.map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("Database connection error: {}", e))
-    })?;
+/// Reads a row's payload, decompressing it if it was stored compressed. Rows
+/// selected without the `compressed` column are taken as stored verbatim.
+fn read_payload(row: &SqliteRow) -> Result<String, sqlx::Error> {
+    let compressed: i64 = row.try_get("compressed").unwrap_or(0);
+    if compressed == 0 {
+        return row.try_get("payload");
+    }
+    let bytes: Vec<u8> = row.try_get("payload")?;
+    common::decompress_payload(&bytes).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+}
 
-    initialize_database(&pool).await.map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("Database initialization error: {}", e))
-    })?;
+#[derive(Debug, PartialEq)]
+enum QueryError {
+    Empty,
+    MultipleStatements,
+    NotSelect(String),
+    OtherTable(String),
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(pool.clone()))
-            .route("/project/{id}/data", web::get().to(get_project_data))
-            .route("/project/{id}/data", web::post().to(post_project_data))
-    })
-    .bind("127.0.0.1:8000")?
-    .run()
-    .await
+impl std::fmt::Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryError::Empty => write!(f, "the query is empty"),
+            QueryError::MultipleStatements => write!(f, "only a single statement is allowed"),
+            QueryError::NotSelect(keyword) => write!(f, "only SELECT statements are allowed, got '{}'", keyword),
+            QueryError::OtherTable(name) => write!(f, "only the wal table can be read, got '{}'", name),
+        }
+    }
+}
+
+/// Accepts `q` only when it is a single `SELECT` statement. Quoted strings,
+/// identifiers and comments are skipped so a `;` inside them does not count.
+fn validate_read_only(q: &str) -> Result<(), QueryError> {
+    let mut statements = vec![String::new()];
+    let mut chars = q.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                for c in chars.by_ref() {
+                    if c == close {
+                        break;
+                    }
+                }
+                statements.last_mut().unwrap().push(' ');
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                statements.last_mut().unwrap().push(' ');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+                statements.last_mut().unwrap().push(' ');
+            }
+            ';' => statements.push(String::new()),
+            c => statements.last_mut().unwrap().push(c),
+        }
+    }
+
+    let statements: Vec<&str> = statements.iter().map(|s| s.trim()).filter(|s| !s.is_empty()).collect();
+    match statements.as_slice() {
+        [] => Err(QueryError::Empty),
+        [statement] => {
+            let keyword = statement.split(|c: char| !c.is_ascii_alphabetic()).next().unwrap_or_default();
+            if keyword.eq_ignore_ascii_case("select") {
+                Ok(())
+            } else {
+                Err(QueryError::NotSelect(keyword.to_string()))
+            }
+        }
+        _ => Err(QueryError::MultipleStatements),
+    }
+}
+
+/// The words and quoted identifiers of `q`, leaving out string literals and
+/// comments.
+fn query_identifiers(q: &str) -> Vec<String> {
+    let mut identifiers = vec![];
+    let mut word = String::new();
+    let mut chars = q.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() || c == '_' || c == '$' {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            identifiers.push(std::mem::take(&mut word));
+        }
+        match c {
+            '\'' => {
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                }
+            }
+            '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                identifiers.push(chars.by_ref().take_while(|&c| c != close).collect());
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            _ => {}
+        }
+    }
+    if !word.is_empty() {
+        identifiers.push(word);
+    }
+    identifiers
+}
+
+/// Accepts `q` only when `wal` is the one table of `tables` it names, and it
+/// names no schema or SQLite object, since `wal` is what gets scoped to the
+/// project; a `main.wal` or another table would read past that scope.
+fn validate_wal_only(q: &str, tables: &[String]) -> Result<(), QueryError> {
+    for identifier in query_identifiers(q) {
+        let name = identifier.to_ascii_lowercase();
+        let foreign = name == "main" || name == "temp"
+            || name.starts_with("sqlite_") || name.starts_with("pragma_")
+            || (name != "wal" && tables.iter().any(|t| t.eq_ignore_ascii_case(&name)));
+        if foreign {
+            return Err(QueryError::OtherTable(identifier));
+        }
+    }
+    Ok(())
+}
+
+/// Tables and views of the WAL database.
+async fn wal_tables(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query("SELECT name FROM sqlite_master WHERE type IN ('table', 'view')").fetch_all(pool).await?;
+    rows.iter().map(|row| row.try_get("name")).collect()
+}
+
+/// A result row as JSON. `time` is null when the query did not select it.
+fn row_json(row: &SqliteRow) -> Result<serde_json::Value, sqlx::Error> {
+    let mut value = serde_json::json!({
+        "project_id": row.try_get::<String, _>("project_id")?,
+        "time": row.try_get::<String, _>("time").ok(),
+        "payload": read_payload(row)?,
+    });
+    if let Some(source) = row.try_get::<Option<String>, _>("source").ok().flatten() {
+        value["source"] = serde_json::Value::String(source);
+    }
+    Ok(value)
+}
+
+/// Reads rows into JSON until their serialized size passes `max_bytes`,
+/// returning the rows and whether the limit stopped the read.
+async fn collect_rows(
+    mut rows: futures::stream::BoxStream<'_, Result<SqliteRow, sqlx::Error>>,
+    max_bytes: Option<usize>,
+) -> Result<(Vec<serde_json::Value>, bool), sqlx::Error> {
+    let mut results = vec![];
+    let mut bytes = 2;
+    while let Some(row) = rows.try_next().await? {
+        let value = row_json(&row)?;
+        bytes += value.to_string().len() + 1;
+        if max_bytes.is_some_and(|max| bytes > max) {
+            return Ok((results, true));
+        }
+        results.push(value);
+    }
+    Ok((results, false))
+}
+
+/// Runs `q` with `wal` standing for the project's rows only.
+async fn dump_select_results(
+    q: &str,
+    project_id: &str,
+    pool: &SqlitePool,
+    max_bytes: Option<usize>,
+) -> Result<(Vec<serde_json::Value>, bool), sqlx::Error> {
+    let scoped = format!("WITH wal AS (SELECT rowid, * FROM main.wal WHERE project_id = ?1) {}", q);
+    collect_rows(sqlx::query(&scoped).bind(project_id).fetch(pool), max_bytes).await
+}
+
+async fn dump_range_results(
+    pool: &SqlitePool,
+    project_id: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    max_bytes: Option<usize>,
+) -> Result<(Vec<serde_json::Value>, bool), sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT * FROM wal WHERE project_id = ?1 AND (?2 IS NULL OR time >= ?2) AND (?3 IS NULL OR time <= ?3) ORDER BY time"
+    )
+        .bind(project_id)
+        .bind(from.map(|t| t.to_rfc3339()))
+        .bind(to.map(|t| t.to_rfc3339()))
+        .fetch(pool);
+    collect_rows(rows, max_bytes).await
+}
+
+/// Keeps rows until their serialized size passes `max_bytes`, returning them
+/// and whether the limit dropped any.
+fn limit_rows(rows: Vec<serde_json::Value>, max_bytes: Option<usize>) -> (Vec<serde_json::Value>, bool) {
+    let Some(max) = max_bytes else {
+        return (rows, false);
+    };
+    let mut bytes = 2;
+    let mut kept = vec![];
+    for row in rows {
+        bytes += row.to_string().len() + 1;
+        if bytes > max {
+            return (kept, true);
+        }
+        kept.push(row);
+    }
+    (kept, false)
+}
+
+/// Reads the project's archived rows within the range from every Parquet
+/// destination under `root`, in the JSON shape of WAL rows.
+fn dump_parquet_results(
+    project_id: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    root: &std::path::Path,
+    explain: Option<&mut ScanStats>,
+) -> Result<Vec<serde_json::Value>, String> {
+    if !valid_schema(project_id) {
+        return Err(format!("invalid project id '{}'", project_id));
+    }
+    let mut explain = explain;
+    let entries = match std::fs::read_dir(root.join(project_id)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.to_string()),
+    };
+    let conn = duckdb::Connection::open_in_memory().map_err(|e| e.to_string())?;
+    conn.execute_batch("INSTALL parquet; LOAD parquet;").map_err(|e| e.to_string())?;
+
+    let bound = |op: &str, t: Option<DateTime<Utc>>| t.map(|t| format!(" AND time {} TIMESTAMP '{}'", op, t.format("%Y-%m-%d %H:%M:%S%.6f")));
+    let range = format!("{}{}", bound(">=", from).unwrap_or_default(), bound("<=", to).unwrap_or_default());
+    // Bounds on the partition column let DuckDB skip the partitions outside
+    // the range without opening their files.
+    let partition_bound = |column: &str, format: &str, op: &str, t: Option<DateTime<Utc>>| {
+        t.map(|t| format!(" AND {} {} '{}'", column, op, t.format(format))).unwrap_or_default()
+    };
+    let mut results = vec![];
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if !is_archived(&path) {
+            continue;
+        }
+        let quoted = path.to_string_lossy().replace('\'', "''");
+        // A partitioned destination is read with one glob over all its
+        // partitions, which DuckDB reads in parallel.
+        let (files, source, derived, pruned) = if path.is_dir() {
+            let (column, format) = partition_key(&path);
+            let files = format!("{}/{}=*/*.parquet", quoted, column);
+            let pruned = format!("{}{}", partition_bound(column, format, ">=", from), partition_bound(column, format, "<=", to));
+            (files.clone(), format!("read_parquet('{}', hive_partitioning=1)", files), format!("time, {}", column), pruned)
+        } else {
+            (quoted.clone(), format!("read_parquet('{}')", quoted), "time".to_string(), String::new())
+        };
+        let sql = format!(
+            "SELECT CAST(time AS VARCHAR) AS time_text, * EXCLUDE ({}) FROM {} WHERE true{}{} ORDER BY time",
+            derived, source, pruned, range,
+        );
+        if let Some(stats) = explain.as_deref_mut() {
+            stats.scan(&conn, &files, &sql).map_err(|e| e.to_string())?;
+        }
+        let mut stmt = conn.prepare(&sql).map_err(|e| e.to_string())?;
+        let mut rows = stmt.query([]).map_err(|e| e.to_string())?;
+        // Every column but the text `source` the persister writes beside
+        // `time` is a value.
+        let columns: Vec<(usize, String)> = rows.as_ref().map(|s| s.column_names()).unwrap_or_default().into_iter()
+            .enumerate().skip(1)
+            .filter(|(_, name)| name != "source")
+            .collect();
+        let names: Vec<String> = columns.iter().map(|(_, name)| name.clone()).collect();
+        let source_column = rows.as_ref().and_then(|s| s.column_index("source").ok());
+        while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+            let time: String = row.get(0).map_err(|e| e.to_string())?;
+            let time = chrono::NaiveDateTime::parse_from_str(&time, "%Y-%m-%d %H:%M:%S%.f")
+                .map_err(|e| format!("invalid archived time '{}': {}", time, e))?
+                .and_utc();
+            let values: Vec<Option<f64>> = columns.iter()
+                .map(|(i, _)| row.get(*i))
+                .collect::<Result<_, _>>()
+                .map_err(|e| e.to_string())?;
+            let payload = payload_from_columns(&names, values);
+            let mut value = serde_json::json!({ "project_id": project_id, "time": time.to_rfc3339(), "payload": payload });
+            if let Some(i) = source_column {
+                if let Some(source) = row.get::<_, Option<String>>(i).map_err(|e| e.to_string())? {
+                    value["source"] = serde_json::Value::String(source);
+                }
+            }
+            results.push(value);
+        }
+    }
+    Ok(results)
+}
+
+/// Partition columns of the granularities the persister writes, with the
+/// format of their values, which sort like the times they hold.
+const PARTITION_KEYS: [(&str, &str); 3] = [("hour", "%Y-%m-%dT%H"), ("date", "%Y-%m-%d"), ("month", "%Y-%m")];
+
+/// The partition column and value format of a partitioned destination,
+/// `date` unless its partitions are named by another.
+fn partition_key(dir: &std::path::Path) -> (&'static str, &'static str) {
+    let names: Vec<String> = std::fs::read_dir(dir).into_iter().flatten()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect();
+    PARTITION_KEYS.into_iter()
+        .find(|(column, _)| names.iter().any(|n| n.starts_with(&format!("{}=", column))))
+        .unwrap_or(PARTITION_KEYS[1])
+}
+
+/// Whether a path under a project's archive directory holds archived rows:
+/// not a persister's temporary, lock or identity file.
+fn is_archived(path: &std::path::Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    !name.starts_with('.') && ![".lock", ".tmp", ".identity"].iter().any(|ext| name.ends_with(ext))
+}
+
+/// The Parquet files of a project's archive, each plain destination and each
+/// file of a partitioned one, named by their path under `dir` and sorted.
+fn archive_fragments(dir: &std::path::Path) -> std::io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if !is_archived(&path) {
+            continue;
+        }
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !path.is_dir() {
+            names.push(name.to_string());
+            continue;
+        }
+        for partition in std::fs::read_dir(&path)? {
+            let partition = partition?.path();
+            if !partition.is_dir() || !is_archived(&partition) {
+                continue;
+            }
+            for file in std::fs::read_dir(&partition)? {
+                let file = file?.path();
+                if file.extension().is_some_and(|e| e == "parquet") {
+                    if let Ok(relative) = file.strip_prefix(dir) {
+                        names.push(relative.to_string_lossy().into_owned());
+                    }
+                }
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Each fragment of the project's archive with its size in bytes, row count
+/// and the time range of its rows.
+fn describe_fragments(dir: &std::path::Path) -> Result<Vec<serde_json::Value>, String> {
+    let names = archive_fragments(dir).map_err(|e| e.to_string())?;
+    let conn = duckdb::Connection::open_in_memory().map_err(|e| e.to_string())?;
+    conn.execute_batch("INSTALL parquet; LOAD parquet;").map_err(|e| e.to_string())?;
+    let time = |t: Option<String>| t.and_then(|t| chrono::NaiveDateTime::parse_from_str(&t, "%Y-%m-%d %H:%M:%S%.f").ok()).map(|t| t.and_utc().to_rfc3339());
+    names.into_iter().map(|name| {
+        let path = dir.join(&name);
+        let size = std::fs::metadata(&path).map_err(|e| e.to_string())?.len();
+        let sql = format!(
+            "SELECT COUNT(*), CAST(MIN(time) AS VARCHAR), CAST(MAX(time) AS VARCHAR) FROM read_parquet('{}')",
+            path.to_string_lossy().replace('\'', "''"),
+        );
+        let (rows, from, to): (i64, Option<String>, Option<String>) = conn.query_row(&sql, [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| format!("{}: {}", name, e))?;
+        Ok(serde_json::json!({ "name": name, "size": size, "rows": rows, "from": time(from), "to": time(to) }))
+    }).collect()
+}
+
+/// What `?explain=true` reports about the archive reads of a request: the
+/// Parquet files and row groups they cover and DuckDB's `EXPLAIN ANALYZE`
+/// of each query.
+#[derive(Debug, Default)]
+struct ScanStats {
+    files: Vec<String>,
+    row_groups: i64,
+    plans: Vec<String>,
+}
+
+impl ScanStats {
+    fn scan(&mut self, conn: &duckdb::Connection, files: &str, sql: &str) -> Result<(), duckdb::Error> {
+        let metadata = format!("parquet_metadata('{}')", files);
+        let mut stmt = conn.prepare(&format!("SELECT DISTINCT file_name FROM {} ORDER BY file_name", metadata))?;
+        let names = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()?;
+        self.files.extend(names);
+        let sql_groups = format!("SELECT COUNT(*) FROM (SELECT DISTINCT file_name, row_group_id FROM {})", metadata);
+        self.row_groups += conn.query_row(&sql_groups, [], |row| row.get::<_, i64>(0))?;
+        let mut stmt = conn.prepare(&format!("EXPLAIN ANALYZE {}", sql))?;
+        let plan = stmt.query_map([], |row| row.get::<_, String>(1))?.collect::<Result<Vec<_>, _>>()?;
+        self.plans.push(plan.join("\n"));
+        Ok(())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "files": self.files, "row_groups": self.row_groups, "plans": self.plans })
+    }
+}
+
+/// Renders column values as the payload they would be written as: the
+/// comma-separated values of positional `fN` columns, without trailing
+/// NULLs, or else a JSON object of the named columns that have a value.
+fn payload_from_columns(names: &[String], values: Vec<Option<f64>>) -> String {
+    let positions: Option<Vec<usize>> = names.iter().map(|n| n.strip_prefix('f').and_then(|i| i.parse().ok())).collect();
+    match positions {
+        Some(positions) => {
+            let mut ordered: Vec<Option<f64>> = vec![None; positions.iter().max().map_or(0, |m| m + 1)];
+            for (position, value) in positions.into_iter().zip(values) {
+                ordered[position] = value;
+            }
+            while ordered.last() == Some(&None) {
+                ordered.pop();
+            }
+            ordered.iter().map(|v| v.map(|v| v.to_string()).unwrap_or_default()).collect::<Vec<_>>().join(", ")
+        }
+        None => {
+            let fields: serde_json::Map<String, serde_json::Value> = names.iter().cloned().zip(values)
+                .map(|(name, value)| (name, serde_json::json!(value)))
+                .collect();
+            serde_json::Value::Object(fields).to_string()
+        }
+    }
+}
+
+/// Identifies a row by its time to the millisecond, the coarsest unit the
+/// persister writes, and its payload's numeric fields, so a row archived but
+/// not yet deleted from the WAL is recognised in both.
+fn row_key(row: &serde_json::Value) -> Option<(i64, Vec<(String, u64)>)> {
+    let time = DateTime::parse_from_rfc3339(row["time"].as_str()?).ok()?;
+    let mut fields: Vec<(String, u64)> = payload_fields(row["payload"].as_str()?).into_iter()
+        .filter_map(|(name, value)| Some((name, value?.to_bits())))
+        .collect();
+    fields.sort();
+    Some((time.timestamp_millis(), fields))
+}
+
+/// The tier a range read is served from, chosen with `?source=`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Wal,
+    Parquet,
+    Unified,
+}
+
+fn parse_source(s: Option<&str>) -> Result<Source, String> {
+    match s.map(|s| s.trim()) {
+        None | Some("unified") => Ok(Source::Unified),
+        Some("wal") => Ok(Source::Wal),
+        Some("parquet") => Ok(Source::Parquet),
+        Some(other) => Err(format!("source must be 'wal', 'parquet' or 'unified', got '{}'", other)),
+    }
+}
+
+/// The project's rows within the range from the archive and the WAL, or only
+/// the one `source` names, ordered by time. WAL rows already in the archive
+/// are returned once.
+async fn dump_archived_range_results(
+    pool: &SqlitePool,
+    archive: Option<&ParquetArchive>,
+    source: Source,
+    project_id: &str,
+    (from, to): (Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+    (max_bytes, timeout): (Option<usize>, Option<Duration>),
+    explain: Option<&mut ScanStats>,
+) -> Result<(Vec<serde_json::Value>, bool), BlockingError> {
+    let (recent, truncated) = match source {
+        Source::Parquet => (vec![], false),
+        _ => dump_range_results(pool, project_id, from, to, max_bytes).await.map_err(|e| e.to_string())?,
+    };
+    let Some(root) = archive.and_then(|a| a.root.clone()).filter(|_| source != Source::Wal) else {
+        if source == Source::Parquet {
+            return Err(BlockingError::Failed("source=parquet needs PARQUET_ROOT to be set".to_string()));
+        }
+        return Ok((recent, truncated));
+    };
+    let id = project_id.to_string();
+    let mut stats = explain.as_ref().map(|_| ScanStats::default());
+    let (mut rows, stats) = run_blocking(timeout, move || dump_parquet_results(&id, from, to, &root, stats.as_mut()).map(|rows| (rows, stats))).await?;
+    if let (Some(explain), Some(stats)) = (explain, stats) {
+        *explain = stats;
+    }
+    let archived: std::collections::HashSet<_> = rows.iter().filter_map(row_key).collect();
+    rows.extend(recent.into_iter().filter(|r| !row_key(r).is_some_and(|k| archived.contains(&k))));
+    rows.sort_by_key(|r| r["time"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok()));
+    let (rows, limited) = limit_rows(rows, max_bytes);
+    Ok((rows, truncated || limited))
+}
+
+/// Parses `now` or `now-<n><unit>` with a unit of `s`, `m`, `h` or `d`.
+fn parse_relative_time(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let s = s.trim();
+    let offset = match s.strip_prefix("now") {
+        Some("") => return Ok(now),
+        Some(offset) => offset.strip_prefix('-')
+            .ok_or_else(|| format!("invalid relative time '{}': expected now-<n><unit>", s))?,
+        None => return Err(format!("invalid relative time '{}': must start with 'now'", s)),
+    };
+
+    let split = offset.len().saturating_sub(1);
+    let (n, unit) = offset.split_at(split);
+    if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("invalid relative time '{}': bad amount '{}'", s, n));
+    }
+    let unit_secs: i64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid relative time '{}': unknown unit '{}'", s, unit)),
+    };
+    n.parse::<i64>().ok()
+        .and_then(|n| n.checked_mul(unit_secs))
+        .filter(|secs| secs.checked_mul(1000).is_some())
+        .and_then(|secs| now.checked_sub_signed(chrono::Duration::seconds(secs)))
+        .ok_or_else(|| format!("invalid relative time '{}': out of range", s))
+}
+
+/// Parses a time query parameter, either RFC3339 or a relative expression.
+fn parse_time_param(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if s.trim().starts_with("now") {
+        return parse_relative_time(s, now);
+    }
+    DateTime::parse_from_rfc3339(s.trim())
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time '{}': {}", s, e))
+}
+
+
+async fn get_project_data(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<std::collections::HashMap<String, String>>,
+    db_pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let id = path.into_inner();
+    // A signed URL stands in for the API key, but only to export.
+    let denied = if query.contains_key("signature") {
+        verify_signed_export(&req, &query)
+    } else {
+        authorize(&req, &db_pool, &id).await
+    };
+    if let Some(resp) = denied {
+        return resp;
+    }
+    if !valid_schema(&id) {
+        return invalid_project_id();
+    }
+    let q = query.get("q").cloned().unwrap_or_default();
+
+    let now = Utc::now();
+    let range = query.get("from").map(|s| parse_time_param(s, now)).transpose()
+        .and_then(|from| Ok((from, query.get("to").map(|s| parse_time_param(s, now)).transpose()?)));
+    let (from, to) = match range {
+        Ok(range) => range,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let source = match parse_source(query.get("source").map(|s| s.as_str())) {
+        Ok(source) => source,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let parquet = match query.get("format").map(|f| f.as_str()) {
+        None | Some("json") => false,
+        Some("parquet") => true,
+        Some(other) => return HttpResponse::BadRequest().body(format!("format must be 'json' or 'parquet', got '{}'", other)),
+    };
+    let rate = match (query.get("transform").map(|t| t.as_str()), query.get("negative").map(|n| n.as_str())) {
+        (None, None) => None,
+        (Some("rate"), None | Some("null")) => Some(NegativeRate::Null),
+        (Some("rate"), Some("reset")) => Some(NegativeRate::Reset),
+        (Some("rate"), Some(other)) => return HttpResponse::BadRequest().body(format!("negative must be 'null' or 'reset', got '{}'", other)),
+        (None, Some(_)) => return HttpResponse::BadRequest().body("negative only applies to transform=rate"),
+        (Some(other), _) => return HttpResponse::BadRequest().body(format!("transform must be 'rate', got '{}'", other)),
+    };
+    let encoding = match negotiate_encoding(&req) {
+        _ if parquet => Encoding::Json,
+        Some(encoding) => encoding,
+        None => return HttpResponse::NotAcceptable().body(format!("results are served as application/json, {} or with format=parquet", MSGPACK_CONTENT_TYPE)),
+    };
+    let ts_format = match query.get("ts_format").map(|f| f.as_str()) {
+        None | Some("rfc3339") => TsFormat::Rfc3339,
+        Some("epoch_ms") => TsFormat::EpochMs,
+        Some("epoch_ns") => TsFormat::EpochNs,
+        Some(other) => return HttpResponse::BadRequest().body(format!("ts_format must be 'rfc3339', 'epoch_ms' or 'epoch_ns', got '{}'", other)),
+    };
+    let null_as = match query.get("null_as").map(|n| n.as_str()) {
+        None | Some("null") => NullAs::Null,
+        Some("omit") => NullAs::Omit,
+        Some(token) => NullAs::Token(token.to_string()),
+    };
+    if parquet && null_as != NullAs::Null {
+        return HttpResponse::BadRequest().body("null_as only applies to JSON output; Parquet stores missing values as NULL");
+    }
+    if parquet && ts_format != TsFormat::Rfc3339 {
+        return HttpResponse::BadRequest().body("ts_format only applies to JSON output; Parquet stores timestamps natively");
+    }
+    if !q.is_empty() && source == Source::Parquet {
+        return HttpResponse::BadRequest().body("q queries read the WAL; source=parquet only applies to range reads");
+    }
+
+    if !q.is_empty() {
+        let tables = match wal_tables(&db_pool).await {
+            Ok(tables) => tables,
+            Err(e) => {
+                log::error!("{}", e);
+                return HttpResponse::InternalServerError().body("Failed to read the WAL tables");
+            }
+        };
+        if let Err(e) = validate_read_only(&q).and_then(|_| validate_wal_only(&q, &tables)) {
+            return HttpResponse::Forbidden().json(serde_json::json!({ "error": e.to_string() }));
+        }
+    }
+
+    let limit = req.app_data::<web::Data<ResponseLimit>>();
+    let max_bytes = limit.and_then(|l| l.max_bytes);
+    let timeout = app_data_or_default::<QueryTimeout>(&req).0;
+    let mut explain = query.get("explain").is_some_and(|v| v == "true" || v == "1").then(ScanStats::default);
+    let started = Instant::now();
+    let result = if q.is_empty() {
+        let archive = req.app_data::<web::Data<ParquetArchive>>().map(|a| a.get_ref());
+        dump_archived_range_results(&db_pool, archive, source, &id, (from, to), (max_bytes, timeout), explain.as_mut()).await
+    } else {
+        dump_select_results(&q, &id, &db_pool, max_bytes).await.map_err(|e| BlockingError::Failed(e.to_string()))
+    };
+    let query_time = started.elapsed();
+    let include_stats = query.get("include_stats").is_some_and(|v| v == "true" || v == "1");
+    let (rows, truncated) = match result {
+        Ok((_, true)) if !limit.is_some_and(|l| l.truncate) => {
+            return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                "error": format!("the result exceeds {} bytes", max_bytes.unwrap_or_default()),
+            }));
+        }
+        Ok(result) => result,
+        Err(e @ BlockingError::TimedOut(_)) => return query_timed_out(&e),
+        Err(e) => {
+            log::error!("query error: {}", e);
+            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e.to_string() }));
+        }
+    };
+    let rows = match rate {
+        Some(negative) => match run_blocking(timeout, move || rate_rows(rows, negative)).await {
+            Ok(rows) => rows,
+            Err(e @ BlockingError::TimedOut(_)) => return query_timed_out(&e),
+            Err(e) => {
+                log::error!("{}", e);
+                return HttpResponse::InternalServerError().body("Failed to compute the rates");
+            }
+        },
+        None => rows,
+    };
+    let rows = if query.get("changes_only").is_some_and(|v| v == "true" || v == "1") {
+        match run_blocking(timeout, move || changed_rows(rows)).await {
+            Ok(rows) => rows,
+            Err(e @ BlockingError::TimedOut(_)) => return query_timed_out(&e),
+            Err(e) => {
+                log::error!("{}", e);
+                return HttpResponse::InternalServerError().body("Failed to filter the unchanged rows");
+            }
+        }
+    } else {
+        rows
+    };
+    if parquet {
+        return match run_blocking(timeout, move || export_parquet(&rows)).await {
+            Ok(bytes) => HttpResponse::Ok()
+                .content_type(PARQUET_CONTENT_TYPE)
+                .insert_header(("Content-Disposition", format!("attachment; filename=\"{}.parquet\"", id)))
+                .body(bytes),
+            Err(e @ BlockingError::TimedOut(_)) => query_timed_out(&e),
+            Err(e) => {
+                log::error!("{}", e);
+                HttpResponse::InternalServerError().body("Failed to export the results")
+            }
+        };
+    }
+    let mut rows = rows;
+    render_nulls(&mut rows, &null_as);
+    format_times(&mut rows, ts_format);
+    let count = rows.len();
+    let mut body = if include_stats {
+        let (rows, stats) = match run_blocking(timeout, move || field_stats(&rows).map(|stats| (rows, stats))).await {
+            Ok(result) => result,
+            Err(e @ BlockingError::TimedOut(_)) => return query_timed_out(&e),
+            Err(e) => {
+                log::error!("{}", e);
+                return HttpResponse::InternalServerError().body("Failed to compute the field stats");
+            }
+        };
+        serde_json::json!({ "rows": rows, "stats": stats })
+    } else if truncated {
+        serde_json::json!({ "rows": rows })
+    } else {
+        serde_json::Value::Array(rows)
+    };
+    if truncated {
+        body["truncated"] = serde_json::Value::Bool(true);
+    }
+    if explain.is_some() || query.get("envelope").is_some_and(|v| v == "true" || v == "1") {
+        body = serde_json::json!({
+            "data": body,
+            "meta": {
+                "count": count,
+                "query_time_ms": query_time.as_secs_f64() * 1000.0,
+                "source": if q.is_empty() { "range" } else { "query" },
+            },
+        });
+        if let Some(explain) = &explain {
+            body["meta"]["explain"] = explain.to_json();
+        }
+    }
+    match encoding {
+        Encoding::Json => json_response(&body, wants_pretty(&query)),
+        Encoding::MessagePack => msgpack_response(&body),
+    }
+}
+
+/// Loads the payload fields of the rows into a DuckDB table `results` of
+/// each row's index `idx`, its `time` and one DOUBLE column `c<n>` per field,
+/// returning the field names in column order.
+fn load_results(conn: &duckdb::Connection, rows: &[serde_json::Value]) -> Result<Vec<String>, duckdb::Error> {
+    let parsed: Vec<Vec<(String, Option<f64>)>> = rows.iter()
+        .map(|r| r["payload"].as_str().map(payload_fields).unwrap_or_default())
+        .collect();
+    let mut names: Vec<String> = vec![];
+    for (name, _) in parsed.iter().flatten() {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    let columns: String = (0..names.len()).map(|i| format!(", c{} DOUBLE", i)).collect();
+    conn.execute_batch(&format!("CREATE TABLE results (idx BIGINT, time TIMESTAMP{})", columns))?;
+    let mut appender = conn.appender("results")?;
+    for (idx, (row, fields)) in rows.iter().zip(&parsed).enumerate() {
+        let time = match row["time"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok()) {
+            Some(time) => duckdb::types::Value::Timestamp(duckdb::types::TimeUnit::Microsecond, time.timestamp_micros()),
+            None => duckdb::types::Value::Null,
+        };
+        let values = names.iter().map(|name| match fields.iter().find(|(n, _)| n == name) {
+            Some((_, Some(v))) => duckdb::types::Value::Double(*v),
+            _ => duckdb::types::Value::Null,
+        });
+        let keys = [duckdb::types::Value::BigInt(idx as i64), time];
+        appender.append_row(duckdb::appender_params_from_iter(keys.into_iter().chain(values)))?;
+    }
+    appender.flush();
+    Ok(names)
+}
+
+/// Min, max, average and count of each payload field over the rows, keyed
+/// like the Parquet columns and computed in DuckDB. Values that are not
+/// numbers are left out of their field's stats.
+fn field_stats(rows: &[serde_json::Value]) -> Result<serde_json::Value, duckdb::Error> {
+    let conn = duckdb::Connection::open_in_memory()?;
+    let names = load_results(&conn, rows)?;
+    if names.is_empty() {
+        return Ok(serde_json::json!({}));
+    }
+    let aggregates: Vec<String> = (0..names.len()).map(|i| format!("min(c{i}), max(c{i}), avg(c{i}), count(c{i})")).collect();
+    conn.query_row(&format!("SELECT {} FROM results", aggregates.join(", ")), [], |row| {
+        names.iter().enumerate().map(|(i, name)| {
+            let stat = serde_json::json!({
+                "min": row.get::<_, Option<f64>>(i * 4)?,
+                "max": row.get::<_, Option<f64>>(i * 4 + 1)?,
+                "avg": row.get::<_, Option<f64>>(i * 4 + 2)?,
+                "count": row.get::<_, i64>(i * 4 + 3)?,
+            });
+            Ok((name.clone(), stat))
+        }).collect::<Result<serde_json::Map<_, _>, _>>()
+    }).map(serde_json::Value::Object)
+}
+
+/// Why blocking DuckDB work returned no result.
+#[derive(Debug)]
+enum BlockingError {
+    /// The work or running it failed.
+    Failed(String),
+    /// The work ran past the `QueryTimeout`.
+    TimedOut(Duration),
+}
+
+impl std::fmt::Display for BlockingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockingError::Failed(e) => write!(f, "{}", e),
+            BlockingError::TimedOut(limit) => write!(f, "the query ran longer than {}s", limit.as_secs_f64()),
+        }
+    }
+}
+
+impl From<String> for BlockingError {
+    fn from(e: String) -> Self {
+        BlockingError::Failed(e)
+    }
+}
+
+/// Runs blocking DuckDB work off the async workers, folding its error and a
+/// failure to run it into one message, and giving up on it after `timeout`.
+async fn run_blocking<T, E>(timeout: Option<Duration>, f: impl FnOnce() -> Result<T, E> + Send + 'static) -> Result<T, BlockingError>
+where
+    T: Send + 'static,
+    E: std::fmt::Display + Send + 'static,
+{
+    let work = web::block(f);
+    let result = match timeout {
+        Some(limit) => actix_web::rt::time::timeout(limit, work).await.map_err(|_| BlockingError::TimedOut(limit))?,
+        None => work.await,
+    };
+    result.map_err(|e| e.to_string())?.map_err(|e| BlockingError::Failed(e.to_string()))
+}
+
+fn query_timed_out(e: &BlockingError) -> HttpResponse {
+    log::warn!("{}", e);
+    HttpResponse::GatewayTimeout().json(serde_json::json!({ "error": e.to_string() }))
+}
+
+/// Keeps the rows whose payload fields differ from the previous row's, and
+/// always the first and last, comparing them in DuckDB with `lag`.
+fn changed_rows(rows: Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, duckdb::Error> {
+    let conn = duckdb::Connection::open_in_memory()?;
+    let names = load_results(&conn, &rows)?;
+    let changed: Vec<String> = (0..names.len()).map(|i| format!("c{i} IS DISTINCT FROM lag(c{i}) OVER w")).collect();
+    let changed = if changed.is_empty() { "false".to_string() } else { changed.join(" OR ") };
+    let sql = format!(
+        "SELECT idx FROM (
+             SELECT idx, {} AS changed, idx = 0 OR idx = (SELECT max(idx) FROM results) AS endpoint
+             FROM results WINDOW w AS (ORDER BY idx)
+         ) WHERE changed OR endpoint",
+        changed,
+    );
+    let kept = conn.prepare(&sql)?.query_map([], |row| row.get::<_, i64>(0))?
+        .collect::<Result<std::collections::HashSet<i64>, _>>()?;
+    Ok(rows.into_iter().enumerate().filter(|(i, _)| kept.contains(&(*i as i64))).map(|(_, row)| row).collect())
+}
+
+/// How `?null_as` renders a missing value in the payload of each result row.
+#[derive(Clone, Debug, PartialEq)]
+enum NullAs {
+    /// `null` in a JSON payload and an empty token in a comma-separated one.
+    Null,
+    /// The key is left out of a JSON object payload; positional payloads
+    /// keep their empty token or `null`.
+    Omit,
+    /// The string replaces the missing value.
+    Token(String),
+}
+
+/// Renders the missing values of each row's payload as `null_as` asks: the
+/// `null` values of a JSON payload and the empty tokens of a comma-separated
+/// one.
+fn render_nulls(rows: &mut [serde_json::Value], null_as: &NullAs) {
+    if *null_as == NullAs::Null {
+        return;
+    }
+    for row in rows {
+        let Some(payload) = row["payload"].as_str() else {
+            continue;
+        };
+        let rendered = match serde_json::from_str::<serde_json::Value>(payload) {
+            Ok(serde_json::Value::Object(fields)) if payload.trim_start().starts_with('{') => {
+                let fields: serde_json::Map<String, serde_json::Value> = fields.into_iter().filter_map(|(name, value)| match (value, null_as) {
+                    (serde_json::Value::Null, NullAs::Omit) => None,
+                    (serde_json::Value::Null, NullAs::Token(token)) => Some((name, token.clone().into())),
+                    (value, _) => Some((name, value)),
+                }).collect();
+                serde_json::Value::Object(fields).to_string()
+            }
+            Ok(serde_json::Value::Array(values)) if payload.trim_start().starts_with('[') => match null_as {
+                NullAs::Token(token) => serde_json::Value::Array(values.into_iter()
+                    .map(|value| if value.is_null() { token.clone().into() } else { value })
+                    .collect()).to_string(),
+                _ => continue,
+            },
+            _ => match null_as {
+                NullAs::Token(token) if payload.split(',').any(|v| v.trim().is_empty()) => payload.split(',')
+                    .map(|v| if v.trim().is_empty() { token.as_str() } else { v.trim() })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                _ => continue,
+            },
+        };
+        row["payload"] = rendered.into();
+    }
+}
+
+/// How `?ts_format` renders the `time` of each result row.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TsFormat {
+    /// The RFC 3339 string the row was read with.
+    Rfc3339,
+    /// Milliseconds since the Unix epoch.
+    EpochMs,
+    /// Nanoseconds since the Unix epoch.
+    EpochNs,
+}
+
+/// Rewrites the `time` of each row in the format. A time that is not an
+/// RFC 3339 string, like the columns of a `q` query, is left as it is.
+fn format_times(rows: &mut [serde_json::Value], format: TsFormat) {
+    if format == TsFormat::Rfc3339 {
+        return;
+    }
+    for row in rows {
+        let Some(time) = row["time"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok()) else {
+            continue;
+        };
+        row["time"] = match format {
+            TsFormat::EpochMs => time.timestamp_millis().into(),
+            TsFormat::EpochNs => time.timestamp_nanos_opt().map_or(serde_json::Value::Null, Into::into),
+            TsFormat::Rfc3339 => unreachable!(),
+        };
+    }
+}
+
+/// How `?transform=rate` treats a value lower than the previous one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NegativeRate {
+    /// The interval has no rate.
+    Null,
+    /// The counter restarted from zero, so the value is its increase.
+    Reset,
+}
+
+/// Replaces each row's values by their rate of change per second since the
+/// previous row, computed in DuckDB with window functions. The first row has
+/// no previous one and is left out.
+fn rate_rows(rows: Vec<serde_json::Value>, negative: NegativeRate) -> Result<Vec<serde_json::Value>, duckdb::Error> {
+    let conn = duckdb::Connection::open_in_memory()?;
+    let names = load_results(&conn, &rows)?;
+    let rates: String = (0..names.len()).map(|i| {
+        let delta = format!("c{i} - lag(c{i}) OVER w");
+        let delta = match negative {
+            NegativeRate::Null => format!("CASE WHEN {delta} < 0 THEN NULL ELSE {delta} END"),
+            NegativeRate::Reset => format!("CASE WHEN {delta} < 0 THEN c{i} ELSE {delta} END"),
+        };
+        format!(", ({}) / NULLIF(date_diff('microsecond', lag(time) OVER w, time) / 1e6, 0)", delta)
+    }).collect();
+    let sql = format!("SELECT idx{} FROM results WINDOW w AS (ORDER BY idx) ORDER BY idx", rates);
+    let mut stmt = conn.prepare(&sql)?;
+    let rates: Vec<(usize, Vec<Option<f64>>)> = stmt.query_map([], |row| {
+        let values = (1..=names.len()).map(|i| row.get(i)).collect::<Result<_, _>>()?;
+        Ok((row.get::<_, i64>(0)? as usize, values))
+    })?.collect::<Result<_, _>>()?;
+    Ok(rates.into_iter().skip(1).map(|(idx, values)| {
+        let mut row = rows[idx].clone();
+        row["payload"] = serde_json::Value::String(payload_from_columns(&names, values));
+        row
+    }).collect())
+}
+
+const PARQUET_CONTENT_TYPE: &str = "application/vnd.apache.parquet";
+
+/// A file removed when dropped, so an export never outlives its request
+/// whichever step fails.
+struct TempFile(std::path::PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Writes the rows, in order, as a Parquet file of `time` and one DOUBLE
+/// column per payload field named like the persister's, and returns its bytes.
+fn export_parquet(rows: &[serde_json::Value]) -> Result<Vec<u8>, String> {
+    let conn = duckdb::Connection::open_in_memory().map_err(|e| e.to_string())?;
+    conn.execute_batch("INSTALL parquet; LOAD parquet;").map_err(|e| e.to_string())?;
+    let names = load_results(&conn, rows).map_err(|e| e.to_string())?;
+    let columns: String = names.iter().enumerate().map(|(i, name)| format!(", c{} AS \"{}\"", i, name.replace('"', "\"\""))).collect();
+    let file = TempFile(std::env::temp_dir().join(format!("zeta-export-{}.parquet", generate_request_id())));
+    let path = file.0.to_string_lossy().replace('\'', "''");
+    conn.execute_batch(&format!("COPY (SELECT time{} FROM results ORDER BY idx) TO '{}' (FORMAT 'parquet')", columns, path))
+        .map_err(|e| e.to_string())?;
+    std::fs::read(&file.0).map_err(|e| e.to_string())
+}
+
+/// Whether `?pretty=true` asks for indented JSON.
+fn wants_pretty(query: &HashMap<String, String>) -> bool {
+    query.get("pretty").map(|v| v == "true" || v == "1").unwrap_or(false)
+}
+
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// How a query result is encoded in the response body.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Json,
+    MessagePack,
+}
+
+/// Picks the encoding the `Accept` header ranks highest, JSON when there is
+/// none, or `None` when it accepts neither.
+fn negotiate_encoding(req: &HttpRequest) -> Option<Encoding> {
+    let Ok(accept) = <actix_web::http::header::Accept as actix_web::http::header::Header>::parse(req) else {
+        return Some(Encoding::Json);
+    };
+    if accept.is_empty() {
+        return Some(Encoding::Json);
+    }
+    accept.ranked().into_iter().find_map(|mime| match (mime.type_().as_str(), mime.subtype().as_str()) {
+        ("application", "json") | ("application", "*") | ("*", "*") => Some(Encoding::Json),
+        ("application", "msgpack") | ("application", "x-msgpack") => Some(Encoding::MessagePack),
+        _ => None,
+    })
+}
+
+fn msgpack_response(value: &serde_json::Value) -> HttpResponse {
+    match rmp_serde::to_vec_named(value) {
+        Ok(body) => HttpResponse::Ok().content_type(MSGPACK_CONTENT_TYPE).body(body),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to serialize the response")
+        }
+    }
+}
+
+fn json_response(value: &serde_json::Value, pretty: bool) -> HttpResponse {
+    if !pretty {
+        return HttpResponse::Ok().json(value);
+    }
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => HttpResponse::Ok().content_type("application/json").body(body),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to serialize the response")
+        }
+    }
+}
+
+async fn get_stats(query: web::Query<HashMap<String, String>>, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    let rows = sqlx::query("SELECT project_id, from_seq, to_seq, detected_at FROM sequence_gaps ORDER BY rowid")
+        .fetch_all(&**db_pool).await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            log::error!("{}", e);
+            return HttpResponse::InternalServerError().body("Failed to read stats");
+        }
+    };
+
+    let gaps: Vec<serde_json::Value> = rows.iter().map(|row| serde_json::json!({
+        "project_id": row.get::<String, _>("project_id"),
+        "from": row.get::<i64, _>("from_seq"),
+        "to": row.get::<i64, _>("to_seq"),
+        "detected_at": row.get::<String, _>("detected_at"),
+    })).collect();
+    json_response(&serde_json::json!({ "sequence_gaps": gaps }), wants_pretty(&query))
+}
+
+async fn get_wal_schema(query: web::Query<HashMap<String, String>>, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    let rows = match sqlx::query("PRAGMA table_info(wal)").fetch_all(&**db_pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            log::error!("{}", e);
+            return HttpResponse::InternalServerError().body("Failed to read the WAL schema");
+        }
+    };
+
+    let columns: Vec<serde_json::Value> = rows.iter().map(|row| serde_json::json!({
+        "name": row.get::<String, _>("name"),
+        "type": row.get::<String, _>("type"),
+        "not_null": row.get::<bool, _>("notnull"),
+        "default": row.get::<Option<String>, _>("dflt_value"),
+        "primary_key": row.get::<i64, _>("pk") > 0,
+    })).collect();
+    json_response(&serde_json::json!({ "table": "wal", "columns": columns }), wants_pretty(&query))
+}
+
+/// Liveness: answers as long as the server is running.
+async fn get_health() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: 200 once the WAL database answers a query, 503 otherwise.
+async fn get_ready(db_pool: web::Data<SqlitePool>) -> impl Responder {
+    match sqlx::query("SELECT 1").execute(&**db_pool).await {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({ "status": "ready" })),
+        Err(e) => {
+            log::error!("readiness check failed: {}", e);
+            HttpResponse::ServiceUnavailable().json(serde_json::json!({ "status": "unavailable" }))
+        }
+    }
+}
+
+/// Reports 200 while the persister's last completed cycle is recent and 503
+/// when it is stale or has never been recorded.
+async fn get_persister_health(req: HttpRequest, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    let health = app_data_or_default::<PersisterHealth>(&req);
+    let row = match sqlx::query("SELECT last_cycle_at, last_batch_rows FROM heartbeat WHERE id = 1").fetch_optional(&**db_pool).await {
+        Ok(row) => row,
+        Err(e) => {
+            log::error!("{}", e);
+            return HttpResponse::InternalServerError().body("Failed to read the persister heartbeat");
+        }
+    };
+    let row = match row {
+        Some(row) => row,
+        None => return HttpResponse::ServiceUnavailable().json(serde_json::json!({ "healthy": false })),
+    };
+
+    let last_cycle_at: String = row.get("last_cycle_at");
+    let age = DateTime::parse_from_rfc3339(&last_cycle_at)
+        .map(|t| Utc::now().signed_duration_since(t).num_seconds())
+        .unwrap_or(i64::MAX);
+    let healthy = age <= health.stale_after.as_secs() as i64;
+    let body = serde_json::json!({
+        "healthy": healthy,
+        "last_cycle_at": last_cycle_at,
+        "last_batch_rows": row.get::<i64, _>("last_batch_rows"),
+        "age_secs": age,
+    });
+    if healthy {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+async fn post_project_data(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+    db_pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let is_batch = req.headers().get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let format = if is_batch { Some(BatchFormat::Json) } else { None };
+    ingest(&req, path.into_inner(), &query, body, &db_pool, format).await
+}
+
+/// Writes the records of a CSV body, whose header row names the columns, as
+/// a batch. `?time_column=` names the column of the times, `time` by default.
+async fn post_project_csv(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+    db_pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    ingest(&req, path.into_inner(), &query, body, &db_pool, Some(BatchFormat::Csv)).await
+}
+
+/// How the body of a batch write is encoded.
+#[derive(Clone, Copy, PartialEq)]
+enum BatchFormat {
+    Json,
+    Csv,
+}
+
+/// Writes a single payload, or the records of a batch `format`, to the WAL.
+async fn ingest(
+    req: &HttpRequest,
+    id: String,
+    query: &HashMap<String, String>,
+    body: web::Bytes,
+    db_pool: &SqlitePool,
+    format: Option<BatchFormat>,
+) -> HttpResponse {
+    let decimation = app_data_or_default::<Decimation>(req);
+    let backlog = app_data_or_default::<Backlog>(req);
+    let quota = app_data_or_default::<WriteQuota>(req);
+    let options = app_data_or_default::<IngestOptions>(req);
+    let immediate = app_data_or_default::<ImmediatePersist>(req);
+    if let Some(resp) = authorize(req, db_pool, &id).await {
+        return resp;
+    }
+    if !valid_schema(&id) {
+        return invalid_project_id();
+    }
+    if let Some(resp) = reject_if_read_only(req) {
+        return resp;
+    }
+    let best_effort = match query.get("mode").map(|m| m.as_str()) {
+        None | Some("atomic") => false,
+        Some("best_effort") if format.is_some() => true,
+        Some("best_effort") => return HttpResponse::BadRequest().body("mode=best_effort applies to batches only"),
+        Some(_) => return HttpResponse::BadRequest().body("mode must be atomic or best_effort"),
+    };
+    // A best-effort batch keeps its invalid records to report them, while an
+    // atomic one is rejected as a whole by the first of them.
+    let parsed = match format {
+        Some(BatchFormat::Json) => Some(parse_batch(&body, &options, Utc::now())),
+        Some(BatchFormat::Csv) => {
+            let time_column = query.get("time_column").map(|c| c.as_str()).unwrap_or("time");
+            Some(parse_csv_batch(&body, time_column, &options, Utc::now()))
+        }
+        None => None,
+    };
+    let mut batch = if let Some(parsed) = parsed {
+        match parsed {
+            Ok(rows) => {
+                if let Some(Err(e)) = rows.iter().find(|row| row.is_err() && !best_effort) {
+                    return HttpResponse::BadRequest().body(e.clone());
+                }
+                Some(rows)
+            }
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        }
+    } else {
+        None
+    };
+    let mut data = String::from_utf8(body.to_vec()).unwrap_or_default();
+    // Field aliases apply before the payloads are checked, so a renamed
+    // field counts as its schema name.
+    if let Some(aliases) = options.field_aliases.get(&id) {
+        let alias = |payload: String| alias_payload_fields(payload, aliases, options.unknown_fields);
+        match &mut batch {
+            Some(rows) => for row in rows.iter_mut() {
+                if let Ok((_, payload)) = row {
+                    match alias(std::mem::take(payload)) {
+                        Ok(aliased) => *payload = aliased,
+                        Err(e) if !best_effort => return HttpResponse::BadRequest().body(e),
+                        Err(e) => *row = Err(e),
+                    }
+                }
+            },
+            None => match alias(data) {
+                Ok(aliased) => data = aliased,
+                Err(e) => return HttpResponse::BadRequest().body(e),
+            },
+        }
+    }
+    if batch.is_none() && data.trim().is_empty() && !options.allow_empty_records {
+        return HttpResponse::BadRequest().body("Payload has no values");
+    }
+    let seq = match query.get("seq").map(|s| s.parse::<i64>()).transpose() {
+        Ok(seq) => seq,
+        Err(_) => return HttpResponse::BadRequest().body("seq must be an integer"),
+    };
+    let schema = query.get("schema").map(|s| s.as_str()).unwrap_or(DEFAULT_SCHEMA);
+    if !valid_schema(schema) {
+        return HttpResponse::BadRequest().body("schema must be a plain name of letters, digits, '_', '-' or '.'");
+    }
+    // The producer of the rows, from `X-Source` or else `?source=`.
+    let source = match req.headers().get("X-Source").map(|v| v.to_str().unwrap_or_default()).or(query.get("source").map(|s| s.as_str())) {
+        Some(source) if valid_schema(source.trim()) => Some(source.trim().to_string()),
+        Some(_) => return HttpResponse::BadRequest().body("source must be a plain name of letters, digits, '_', '-' or '.'"),
+        None => None,
+    };
+    let expected_fields = match req.headers().get("X-Field-Count") {
+        Some(v) => match v.to_str().ok().and_then(|v| v.trim().parse::<usize>().ok()) {
+            Some(n) => Some(n),
+            None => return HttpResponse::BadRequest().body("X-Field-Count must be a non-negative integer"),
+        },
+        None => None,
+    };
+    if let Some(expected) = expected_fields {
+        let mismatch = |payload: &str| {
+            let found = field_count(payload);
+            (found != expected).then(|| format!("expected {} values per record, found {}", expected, found))
+        };
+        match &mut batch {
+            Some(rows) => for row in rows.iter_mut() {
+                if let Some(e) = row.as_ref().ok().and_then(|(_, payload)| mismatch(payload)) {
+                    if !best_effort {
+                        return HttpResponse::BadRequest().body(e);
+                    }
+                    *row = Err(e);
+                }
+            },
+            None => if let Some(e) = mismatch(&data) {
+                return HttpResponse::BadRequest().body(e);
+            },
+        }
+    }
+
+    let idempotency_key = match req.headers().get("Idempotency-Key").map(|v| v.to_str()) {
+        Some(Ok(key)) if batch.is_some() && !key.trim().is_empty() => Some(key.trim().to_string()),
+        Some(_) if batch.is_some() => return HttpResponse::BadRequest().body("Idempotency-Key must be a non-empty visible ASCII string"),
+        _ => None,
+    };
+    if let Some(key) = &idempotency_key {
+        match recorded_response(db_pool, &id, key).await {
+            Ok(Some(resp)) => return resp,
+            Ok(None) => {}
+            Err(e) => {
+                log::error!("{}", e);
+                return HttpResponse::InternalServerError().body("Failed to read the idempotency key");
+            }
+        }
+    }
+
+    let tier = match backlog.tier(db_pool).await {
+        Ok(tier) => tier,
+        Err(e) => {
+            log::error!("{}", e);
+            return HttpResponse::InternalServerError().body("Failed to read the WAL backlog")
+        }
+    };
+    let mut created = match tier {
+        BacklogTier::Normal => HttpResponse::Created(),
+        BacklogTier::Soft => {
+            let mut builder = HttpResponse::Accepted();
+            builder.insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()));
+            builder
+        },
+        BacklogTier::Hard => {
+            return HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()))
+                .body("WAL backlog is over the hard limit")
+        },
+    };
+
+    match admits_project(db_pool, &id, &options).await {
+        Ok(true) => {}
+        Ok(false) => return project_limit_reached(),
+        Err(e) => {
+            log::error!("{}", e);
+            return HttpResponse::InternalServerError().body("Failed to check the project limit")
+        }
+    }
+
+    // The sequence number of a write is only taken as seen once the write
+    // is answered with success, so a refused write retried with the same
+    // number leaves no gap, and its retry no duplicate.
+    let Some(batch) = batch else {
+        if !decimation.accept(&id) {
+            note_sequence(db_pool, &id, seq).await;
+            return created.json(serde_json::json!({ "accepted": 0, "dropped": 1 }));
+        }
+        let bytes = data.len() as u64;
+        if let Some(resp) = consume_quota(db_pool, &quota, &id, 1, bytes).await {
+            return resp;
+        }
+        let data = order_payload_fields(data, options.field_order);
+        let saved = save_to_db(db_pool, id.clone(), schema, source.as_deref(), data, &options).await;
+        if !matches!(saved, Ok(Some(()))) {
+            refund_quota(db_pool, &quota, &id, 1, bytes).await;
+        }
+        return match saved {
+            Ok(Some(())) => {
+                note_sequence(db_pool, &id, seq).await;
+                request_persist(db_pool, &immediate, &id).await;
+                created.json(serde_json::json!({ "accepted": 1, "dropped": 0 }))
+            }
+            Ok(None) => project_limit_reached(),
+            Err(e) => {
+                log::error!("{}", e);
+                HttpResponse::InternalServerError().body("Failed to persist a write request")
+            }
+        };
+    };
+
+    let mut kept = Vec::new();
+    let outcomes: Vec<Result<bool, String>> = batch.into_iter().map(|row| row.map(|row| {
+        let accept = decimation.accept(&id);
+        if accept {
+            kept.push(row);
+        }
+        accept
+    })).collect();
+    let respond = |ids: &[i64]| batch_response(&outcomes, ids, best_effort);
+    if kept.is_empty() && idempotency_key.is_none() {
+        note_sequence(db_pool, &id, seq).await;
+        return created.json(respond(&[]));
+    }
+    let (rows, bytes) = (kept.len() as u64, kept.iter().map(|(_, payload)| payload.len() as u64).sum());
+    if rows > 0 {
+        if let Some(resp) = consume_quota(db_pool, &quota, &id, rows, bytes).await {
+            return resp;
+        }
+    }
+    let status = match tier {
+        BacklogTier::Soft => actix_web::http::StatusCode::ACCEPTED,
+        _ => actix_web::http::StatusCode::CREATED,
+    };
+    let idempotency = idempotency_key.as_deref().map(|key| Idempotency { key, status: status.as_u16(), respond: &respond });
+    let saved = save_batch_to_db(db_pool, &id, schema, source.as_deref(), kept, &options, idempotency).await;
+    if rows > 0 && !matches!(saved, Ok(Some(_))) {
+        refund_quota(db_pool, &quota, &id, rows, bytes).await;
+    }
+    let ids = match saved {
+        Ok(Some(ids)) => {
+            note_sequence(db_pool, &id, seq).await;
+            request_persist(db_pool, &immediate, &id).await;
+            ids
+        }
+        Ok(None) => return project_limit_reached(),
+        // A retry that raced this one recorded the key first.
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            if let Ok(Some(recorded)) = recorded_response(db_pool, &id, idempotency_key.as_deref().unwrap_or_default()).await {
+                return recorded;
+            }
+            log::error!("{}", e);
+            return HttpResponse::InternalServerError().body("Failed to persist a write request");
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            return HttpResponse::InternalServerError().body("Failed to persist a write request");
+        }
+    };
+    created.json(respond(&ids))
+}
+
+/// Tracks the sequence number of a successful write, logging a gap before
+/// it. A failure only loses the gap, so it is logged.
+async fn note_sequence(db_pool: &SqlitePool, id: &str, seq: Option<i64>) {
+    let Some(seq) = seq else {
+        return;
+    };
+    match track_sequence(db_pool, id, seq).await {
+        Ok(Some((from, to))) => log::warn!("sequence gap for project {}: {}..={}", id, from, to),
+        Ok(None) => {}
+        Err(e) => log::error!("{}", e),
+    }
+}
+
+/// Asks the persister to persist the project without waiting for its poll
+/// interval. A failure only delays the persist, so it is logged.
+async fn request_persist(db_pool: &SqlitePool, immediate: &ImmediatePersist, project_id: &str) {
+    if !immediate.0.contains(project_id) {
+        return;
+    }
+    let requested = sqlx::query(
+        "INSERT INTO persist_requests (project_id, requested_at) VALUES (?1, ?2)
+         ON CONFLICT (project_id) DO UPDATE SET requested_at = excluded.requested_at"
+    )
+        .bind(project_id)
+        .bind(Utc::now().to_rfc3339())
+        .execute(db_pool).await;
+    if let Err(e) = requested {
+        log::error!("failed to request a persist of project {}: {}", project_id, e);
+    }
+}
+
+fn project_limit_reached() -> HttpResponse {
+    HttpResponse::Forbidden().body("the maximum number of projects is reached")
+}
+
+/// Counts a write against the project's quota, or returns the response
+/// refusing it.
+async fn consume_quota(db_pool: &SqlitePool, quota: &WriteQuota, id: &str, rows: u64, bytes: u64) -> Option<HttpResponse> {
+    let now = Utc::now();
+    match quota.consume(db_pool, id, rows, bytes, now).await {
+        Ok(true) => None,
+        Ok(false) => Some(HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", quota.remaining_secs(now).to_string()))
+            .body("write quota exhausted for this project")),
+        Err(e) => {
+            log::error!("{}", e);
+            Some(HttpResponse::InternalServerError().body("Failed to check the write quota"))
+        }
+    }
+}
+
+/// Takes back the quota charged for a write that was not stored. A failure
+/// only leaves the project charged for the rows, so it is logged.
+async fn refund_quota(db_pool: &SqlitePool, quota: &WriteQuota, id: &str, rows: u64, bytes: u64) {
+    if let Err(e) = quota.refund(db_pool, id, rows, bytes, Utc::now()).await {
+        log::error!("failed to refund the write quota of project {}: {}", id, e);
+    }
+}
+
+/// Refuses a request whose signed URL is not a Parquet export, does not match
+/// its path or has expired.
+fn verify_signed_export(req: &HttpRequest, query: &HashMap<String, String>) -> Option<HttpResponse> {
+    let signer = app_data_or_default::<ExportSigner>(req);
+    let expires = query.get("expires").and_then(|e| e.parse::<i64>().ok());
+    let valid = match (query.get("format").map(|f| f.as_str()), expires, query.get("signature")) {
+        (Some("parquet"), Some(expires), Some(signature)) => signer.verify(req.path(), expires, signature, Utc::now()),
+        _ => false,
+    };
+    (!valid).then(|| HttpResponse::Forbidden().body("the signed URL is invalid or has expired"))
+}
+
+/// Signs a URL exporting the project's data as Parquet that needs no API key
+/// until it expires. Query parameters such as the range can be added to it.
+async fn post_export_sign(req: HttpRequest, path: web::Path<String>, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    let id = path.into_inner();
+    if let Some(resp) = authorize(&req, &db_pool, &id).await {
+        return resp;
+    }
+    if !valid_schema(&id) {
+        return invalid_project_id();
+    }
+    let signer = app_data_or_default::<ExportSigner>(&req);
+    let export = format!("/project/{}/data", id);
+    let expires = Utc::now() + chrono::Duration::from_std(signer.ttl).unwrap_or(chrono::Duration::max_value());
+    match signer.sign(&export, expires.timestamp()) {
+        Some(signature) => HttpResponse::Ok().json(serde_json::json!({
+            "url": format!("{}?format=parquet&expires={}&signature={}", export, expires.timestamp(), signature),
+            "expires_at": expires.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        })),
+        None => HttpResponse::NotFound().body("EXPORT_SIGNING_KEY is not set"),
+    }
+}
+
+async fn post_api_key(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db_pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    if let Some(resp) = authorize_admin(&req) {
+        return resp;
+    }
+    if let Some(resp) = reject_if_read_only(&req) {
+        return resp;
+    }
+    let id = path.into_inner();
+    if !valid_schema(&id) {
+        return invalid_project_id();
+    }
+    match create_api_key(&db_pool, &id).await {
+        Ok((id, key)) => HttpResponse::Created().json(serde_json::json!({ "id": id, "key": key })),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to create an API key")
+        }
+    }
+}
+
+async fn delete_api_key(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    db_pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    if let Some(resp) = authorize_admin(&req) {
+        return resp;
+    }
+    if let Some(resp) = reject_if_read_only(&req) {
+        return resp;
+    }
+    match revoke_api_key(&db_pool, path.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("no active API key with this id"),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to revoke an API key")
+        }
+    }
+}
+
+/// The archive directory of a project whose data the request may read, or
+/// the response refusing it.
+async fn fragments_dir(req: &HttpRequest, db_pool: &SqlitePool, id: &str) -> Result<PathBuf, HttpResponse> {
+    if let Some(resp) = authorize(req, db_pool, id).await {
+        return Err(resp);
+    }
+    if !valid_schema(id) {
+        return Err(invalid_project_id());
+    }
+    match req.app_data::<web::Data<ParquetArchive>>().and_then(|a| a.root.clone()) {
+        Some(root) => Ok(root.join(id)),
+        None => Err(HttpResponse::NotFound().body("PARQUET_ROOT is not set")),
+    }
+}
+
+/// Lists the Parquet files archived for the project.
+async fn get_fragments(req: HttpRequest, path: web::Path<String>, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    let dir = match fragments_dir(&req, &db_pool, &path.into_inner()).await {
+        Ok(dir) => dir,
+        Err(resp) => return resp,
+    };
+    let timeout = app_data_or_default::<QueryTimeout>(&req).0;
+    match run_blocking(timeout, move || describe_fragments(&dir)).await {
+        Ok(fragments) => HttpResponse::Ok().json(fragments),
+        Err(e @ BlockingError::TimedOut(_)) => query_timed_out(&e),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to list the fragments")
+        }
+    }
+}
+
+/// Downloads one of the files `get_fragments` lists; any other name, such
+/// as one reaching outside the project's directory, is not found.
+async fn get_fragment(req: HttpRequest, path: web::Path<(String, String)>, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    let (id, name) = path.into_inner();
+    let dir = match fragments_dir(&req, &db_pool, &id).await {
+        Ok(dir) => dir,
+        Err(resp) => return resp,
+    };
+    if name.split('/').any(|c| c.is_empty() || c.starts_with('.')) {
+        return HttpResponse::BadRequest().body("invalid fragment name");
+    }
+    let read = run_blocking(None, move || {
+        if !archive_fragments(&dir)?.contains(&name) {
+            return Ok(None);
+        }
+        std::fs::read(dir.join(&name)).map(|bytes| Some((name, bytes)))
+    }).await;
+    match read {
+        Ok(Some((name, bytes))) => HttpResponse::Ok()
+            .content_type(PARQUET_CONTENT_TYPE)
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", name.rsplit('/').next().unwrap_or_default())))
+            .body(bytes),
+        Ok(None) => HttpResponse::NotFound().body("no such fragment"),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to read the fragment")
+        }
+    }
+}
+
+/// How the persister would read a stored WAL row: its fields with their
+/// Parquet column types, or the error that would dead-letter it. Project
+/// preprocessing is configured on the persister and is not applied.
+fn preview_wal_row(row: &SqliteRow, options: &IngestOptions) -> Result<serde_json::Value, sqlx::Error> {
+    let time: String = row.try_get("time")?;
+    let mut preview = serde_json::json!({
+        "rowid": row.try_get::<i64, _>("rowid")?,
+        "project_id": row.try_get::<String, _>("project_id")?,
+        "schema": row.try_get::<Option<String>, _>("schema")?,
+        "time": time,
+        "payload": null,
+        "fields": [],
+        "error": null,
+    });
+    if DateTime::parse_from_rfc3339(&time).is_err() {
+        preview["error"] = serde_json::json!(format!("invalid time '{}'", time));
+        return Ok(preview);
+    }
+    let payload = match read_payload(row) {
+        Ok(payload) => payload,
+        Err(e) => {
+            preview["error"] = serde_json::json!(format!("failed to decompress the payload: {}", e));
+            return Ok(preview);
+        }
+    };
+    preview["payload"] = serde_json::json!(payload);
+    if payload.trim().is_empty() && !options.allow_empty_records {
+        preview["error"] = serde_json::json!("payload has no values");
+        return Ok(preview);
+    }
+    match common::parse_fields(&payload, options.field_order) {
+        Ok((values, names)) => {
+            preview["fields"] = values.iter().enumerate().map(|(i, value)| serde_json::json!({
+                "name": names.as_ref().and_then(|n| n.get(i)).cloned().unwrap_or_else(|| format!("f{}", i)),
+                "type": "DOUBLE",
+                "value": value,
+            })).collect();
+        }
+        Err(e) => preview["error"] = serde_json::json!(e.to_string()),
+    }
+    Ok(preview)
+}
+
+async fn get_wal_preview(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    db_pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    if let Some(resp) = authorize_admin(&req) {
+        return resp;
+    }
+    let row = sqlx::query("SELECT rowid, * FROM wal WHERE rowid = ?1").bind(path.into_inner()).fetch_optional(db_pool.get_ref()).await;
+    let options = app_data_or_default::<IngestOptions>(&req);
+    match row.and_then(|row| row.map(|row| preview_wal_row(&row, &options)).transpose()) {
+        Ok(Some(preview)) => HttpResponse::Ok().json(preview),
+        Ok(None) => HttpResponse::NotFound().body("no WAL row with this rowid"),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to read the WAL row")
+        }
+    }
+}
+
+/// Records the project as paused, or no longer paused; the persister leaves
+/// the WAL rows of paused projects in place.
+async fn set_paused(db_pool: &SqlitePool, project_id: &str, paused: bool) -> Result<(), sqlx::Error> {
+    if paused {
+        sqlx::query("INSERT INTO paused_projects (project_id, paused_at) VALUES (?1, ?2) ON CONFLICT (project_id) DO NOTHING")
+            .bind(project_id)
+            .bind(Utc::now().to_rfc3339())
+            .execute(db_pool).await?;
+    } else {
+        sqlx::query("DELETE FROM paused_projects WHERE project_id = ?1").bind(project_id).execute(db_pool).await?;
+    }
+    Ok(())
+}
+
+async fn post_pause_project(req: HttpRequest, path: web::Path<String>, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    pause_project(req, path.into_inner(), db_pool, true).await
+}
+
+async fn post_resume_project(req: HttpRequest, path: web::Path<String>, db_pool: web::Data<SqlitePool>) -> impl Responder {
+    pause_project(req, path.into_inner(), db_pool, false).await
+}
+
+async fn pause_project(req: HttpRequest, id: String, db_pool: web::Data<SqlitePool>, paused: bool) -> HttpResponse {
+    if let Some(resp) = authorize_admin(&req) {
+        return resp;
+    }
+    if let Some(resp) = reject_if_read_only(&req) {
+        return resp;
+    }
+    if !valid_schema(&id) {
+        return invalid_project_id();
+    }
+    match set_paused(&db_pool, &id, paused).await {
+        Ok(()) => {
+            log::warn!("persistence {} for project {}", if paused { "paused" } else { "resumed" }, id);
+            HttpResponse::Ok().json(serde_json::json!({ "project_id": id, "paused": paused }))
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("Failed to update the paused projects")
+        }
+    }
+}
+
+/// Sets the read-only flag from a `true` or `false` body.
+async fn put_read_only(
+    req: HttpRequest,
+    body: web::Bytes,
+    read_only: web::Data<ReadOnly>,
+) -> impl Responder {
+    if let Some(resp) = authorize_admin(&req) {
+        return resp;
+    }
+    let enabled = match std::str::from_utf8(&body).map(|b| b.trim()) {
+        Ok("true") => true,
+        Ok("false") => false,
+        _ => return HttpResponse::BadRequest().body("expected true or false"),
+    };
+    read_only.0.store(enabled, Ordering::Relaxed);
+    log::warn!("read-only mode {}", if enabled { "enabled" } else { "disabled" });
+    HttpResponse::Ok().json(serde_json::json!({ "read_only": enabled }))
+}
+
+/// Parses `RESPONSE_HEADERS`, a `|`-separated list of `Name: Value` pairs.
+fn parse_response_headers(s: &str) -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    let mut headers = vec![];
+    for pair in s.split('|').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once(':')
+            .ok_or_else(|| format!("missing ':' in response header '{}'", pair))?;
+        let name = HeaderName::try_from(name.trim())
+            .map_err(|e| format!("invalid response header name '{}': {}", name.trim(), e))?;
+        let value = HeaderValue::try_from(value.trim())
+            .map_err(|e| format!("invalid value for response header '{}': {}", name, e))?;
+        headers.push((name, value));
+    }
+    Ok(headers)
+}
+
+fn response_headers() -> Result<Vec<(HeaderName, HeaderValue)>, String> {
+    match std::env::var("RESPONSE_HEADERS") {
+        Ok(s) => parse_response_headers(&s).map_err(|e| format!("RESPONSE_HEADERS: {}", e)),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+/// Parses `DECIMATION`, a comma-separated list of `project_id=N` pairs.
+fn parse_decimation(s: &str) -> Result<HashMap<String, u64>, String> {
+    let mut factors = HashMap::new();
+    for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let (id, n) = pair.split_once('=')
+            .ok_or_else(|| format!("missing '=' in decimation setting '{}'", pair))?;
+        let n = match n.trim().parse::<u64>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(format!("invalid decimation factor for project '{}': {}", id.trim(), n.trim())),
+        };
+        factors.insert(id.trim().to_string(), n);
+    }
+    Ok(factors)
+}
+
+/// Parses `IMMEDIATE_PERSIST`, a comma-separated list of project ids.
+fn immediate_persist() -> ImmediatePersist {
+    let ids = std::env::var("IMMEDIATE_PERSIST").unwrap_or_default();
+    ImmediatePersist(ids.split(',').map(|id| id.trim()).filter(|id| !id.is_empty()).map(String::from).collect())
+}
+
+fn decimation() -> Result<Decimation, String> {
+    match std::env::var("DECIMATION") {
+        Ok(s) => parse_decimation(&s).map(Decimation::new).map_err(|e| format!("DECIMATION: {}", e)),
+        Err(_) => Ok(Decimation::default()),
+    }
+}
+
+/// Parses a comma-separated list of `project_id=N` quotas.
+fn parse_quotas(s: &str) -> Result<HashMap<String, u64>, String> {
+    let mut quotas = HashMap::new();
+    for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let (id, n) = pair.split_once('=')
+            .ok_or_else(|| format!("missing '=' in quota setting '{}'", pair))?;
+        let n = n.trim().parse::<u64>()
+            .map_err(|_| format!("invalid quota for project '{}': {}", id.trim(), n.trim()))?;
+        quotas.insert(id.trim().to_string(), n);
+    }
+    Ok(quotas)
+}
+
+fn response_limit() -> Result<ResponseLimit, String> {
+    let max_bytes = match parse_limit("MAX_RESPONSE_BYTES")? {
+        Some(max) if max <= 0 => return Err(format!("invalid MAX_RESPONSE_BYTES '{}': must be positive", max)),
+        max => max.map(|m| m as usize),
+    };
+    let truncate = match std::env::var("RESPONSE_LIMIT_POLICY") {
+        Ok(s) => match s.trim() {
+            "error" => false,
+            "truncate" => true,
+            _ => return Err(format!("invalid RESPONSE_LIMIT_POLICY '{}': expected error or truncate", s)),
+        },
+        Err(_) => false,
+    };
+    Ok(ResponseLimit { max_bytes, truncate })
+}
+
+fn query_timeout() -> Result<QueryTimeout, String> {
+    match parse_limit("DUCKDB_QUERY_TIMEOUT_SECS")? {
+        Some(secs) if secs <= 0 => Err(format!("invalid DUCKDB_QUERY_TIMEOUT_SECS '{}': must be positive", secs)),
+        secs => Ok(QueryTimeout(secs.map(|s| Duration::from_secs(s as u64)))),
+    }
+}
+
+/// `PARQUET_ROOT`, defaulting to the directory of a file WAL.
+fn parquet_archive() -> Result<ParquetArchive, String> {
+    if let Ok(root) = std::env::var("PARQUET_ROOT") {
+        if !root.trim().is_empty() {
+            return Ok(ParquetArchive { root: Some(PathBuf::from(root.trim())) });
+        }
+    }
+    match wal_location()? {
+        WalLocation::File(path) => Ok(ParquetArchive { root: path.parent().map(|p| p.to_path_buf()) }),
+        WalLocation::Memory => Ok(ParquetArchive::default()),
+    }
+}
+
+fn persister_health() -> Result<PersisterHealth, String> {
+    match parse_limit("PERSISTER_STALE_SECS")? {
+        Some(secs) if secs <= 0 => Err(format!("invalid PERSISTER_STALE_SECS '{}': must be positive", secs)),
+        Some(secs) => Ok(PersisterHealth { stale_after: Duration::from_secs(secs as u64) }),
+        None => Ok(PersisterHealth::default()),
+    }
+}
+
+/// Address the HTTP server binds to.
+fn server_addr() -> String {
+    std::env::var("ZETA_BIND_ADDR").unwrap_or_else(|_| "127.0.0.1:8000".to_string())
+}
+
+fn parse_server_addr(addr: &str) -> Result<std::net::SocketAddr, String> {
+    addr.trim().parse().map_err(|e| format!("invalid ZETA_BIND_ADDR '{}': {}", addr, e))
+}
+
+/// Connections each worker accepts before it stops accepting new ones; further
+/// clients wait in the listen backlog until a connection closes.
+fn max_connections() -> Result<Option<usize>, String> {
+    match parse_limit("MAX_CONNECTIONS")? {
+        Some(max) if max <= 0 => Err(format!("invalid MAX_CONNECTIONS '{}': must be positive", max)),
+        max => Ok(max.map(|m| m as usize)),
+    }
+}
+
+fn write_quota() -> Result<WriteQuota, String> {
+    let quotas = |name: &str| match std::env::var(name) {
+        Ok(s) => parse_quotas(&s).map_err(|e| format!("{}: {}", name, e)),
+        Err(_) => Ok(HashMap::new()),
+    };
+    let window = match parse_limit("WRITE_QUOTA_WINDOW_SECS")? {
+        Some(w) if w <= 0 => return Err(format!("invalid WRITE_QUOTA_WINDOW_SECS '{}': must be positive", w)),
+        Some(w) => w as u64,
+        None => DEFAULT_QUOTA_WINDOW_SECS,
+    };
+    Ok(WriteQuota {
+        rows: quotas("WRITE_QUOTA_ROWS")?,
+        bytes: quotas("WRITE_QUOTA_BYTES")?,
+        window: Duration::from_secs(window),
+    })
+}
+
+fn parse_limit(name: &str) -> Result<Option<i64>, String> {
+    match std::env::var(name) {
+        Ok(s) => s.trim().parse::<i64>().map(Some)
+            .map_err(|e| format!("invalid {} '{}': {}", name, s, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn backlog() -> Result<Backlog, String> {
+    let soft_limit = parse_limit("BACKLOG_SOFT_LIMIT")?;
+    let hard_limit = parse_limit("BACKLOG_HARD_LIMIT")?;
+    let ttl = parse_limit("BACKLOG_REFRESH_SECS")?.unwrap_or(5);
+    for (name, value) in [("BACKLOG_SOFT_LIMIT", soft_limit), ("BACKLOG_HARD_LIMIT", hard_limit), ("BACKLOG_REFRESH_SECS", Some(ttl))] {
+        if let Some(v) = value.filter(|v| *v < 0) {
+            return Err(format!("invalid {} '{}': must not be negative", name, v));
+        }
+    }
+    if let (Some(soft), Some(hard)) = (soft_limit, hard_limit) {
+        if soft > hard {
+            return Err(format!("invalid BACKLOG_SOFT_LIMIT '{}': exceeds BACKLOG_HARD_LIMIT '{}'", soft, hard));
+        }
+    }
+    Ok(Backlog::new(soft_limit, hard_limit, Duration::from_secs(ttl as u64)))
+}
+
+fn parse_flag(name: &str) -> Result<bool, String> {
+    match std::env::var(name) {
+        Ok(s) => match s.trim() {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err(format!("invalid {} '{}': expected true or false", name, s)),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
+fn ingest_options() -> Result<IngestOptions, String> {
+    let threshold = parse_limit("COMPRESS_THRESHOLD_BYTES")?;
+    if let Some(t) = threshold.filter(|t| *t < 0) {
+        return Err(format!("invalid COMPRESS_THRESHOLD_BYTES '{}': must not be negative", t));
+    }
+    let skew = |name: &str| match parse_limit(name)? {
+        Some(secs) if secs < 0 => Err(format!("invalid {} '{}': must not be negative", name, secs)),
+        secs => Ok(secs.map(|s| Duration::from_secs(s as u64))),
+    };
+    let max_projects = parse_limit("MAX_PROJECTS")?;
+    if let Some(max) = max_projects.filter(|m| *m < 0) {
+        return Err(format!("invalid MAX_PROJECTS '{}': must not be negative", max));
+    }
+    Ok(IngestOptions {
+        compress_threshold: threshold.map(|t| t as usize),
+        allow_empty_records: parse_flag("ALLOW_EMPTY_RECORDS")?,
+        max_future_skew: skew("MAX_FUTURE_SKEW_SECS")?,
+        max_past_skew: skew("MAX_PAST_SKEW_SECS")?,
+        max_projects: max_projects.map(|m| m as usize),
+        field_order: match std::env::var("FIELD_ORDER") {
+            Ok(s) => FieldOrder::parse(&s)?,
+            Err(_) => FieldOrder::default(),
+        },
+        field_aliases: match std::env::var("FIELD_ALIASES") {
+            Ok(s) if !s.trim().is_empty() => parse_field_aliases(&s)?,
+            _ => HashMap::new(),
+        },
+        unknown_fields: match std::env::var("UNKNOWN_FIELDS").as_deref().map(str::trim) {
+            Ok("keep") | Err(_) => UnknownFields::Keep,
+            Ok("reject") => UnknownFields::Reject,
+            Ok("ignore") => UnknownFields::Ignore,
+            Ok(other) => return Err(format!("invalid UNKNOWN_FIELDS '{}': expected keep, reject or ignore", other)),
+        },
+    })
+}
+
+/// Parses `FIELD_ALIASES`, a JSON object mapping project ids to their
+/// aliases, e.g. `{"p1": {"temperature": "temp"}}`.
+fn parse_field_aliases(s: &str) -> Result<HashMap<String, HashMap<String, String>>, String> {
+    let aliases: HashMap<String, HashMap<String, String>> = serde_json::from_str(s)
+        .map_err(|e| format!("invalid FIELD_ALIASES: {}", e))?;
+    for (project_id, aliases) in &aliases {
+        if let Some((from, _)) = aliases.iter().find(|(from, to)| from.is_empty() || to.is_empty()) {
+            return Err(format!("invalid FIELD_ALIASES: empty field name in the aliases of '{}' ({:?})", project_id, from));
+        }
+    }
+    Ok(aliases)
+}
+
+fn export_signer() -> Result<ExportSigner, String> {
+    let key = std::env::var("EXPORT_SIGNING_KEY").ok().filter(|k| !k.trim().is_empty()).map(String::into_bytes);
+    let ttl = match parse_limit("EXPORT_URL_TTL_SECS")? {
+        Some(secs) if secs <= 0 => return Err(format!("invalid EXPORT_URL_TTL_SECS '{}': must be positive", secs)),
+        secs => secs.map(|s| Duration::from_secs(s as u64)).unwrap_or(DEFAULT_EXPORT_URL_TTL),
+    };
+    Ok(ExportSigner { key, ttl })
+}
+
+fn api_keys() -> Result<ApiKeys, String> {
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.trim().is_empty());
+    let required = parse_flag("REQUIRE_API_KEYS")?;
+    if required && admin_token.is_none() {
+        return Err("REQUIRE_API_KEYS: ADMIN_TOKEN must be set to manage keys".to_string());
+    }
+    Ok(ApiKeys { admin_token, required })
+}
+
+/// Name of the header carrying the request id, `x-request-id` by default.
+#[derive(Clone)]
+struct RequestIdHeader(HeaderName);
+
+impl Default for RequestIdHeader {
+    fn default() -> Self {
+        RequestIdHeader(HeaderName::from_static("x-request-id"))
+    }
+}
+
+fn request_id_header() -> Result<RequestIdHeader, String> {
+    match std::env::var("REQUEST_ID_HEADER") {
+        Ok(s) => HeaderName::try_from(s.trim()).map(RequestIdHeader)
+            .map_err(|e| format!("invalid REQUEST_ID_HEADER '{}': {}", s, e)),
+        Err(_) => Ok(RequestIdHeader::default()),
+    }
+}
+
+fn generate_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("{:x}-{:x}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Reuses the request id set by an upstream gateway, or generates a new one,
+/// and echoes it back on the response.
+async fn correlate_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let header = req.app_data::<web::Data<RequestIdHeader>>()
+        .map(|h| h.0.clone())
+        .unwrap_or_else(|| RequestIdHeader::default().0);
+    let id = req.headers().get(&header)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .unwrap_or_else(generate_request_id);
+
+    let mut res = next.call(req).await?;
+    if let Ok(value) = HeaderValue::try_from(id) {
+        res.headers_mut().insert(header, value);
+    }
+    Ok(res)
+}
+
+/// Loads every setting from the environment, returning a summary of the
+/// effective values or an error naming the first invalid one.
+fn validate_config() -> Result<Vec<String>, String> {
+    let headers = response_headers()?;
+    let decimation = decimation()?;
+    let backlog = backlog()?;
+    let request_id_header = request_id_header()?;
+    let options = ingest_options()?;
+    let keys = api_keys()?;
+    let signer = export_signer()?;
+    let quota = write_quota()?;
+    let health = persister_health()?;
+    let read_only = parse_flag("READ_ONLY")?;
+    let connections = max_connections()?;
+    let response_limit = response_limit()?;
+    let archive = parquet_archive()?;
+    let timeout = query_timeout()?;
+    let immediate = immediate_persist();
+    let addr = parse_server_addr(&server_addr())?;
+    let location = match wal_location()? {
+        WalLocation::File(path) => path.display().to_string(),
+        WalLocation::Memory => "in memory".to_string(),
+    };
+
+    let limit = |l: Option<i64>| l.map(|v| v.to_string()).unwrap_or_else(|| "unset".to_string());
+    Ok(vec![
+        format!("RESPONSE_HEADERS: {} header(s)", headers.len()),
+        format!("DECIMATION: {} project(s)", decimation.factors.len()),
+        format!("BACKLOG_SOFT_LIMIT: {}", limit(backlog.soft_limit)),
+        format!("BACKLOG_HARD_LIMIT: {}", limit(backlog.hard_limit)),
+        format!("BACKLOG_REFRESH_SECS: {}", backlog.ttl.as_secs()),
+        format!("REQUEST_ID_HEADER: {}", request_id_header.0),
+        format!("COMPRESS_THRESHOLD_BYTES: {}", limit(options.compress_threshold.map(|t| t as i64))),
+        format!("ALLOW_EMPTY_RECORDS: {}", options.allow_empty_records),
+        format!("FIELD_ALIASES: {} project(s)", options.field_aliases.len()),
+        format!("UNKNOWN_FIELDS: {:?}", options.unknown_fields),
+        format!("MAX_FUTURE_SKEW_SECS: {}", limit(options.max_future_skew.map(|s| s.as_secs() as i64))),
+        format!("MAX_PAST_SKEW_SECS: {}", limit(options.max_past_skew.map(|s| s.as_secs() as i64))),
+        format!("ADMIN_TOKEN: {}", if keys.admin_token.is_some() { "set" } else { "unset" }),
+        format!("REQUIRE_API_KEYS: {}", keys.required),
+        format!("EXPORT_SIGNING_KEY: {}", if signer.key.is_some() { "set" } else { "unset" }),
+        format!("EXPORT_URL_TTL_SECS: {}", signer.ttl.as_secs()),
+        format!("WRITE_QUOTA_ROWS: {} project(s)", quota.rows.len()),
+        format!("WRITE_QUOTA_BYTES: {} project(s)", quota.bytes.len()),
+        format!("WRITE_QUOTA_WINDOW_SECS: {}", quota.window.as_secs()),
+        format!("MAX_PROJECTS: {}", limit(options.max_projects.map(|m| m as i64))),
+        format!("PERSISTER_STALE_SECS: {}", health.stale_after.as_secs()),
+        format!("READ_ONLY: {}", read_only),
+        format!("MAX_CONNECTIONS: {}", limit(connections.map(|c| c as i64))),
+        format!("MAX_RESPONSE_BYTES: {}", limit(response_limit.max_bytes.map(|m| m as i64))),
+        format!("RESPONSE_LIMIT_POLICY: {}", if response_limit.truncate { "truncate" } else { "error" }),
+        format!("ZETA_BIND_ADDR: {}", addr),
+        format!("WAL: {}", location),
+        format!("PARQUET_ROOT: {}", archive.root.map(|r| r.display().to_string()).unwrap_or_else(|| "unset".to_string())),
+        format!("DUCKDB_QUERY_TIMEOUT_SECS: {}", limit(timeout.0.map(|t| t.as_secs() as i64))),
+        format!("IMMEDIATE_PERSIST: {} project(s)", immediate.0.len()),
+    ])
+}
+
+fn config_error(e: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Configuration error: {}", e))
+}
+
+fn default_headers(headers: &[(HeaderName, HeaderValue)]) -> middleware::DefaultHeaders {
+    headers.iter().fold(middleware::DefaultHeaders::new(), |acc, h| acc.add(h.clone()))
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/project/{id}/data", web::get().to(get_project_data))
+        .route("/project/{id}/data", web::post().to(post_project_data))
+        .route("/project/{id}/data/csv", web::post().to(post_project_csv))
+        .route("/project/{id}/export/sign", web::post().to(post_export_sign))
+        .route("/project/{id}/fragments", web::get().to(get_fragments))
+        .route("/project/{id}/fragments/{name:.*}", web::get().to(get_fragment))
+        .route("/project/{id}/pause", web::post().to(post_pause_project))
+        .route("/project/{id}/resume", web::post().to(post_resume_project))
+        .route("/stats", web::get().to(get_stats))
+        .route("/meta/wal-schema", web::get().to(get_wal_schema))
+        .route("/persister-health", web::get().to(get_persister_health))
+        .route("/health", web::get().to(get_health))
+        .route("/ready", web::get().to(get_ready))
+        .route("/admin/projects/{id}/keys", web::post().to(post_api_key))
+        .route("/admin/keys/{key_id}", web::delete().to(delete_api_key))
+        .route("/wal/{rowid}/preview", web::get().to(get_wal_preview))
+        .route("/admin/read-only", web::put().to(put_read_only));
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+
+    if std::env::args().any(|a| a == "--validate-config") {
+        match validate_config() {
+            Ok(summary) => {
+                summary.iter().for_each(|line| println!("{}", line));
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Configuration error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let location = wal_location().map_err(config_error)?;
+    let pool = connect_wal(&location).await.map_err(|e| {
+        std::io::Error::other(format!("Database connection error: {}", e))
+    })?;
+
+    initialize_database(&pool).await.map_err(|e| {
+        std::io::Error::other(format!("Database initialization error: {}", e))
+    })?;
+
+    let headers = response_headers().map_err(config_error)?;
+    let decimation = web::Data::new(decimation().map_err(config_error)?);
+    let backlog = web::Data::new(backlog().map_err(config_error)?);
+    let request_id_header = web::Data::new(request_id_header().map_err(config_error)?);
+    let options = web::Data::new(ingest_options().map_err(config_error)?);
+    let keys = web::Data::new(api_keys().map_err(config_error)?);
+    let signer = web::Data::new(export_signer().map_err(config_error)?);
+    let quota = web::Data::new(write_quota().map_err(config_error)?);
+    let health = web::Data::new(persister_health().map_err(config_error)?);
+    let read_only = web::Data::new(ReadOnly(AtomicBool::new(parse_flag("READ_ONLY").map_err(config_error)?)));
+    let max_connections = max_connections().map_err(config_error)?;
+    let response_limit = web::Data::new(response_limit().map_err(config_error)?);
+    let archive = web::Data::new(parquet_archive().map_err(config_error)?);
+    let timeout = web::Data::new(query_timeout().map_err(config_error)?);
+    let immediate = web::Data::new(immediate_persist());
+    let addr = parse_server_addr(&server_addr()).map_err(config_error)?;
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(default_headers(&headers))
+            .wrap(middleware::from_fn(correlate_request))
+            .app_data(request_id_header.clone())
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(decimation.clone())
+            .app_data(backlog.clone())
+            .app_data(quota.clone())
+            .app_data(health.clone())
+            .app_data(read_only.clone())
+            .app_data(response_limit.clone())
+            .app_data(archive.clone())
+            .app_data(timeout.clone())
+            .app_data(immediate.clone())
+            .app_data(options.clone())
+            .app_data(keys.clone())
+            .app_data(signer.clone())
+            .configure(routes)
+    });
+    let server = match max_connections {
+        Some(max) => {
+            log::info!("accepting at most {} connection(s) per worker", max);
+            server.max_connections(max)
+        }
+        None => server,
+    };
+    log::info!("listening on {}", addr);
+    server.bind(addr)?
+        .run()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::ServiceFactory;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body_json, TestRequest};
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn test_app(
+        pool: SqlitePool,
+    ) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse, Error = actix_web::Error, InitError = ()>> {
+        App::new().app_data(web::Data::new(pool)).configure(routes)
+    }
+
+    async fn test_pool() -> SqlitePool {
+        let pool = connect_wal(&WalLocation::Memory).await.unwrap();
+        initialize_database(&pool).await.unwrap();
+        pool
+    }
+
+    #[test]
+    fn test_parse_response_headers() {
+        let headers = parse_response_headers("Cache-Control: no-store | X-Frame-Options: DENY").unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].0, "cache-control");
+        assert_eq!(headers[0].1, "no-store");
+        assert_eq!(headers[1].0, "x-frame-options");
+        assert_eq!(headers[1].1, "DENY");
+
+        assert!(parse_response_headers("").unwrap().is_empty());
+        assert!(parse_response_headers("X-Missing-Colon").is_err());
+        assert!(parse_response_headers("Bad Name: value").is_err());
+        assert!(parse_response_headers("X-Bad-Value: a\nb").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_custom_response_headers() {
+        let headers = parse_response_headers("X-Custom: zeta").unwrap();
+        let app = init_service(
+            test_app(test_pool().await)
+                .wrap(default_headers(&headers))
+        ).await;
+
+        let req = TestRequest::post().uri("/project/a/data").set_payload("1, 2, 3").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        assert_eq!(resp.headers().get("x-custom").unwrap(), "zeta");
+
+        let req = TestRequest::get().uri("/project/a/data?q=SELECT%20*%20FROM%20wal").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-custom").unwrap(), "zeta");
+    }
+
+    #[test]
+    fn test_parse_decimation() {
+        let factors = parse_decimation("a=3, b=10").unwrap();
+        assert_eq!(factors.get("a"), Some(&3));
+        assert_eq!(factors.get("b"), Some(&10));
+
+        assert!(parse_decimation("").unwrap().is_empty());
+        assert!(parse_decimation("a").is_err());
+        assert!(parse_decimation("a=0").is_err());
+        assert!(parse_decimation("a=x").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_decimation() {
+        let pool = test_pool().await;
+        let factors = HashMap::from([("a".to_string(), 3)]);
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(Decimation::new(factors)))
+        ).await;
+
+        let mut accepted = 0;
+        for i in 0..9 {
+            let req = TestRequest::post().uri("/project/a/data").set_payload(format!("{}", i)).to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), 201);
+            let body: serde_json::Value = read_body_json(resp).await;
+            accepted += body["accepted"].as_u64().unwrap();
+        }
+        for i in 0..9 {
+            let req = TestRequest::post().uri("/project/b/data").set_payload(format!("{}", i)).to_request();
+            call_service(&app, req).await;
+        }
+        assert_eq!(accepted, 3);
+
+        let count = |id: &'static str| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query("SELECT COUNT(*) AS n FROM wal WHERE project_id = ?1")
+                    .bind(id).fetch_one(&pool).await.unwrap().get::<i64, _>("n")
+            }
+        };
+        assert_eq!(count("a").await, 3);
+        assert_eq!(count("b").await, 9);
+    }
+
+    #[actix_web::test]
+    async fn test_backlog_tiers() {
+        let app = init_service(
+            test_app(test_pool().await)
+                .app_data(web::Data::new(Backlog::new(Some(2), Some(4), Duration::ZERO)))
+        ).await;
+
+        let post = || TestRequest::post().uri("/project/a/data").set_payload("1").to_request();
+
+        for _ in 0..2 {
+            let resp = call_service(&app, post()).await;
+            assert_eq!(resp.status(), 201);
+            assert!(resp.headers().get("retry-after").is_none());
+        }
+        for _ in 0..2 {
+            let resp = call_service(&app, post()).await;
+            assert_eq!(resp.status(), 202);
+            assert_eq!(resp.headers().get("retry-after").unwrap(), "10");
+        }
+        let resp = call_service(&app, post()).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "10");
+    }
+
+    #[actix_web::test]
+    async fn test_backlog_is_cached() {
+        let pool = test_pool().await;
+        let backlog = Backlog::new(Some(1), None, Duration::from_secs(60));
+        assert_eq!(backlog.size(&pool).await.unwrap(), 0);
+
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, "1".to_string(), &IngestOptions::default()).await.unwrap();
+        assert_eq!(backlog.size(&pool).await.unwrap(), 0);
+        assert!(matches!(backlog.tier(&pool).await.unwrap(), BacklogTier::Normal));
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = init_service(
+            test_app(test_pool().await)
+                .wrap(middleware::from_fn(correlate_request))
+                .app_data(web::Data::new(RequestIdHeader(HeaderName::from_static("x-correlation-id"))))
+        ).await;
+
+        let req = TestRequest::post().uri("/project/a/data")
+            .insert_header(("X-Correlation-Id", "gateway-42"))
+            .set_payload("1")
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-correlation-id").unwrap(), "gateway-42");
+
+        let req = TestRequest::post().uri("/project/a/data").set_payload("1").to_request();
+        let first = call_service(&app, req).await;
+        let first = first.headers().get("x-correlation-id").unwrap().to_str().unwrap().to_string();
+        let req = TestRequest::post().uri("/project/a/data").set_payload("1").to_request();
+        let second = call_service(&app, req).await;
+        let second = second.headers().get("x-correlation-id").unwrap().to_str().unwrap().to_string();
+        assert!(!first.is_empty());
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_server_addr() {
+        std::env::remove_var("ZETA_BIND_ADDR");
+        assert_eq!(server_addr(), "127.0.0.1:8000");
+        assert_eq!(parse_server_addr(&server_addr()).unwrap().port(), 8000);
+
+        std::env::set_var("ZETA_BIND_ADDR", "0.0.0.0:9000");
+        assert_eq!(server_addr(), "0.0.0.0:9000");
+        assert!(parse_server_addr(&server_addr()).unwrap().ip().is_unspecified());
+        std::env::remove_var("ZETA_BIND_ADDR");
+
+        assert!(parse_server_addr("0.0.0.0").unwrap_err().starts_with("invalid ZETA_BIND_ADDR"));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        std::env::set_var("RESPONSE_HEADERS", "X-Custom: zeta");
+        std::env::set_var("DECIMATION", "a=3");
+        std::env::set_var("BACKLOG_SOFT_LIMIT", "10");
+        std::env::set_var("BACKLOG_HARD_LIMIT", "20");
+        let summary = validate_config().unwrap();
+        assert!(summary.contains(&"RESPONSE_HEADERS: 1 header(s)".to_string()));
+        assert!(summary.contains(&"BACKLOG_HARD_LIMIT: 20".to_string()));
+
+        std::env::set_var("BACKLOG_HARD_LIMIT", "5");
+        assert!(validate_config().unwrap_err().contains("BACKLOG_SOFT_LIMIT"));
+        std::env::set_var("BACKLOG_HARD_LIMIT", "-1");
+        assert!(validate_config().unwrap_err().contains("BACKLOG_HARD_LIMIT"));
+        std::env::remove_var("BACKLOG_SOFT_LIMIT");
+        std::env::remove_var("BACKLOG_HARD_LIMIT");
+
+        std::env::set_var("DECIMATION", "a=0");
+        assert!(validate_config().unwrap_err().starts_with("DECIMATION"));
+        std::env::remove_var("DECIMATION");
+
+        std::env::set_var("RESPONSE_HEADERS", "Bad Name: x");
+        assert!(validate_config().unwrap_err().starts_with("RESPONSE_HEADERS"));
+        std::env::remove_var("RESPONSE_HEADERS");
+
+        std::env::set_var("REQUEST_ID_HEADER", "bad header");
+        assert!(validate_config().unwrap_err().contains("REQUEST_ID_HEADER"));
+        std::env::remove_var("REQUEST_ID_HEADER");
+
+        std::env::set_var("RESPONSE_LIMIT_POLICY", "drop");
+        assert!(validate_config().unwrap_err().contains("RESPONSE_LIMIT_POLICY"));
+        std::env::set_var("RESPONSE_LIMIT_POLICY", "truncate");
+        std::env::set_var("MAX_RESPONSE_BYTES", "4096");
+        let summary = validate_config().unwrap();
+        assert!(summary.contains(&"MAX_RESPONSE_BYTES: 4096".to_string()));
+        assert!(summary.contains(&"RESPONSE_LIMIT_POLICY: truncate".to_string()));
+        std::env::remove_var("RESPONSE_LIMIT_POLICY");
+        std::env::remove_var("MAX_RESPONSE_BYTES");
+
+        std::env::set_var("MAX_CONNECTIONS", "64");
+        assert!(validate_config().unwrap().contains(&"MAX_CONNECTIONS: 64".to_string()));
+        for bad in ["0", "-5", "many"] {
+            std::env::set_var("MAX_CONNECTIONS", bad);
+            assert!(validate_config().unwrap_err().contains("MAX_CONNECTIONS"));
+        }
+        std::env::remove_var("MAX_CONNECTIONS");
+
+        assert!(validate_config().is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_sequence_gaps() {
+        let app = init_service(test_app(test_pool().await)).await;
+
+        for seq in [1, 2, 4] {
+            let req = TestRequest::post().uri(&format!("/project/a/data?seq={}", seq)).set_payload("1").to_request();
+            assert_eq!(call_service(&app, req).await.status(), 201);
+        }
+        let req = TestRequest::post().uri("/project/b/data?seq=7").set_payload("1").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+        let req = TestRequest::post().uri("/project/a/data?seq=x").set_payload("1").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+
+        let req = TestRequest::get().uri("/stats").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        let gaps = body["sequence_gaps"].as_array().unwrap();
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0]["project_id"], "a");
+        assert_eq!(gaps[0]["from"], 3);
+        assert_eq!(gaps[0]["to"], 3);
+    }
+
+    #[actix_web::test]
+    async fn test_sequence_of_refused_writes() {
+        let pool = test_pool().await;
+        let quota = web::Data::new(WriteQuota { rows: HashMap::from([("a".to_string(), 1)]), window: Duration::from_secs(3600), ..Default::default() });
+        let backlog = |hard_limit| web::Data::new(Backlog::new(None, Some(hard_limit), Duration::ZERO));
+        let write = |seq: i64| TestRequest::post().uri(&format!("/project/a/data?seq={}", seq)).set_payload("1").to_request();
+        let max_seq = || async {
+            sqlx::query("SELECT last_seq FROM sequences WHERE project_id = 'a'").fetch_optional(&pool).await.unwrap()
+                .map(|row| row.get::<i64, _>("last_seq"))
+        };
+
+        // Refused with 503 while the backlog is over its hard limit, and
+        // with 429 once the quota is spent: neither write is seen.
+        let app = init_service(test_app(pool.clone()).app_data(quota.clone()).app_data(backlog(0))).await;
+        assert_eq!(call_service(&app, write(1)).await.status(), 503);
+        assert_eq!(max_seq().await, None);
+        let app = init_service(test_app(pool.clone()).app_data(quota.clone()).app_data(backlog(100))).await;
+        assert_eq!(call_service(&app, write(1)).await.status(), 201);
+        assert_eq!(call_service(&app, write(2)).await.status(), 429);
+        assert_eq!(max_seq().await, Some(1));
+
+        // Retried once there is room, the write leaves no gap.
+        let app = init_service(test_app(pool.clone()).app_data(backlog(100))).await;
+        assert_eq!(call_service(&app, write(2)).await.status(), 201);
+        assert_eq!(call_service(&app, write(3)).await.status(), 201);
+        assert_eq!(max_seq().await, Some(3));
+        let gaps: i64 = sqlx::query("SELECT COUNT(*) AS n FROM sequence_gaps").fetch_one(&pool).await.unwrap().get("n");
+        assert_eq!(gaps, 0);
+    }
+
+    #[test]
+    fn test_parse_relative_time() {
+        let now = Utc.with_ymd_and_hms(2023, 1, 2, 12, 0, 0).unwrap();
+        assert_eq!(parse_relative_time("now", now).unwrap(), now);
+        assert_eq!(parse_relative_time("now-30s", now).unwrap(), Utc.with_ymd_and_hms(2023, 1, 2, 11, 59, 30).unwrap());
+        assert_eq!(parse_relative_time("now-15m", now).unwrap(), Utc.with_ymd_and_hms(2023, 1, 2, 11, 45, 0).unwrap());
+        assert_eq!(parse_relative_time("now-2h", now).unwrap(), Utc.with_ymd_and_hms(2023, 1, 2, 10, 0, 0).unwrap());
+        assert_eq!(parse_relative_time("now-1d", now).unwrap(), Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap());
+
+        assert!(parse_relative_time("", now).is_err());
+        assert!(parse_relative_time("now-", now).is_err());
+        assert!(parse_relative_time("now+1h", now).is_err());
+        assert!(parse_relative_time("now-15", now).is_err());
+        assert!(parse_relative_time("now-m", now).is_err());
+        assert!(parse_relative_time("now-15w", now).is_err());
+        assert!(parse_relative_time("now--15m", now).is_err());
+        assert!(parse_relative_time("yesterday", now).is_err());
+        assert!(parse_relative_time("now-99999999999999d", now).is_err());
+
+        assert_eq!(parse_time_param("2023-01-01T00:00:00Z", now).unwrap(), Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(parse_time_param("now-1d", now).unwrap(), Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap());
+        assert!(parse_time_param("2023-01-01", now).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_response_limit() {
+        let pool = test_pool().await;
+        for i in 0..100 {
+            save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, format!("{}, {}", i, i * 2), &IngestOptions::default()).await.unwrap();
+        }
+        for (truncate, status) in [(false, 413), (true, 200)] {
+            let app = init_service(
+                test_app(pool.clone())
+                    .app_data(web::Data::new(ResponseLimit { max_bytes: Some(1000), truncate }))
+            ).await;
+
+            let req = TestRequest::get().uri("/project/a/data").to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+            let body: serde_json::Value = read_body_json(resp).await;
+            if truncate {
+                assert_eq!(body["truncated"], true);
+                let rows = body["rows"].as_array().unwrap();
+                assert!(!rows.is_empty() && rows.len() < 100);
+                assert!(serde_json::to_string(rows).unwrap().len() <= 1000);
+            } else {
+                assert!(body["error"].as_str().unwrap().contains("1000"));
+            }
+
+            let req = TestRequest::get().uri("/project/a/data?q=SELECT%20project_id,%20payload%20FROM%20wal%20LIMIT%202").to_request();
+            let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+            assert_eq!(body.as_array().unwrap().len(), 2);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_get_include_stats() {
+        let pool = test_pool().await;
+        for payload in ["1, 10", "4, x", "7, 30, 5"] {
+            save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, payload.to_string(), &IngestOptions::default()).await.unwrap();
+        }
+        let app = init_service(test_app(pool)).await;
+
+        let req = TestRequest::get().uri("/project/a/data?include_stats=true").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["rows"].as_array().unwrap().len(), 3);
+        assert_eq!(body["rows"][2]["payload"], "7, 30, 5");
+        assert_eq!(body["stats"], serde_json::json!({
+            "f0": { "min": 1.0, "max": 7.0, "avg": 4.0, "count": 3 },
+            "f1": { "min": 10.0, "max": 30.0, "avg": 20.0, "count": 2 },
+            "f2": { "min": 5.0, "max": 5.0, "avg": 5.0, "count": 1 },
+        }));
+
+        let req = TestRequest::get().uri("/project/a/data").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert!(body.is_array());
+    }
+
+    #[actix_web::test]
+    async fn test_run_blocking_timeout() {
+        let slow = || {
+            let conn = duckdb::Connection::open_in_memory()?;
+            conn.query_row("SELECT count(*) FROM range(5000) a, range(5000) b WHERE a.range + b.range = 7", [], |r| r.get::<_, i64>(0))
+        };
+        let limit = Duration::from_millis(50);
+        match run_blocking(Some(limit), slow).await {
+            Err(BlockingError::TimedOut(l)) => assert_eq!(l, limit),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert_eq!(run_blocking(None, slow).await.unwrap(), 8);
+    }
+
+    #[actix_web::test]
+    async fn test_get_query_timeout() {
+        let pool = test_pool().await;
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, "1, 10".to_string(), &IngestOptions::default()).await.unwrap();
+        let app = init_service(
+            test_app(pool).app_data(web::Data::new(QueryTimeout(Some(Duration::ZERO))))
+        ).await;
+
+        for uri in ["/project/a/data?include_stats=true", "/project/a/data?changes_only=true", "/project/a/data?format=parquet"] {
+            let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), 504, "{}", uri);
+            let body: serde_json::Value = read_body_json(resp).await;
+            assert_eq!(body["error"], "the query ran longer than 0s");
+        }
+        let req = TestRequest::get().uri("/project/a/data").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_get_include_stats_of_json_payloads() {
+        let pool = test_pool().await;
+        for (project, payload) in [("a", "[1, 2]"), ("a", "[3, 4, 5]"), ("b", r#"{"temp": 20, "rh": 0.5}"#), ("b", r#"{"temp": 22}"#)] {
+            save_to_db(&pool, project.to_string(), DEFAULT_SCHEMA, None, payload.to_string(), &IngestOptions::default()).await.unwrap();
+        }
+        let app = init_service(test_app(pool)).await;
+
+        let stats = |project: &str| TestRequest::get().uri(&format!("/project/{}/data?include_stats=true", project)).to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, stats("a")).await).await;
+        assert_eq!(body["stats"], serde_json::json!({
+            "f0": { "min": 1.0, "max": 3.0, "avg": 2.0, "count": 2 },
+            "f1": { "min": 2.0, "max": 4.0, "avg": 3.0, "count": 2 },
+            "f2": { "min": 5.0, "max": 5.0, "avg": 5.0, "count": 1 },
+        }));
+        let body: serde_json::Value = read_body_json(call_service(&app, stats("b")).await).await;
+        assert_eq!(body["stats"], serde_json::json!({
+            "rh": { "min": 0.5, "max": 0.5, "avg": 0.5, "count": 1 },
+            "temp": { "min": 20.0, "max": 22.0, "avg": 21.0, "count": 2 },
+        }));
+    }
+
+    #[actix_web::test]
+    async fn test_get_envelope() {
+        let pool = test_pool().await;
+        for payload in ["1, 2", "3, 4", "5, 6"] {
+            save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, payload.to_string(), &IngestOptions::default()).await.unwrap();
+        }
+        let app = init_service(test_app(pool)).await;
+
+        let req = TestRequest::get().uri("/project/a/data?envelope=true").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+        assert_eq!(body["meta"]["count"], 3);
+        assert_eq!(body["meta"]["source"], "range");
+        let query_time_ms = body["meta"]["query_time_ms"].as_f64().unwrap();
+        assert!((0.0..10_000.0).contains(&query_time_ms));
+
+        let req = TestRequest::get().uri("/project/a/data?envelope=true&q=SELECT%20project_id,%20payload%20FROM%20wal%20LIMIT%201").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["meta"]["count"], 1);
+        assert_eq!(body["meta"]["source"], "query");
+
+        let req = TestRequest::get().uri("/project/a/data").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body.as_array().unwrap().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_get_returns_rows() {
+        let pool = test_pool().await;
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, "1, 2".to_string(), &IngestOptions::default()).await.unwrap();
+        save_to_db(&pool, "b".to_string(), DEFAULT_SCHEMA, None, "3".to_string(), &IngestOptions::default()).await.unwrap();
+        let app = init_service(test_app(pool)).await;
+
+        let req = TestRequest::get().uri("/project/a/data").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let rows = body.as_array().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["project_id"], "a");
+        assert_eq!(rows[0]["payload"], "1, 2");
+        assert!(DateTime::parse_from_rfc3339(rows[0]["time"].as_str().unwrap()).is_ok());
+
+        let req = TestRequest::get().uri("/project/a/data?q=SELECT%20project_id,%20payload%20FROM%20wal%20ORDER%20BY%20rowid").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body, serde_json::json!([
+            { "project_id": "a", "time": null, "payload": "1, 2" },
+        ]));
+
+        let req = TestRequest::get().uri("/project/a/data?q=SELECT%20*%20FROM%20missing").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert!(body["error"].as_str().unwrap().contains("missing"));
+    }
+
+    #[test]
+    fn test_validate_read_only() {
+        assert_eq!(validate_read_only("SELECT * FROM wal"), Ok(()));
+        assert_eq!(validate_read_only("  select project_id from wal;  "), Ok(()));
+        assert_eq!(validate_read_only("SELECT 'a;b', \"x;y\" FROM wal -- trailing; comment"), Ok(()));
+        assert_eq!(validate_read_only("DROP TABLE wal"), Err(QueryError::NotSelect("DROP".to_string())));
+        assert_eq!(validate_read_only("/* select */ DELETE FROM wal"), Err(QueryError::NotSelect("DELETE".to_string())));
+        assert_eq!(validate_read_only("SELECT * FROM wal; DELETE FROM wal"), Err(QueryError::MultipleStatements));
+        assert_eq!(validate_read_only("SELECT 1;;SELECT 2"), Err(QueryError::MultipleStatements));
+        assert_eq!(validate_read_only(" ; "), Err(QueryError::Empty));
+    }
+
+    #[test]
+    fn test_validate_wal_only() {
+        let tables: Vec<String> = ["wal", "api_keys", "sequences"].iter().map(|t| t.to_string()).collect();
+        assert_eq!(validate_wal_only("SELECT * FROM wal WHERE payload <> 'api_keys' -- sequences", &tables), Ok(()));
+        assert_eq!(validate_wal_only("SELECT w.payload FROM wal AS w", &tables), Ok(()));
+        for (q, name) in [
+            ("SELECT * FROM main.wal", "main"),
+            ("SELECT * FROM temp.wal", "temp"),
+            ("SELECT * FROM API_KEYS", "API_KEYS"),
+            ("SELECT * FROM wal, \"sequences\"", "sequences"),
+            ("SELECT * FROM wal JOIN [api_keys] USING (project_id)", "api_keys"),
+            ("SELECT name AS project_id FROM sqlite_master", "sqlite_master"),
+            ("SELECT * FROM pragma_table_info('wal')", "pragma_table_info"),
+        ] {
+            assert_eq!(validate_wal_only(q, &tables), Err(QueryError::OtherTable(name.to_string())), "{}", q);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_get_scoped_to_project() {
+        let pool = test_pool().await;
+        for (project, payload) in [("a", "1"), ("b", "2"), ("a", "3"), ("b", "4")] {
+            save_to_db(&pool, project.to_string(), DEFAULT_SCHEMA, None, payload.to_string(), &IngestOptions::default()).await.unwrap();
+        }
+        create_api_key(&pool, "b").await.unwrap();
+        let app = init_service(test_app(pool)).await;
+        let get = |q: &str| TestRequest::get().uri(&format!("/project/a/data{}", q)).to_request();
+
+        for q in [
+            "",
+            "?from=2000-01-01T00:00:00Z",
+            "?q=SELECT%20project_id,%20payload%20FROM%20wal",
+            "?q=SELECT%20project_id,%20payload%20FROM%20wal%20WHERE%20project_id%20=%20'b'%20OR%201=1",
+            "?q=SELECT%20'a'%20AS%20project_id,%20payload%20FROM%20wal",
+        ] {
+            let body: serde_json::Value = read_body_json(call_service(&app, get(q)).await).await;
+            let payloads: Vec<&str> = body.as_array().unwrap().iter().map(|r| r["payload"].as_str().unwrap()).collect();
+            assert_eq!(payloads, vec!["1", "3"], "{}", q);
+        }
+        for q in [
+            "?q=SELECT%20project_id,%20payload%20FROM%20main.wal",
+            "?q=SELECT%20project_id,%20key_hash%20AS%20payload%20FROM%20api_keys",
+        ] {
+            assert_eq!(call_service(&app, get(q)).await.status(), 403, "{}", q);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_get_rejects_writes() {
+        let pool = test_pool().await;
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, "1".to_string(), &IngestOptions::default()).await.unwrap();
+        let app = init_service(test_app(pool.clone())).await;
+
+        for q in ["DROP%20TABLE%20wal", "SELECT%20*%20FROM%20wal;%20DELETE%20FROM%20wal"] {
+            let req = TestRequest::get().uri(&format!("/project/a/data?q={}", q)).to_request();
+            assert_eq!(call_service(&app, req).await.status(), 403);
+        }
+        let count: i64 = sqlx::query("SELECT COUNT(*) AS n FROM wal").fetch_one(&pool).await.unwrap().get("n");
+        assert_eq!(count, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_get_time_range() {
+        let pool = test_pool().await;
+        let rows = [(2, "2"), (0, "0"), (3, "3"), (1, "1")].iter()
+            .map(|(day, payload)| (Utc.with_ymd_and_hms(2023, 1, 1 + day, 0, 0, 0).unwrap(), payload.to_string()))
+            .collect();
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+        save_to_db(&pool, "b".to_string(), DEFAULT_SCHEMA, None, "9".to_string(), &IngestOptions::default()).await.unwrap();
+        let app = init_service(test_app(pool)).await;
+
+        for (range, expected) in [
+            ("", vec!["0", "1", "2", "3"]),
+            ("?from=2023-01-02T00:00:00Z", vec!["1", "2", "3"]),
+            ("?to=2023-01-03T00:00:00Z", vec!["0", "1", "2"]),
+            ("?from=2023-01-02T00:00:00Z&to=2023-01-02T12:00:00%2B00:00", vec!["1"]),
+        ] {
+            let req = TestRequest::get().uri(&format!("/project/a/data{}", range)).to_request();
+            let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+            let payloads: Vec<&str> = body.as_array().unwrap().iter().map(|r| r["payload"].as_str().unwrap()).collect();
+            assert_eq!(payloads, expected, "{}", range);
+        }
+        for range in ["?from=yesterday", "?to=2023-13-01T00:00:00Z"] {
+            let req = TestRequest::get().uri(&format!("/project/a/data{}", range)).to_request();
+            assert_eq!(call_service(&app, req).await.status(), 400, "{}", range);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_get_archived_rows() {
+        let root = std::env::temp_dir().join(format!("zeta-querier-archive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::create_dir_all(root.join("b")).unwrap();
+        let pool = test_pool().await;
+        let day = |d: u32| Utc.with_ymd_and_hms(2023, 1, d, 0, 0, 0).unwrap();
+        let rows = [(1, "1, 2"), (2, "3, 4"), (3, "5"), (4, "6")].iter().map(|(d, p)| (day(*d), p.to_string())).collect();
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+
+        // Persist the first three rows the way the persister lays them out,
+        // in a plain file and in one partitioned by date, and drop them from
+        // the WAL.
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "INSTALL parquet; LOAD parquet;
+             CREATE TABLE persisted (time TIMESTAMP, f0 DOUBLE, f1 DOUBLE);
+             INSERT INTO persisted VALUES ('2023-01-01 00:00:00', 1, 2), ('2023-01-03 00:00:00', 5, NULL);
+             COPY persisted TO '{a}/default' (FORMAT 'parquet');
+             COPY (SELECT TIMESTAMP '2023-01-02 00:00:00' AS time, 3.0 AS f0, 4.0 AS f1, DATE '2023-01-02' AS date)
+                 TO '{a}/daily' (FORMAT 'parquet', PARTITION_BY (date));
+             COPY persisted TO '{b}/default' (FORMAT 'parquet');
+             COPY (SELECT * FROM (VALUES (TIMESTAMP '2023-01-05 00:00:00', 20.0, 0.5), (TIMESTAMP '2023-01-06 00:00:00', 22.0, NULL)) v(time, temp, rh))
+                 TO '{b}/named' (FORMAT 'parquet');",
+            a = root.join("a").display(), b = root.join("b").display(),
+        )).unwrap();
+        std::fs::write(root.join("a").join("default.lock"), "").unwrap();
+        // The third row is archived but still in the WAL, as between the
+        // persister's COPY and its delete.
+        sqlx::query("DELETE FROM wal WHERE payload NOT IN ('5', '6')").execute(&pool).await.unwrap();
+
+        let app = init_service(
+            test_app(pool)
+                .app_data(web::Data::new(ParquetArchive { root: Some(root.clone()) }))
+        ).await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+        let rows = |body: serde_json::Value| -> Vec<(String, String)> {
+            body.as_array().unwrap().iter()
+                .map(|r| (r["time"].as_str().unwrap().to_string(), r["payload"].as_str().unwrap().to_string()))
+                .collect()
+        };
+
+        let body: serde_json::Value = read_body_json(call_service(&app, get("/project/a/data")).await).await;
+        assert_eq!(body[0]["project_id"], "a");
+        assert_eq!(rows(body), [(1, "1, 2"), (2, "3, 4"), (3, "5"), (4, "6")].iter()
+            .map(|(d, p)| (day(*d).to_rfc3339(), p.to_string()))
+            .collect::<Vec<_>>());
+
+        let body: serde_json::Value = read_body_json(call_service(&app, get("/project/a/data?from=2023-01-02T00:00:00Z&to=2023-01-03T00:00:00Z")).await).await;
+        assert_eq!(rows(body).into_iter().map(|(_, p)| p).collect::<Vec<_>>(), vec!["3, 4", "5"]);
+        let body: serde_json::Value = read_body_json(call_service(&app, get("/project/c/data")).await).await;
+        assert_eq!(body, serde_json::json!([]));
+
+        // Named columns come back as the JSON object they were written as,
+        // in the file's column order and with `null` for a padded column.
+        let body: serde_json::Value = read_body_json(call_service(&app, get("/project/b/data?from=2023-01-05T00:00:00Z")).await).await;
+        assert_eq!(rows(body).into_iter().map(|(_, p)| p).collect::<Vec<_>>(), vec![r#"{"temp":20.0,"rh":0.5}"#, r#"{"temp":22.0,"rh":null}"#]);
+
+        // Ids that could not name a project directory are refused on read
+        // as on write.
+        assert_eq!(call_service(&app, get("/project/.a/data")).await.status(), 400);
+        let req = TestRequest::post().uri("/project/.a/data").set_payload("1").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_get_partitioned_rows() {
+        let root = std::env::temp_dir().join(format!("zeta-querier-partitions-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "INSTALL parquet; LOAD parquet;
+             COPY (SELECT * FROM (VALUES
+                     (TIMESTAMP '2023-01-01 00:00:00', 1.0, DATE '2023-01-01'),
+                     (TIMESTAMP '2023-01-02 12:00:00', 2.0, DATE '2023-01-02'),
+                     (TIMESTAMP '2023-01-03 00:00:00', 3.0, DATE '2023-01-03')) v(time, f0, date))
+                 TO '{}' (FORMAT 'parquet', PARTITION_BY (date));",
+            root.join("a").join("default").display(),
+        )).unwrap();
+        // A partition outside the range that could not even be read.
+        let outside = root.join("a").join("default").join("date=2023-01-09");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("data_0.parquet"), "not parquet").unwrap();
+
+        let app = init_service(
+            test_app(test_pool().await)
+                .app_data(web::Data::new(ParquetArchive { root: Some(root.clone()) }))
+        ).await;
+        let req = TestRequest::get().uri("/project/a/data?from=2023-01-01T00:00:00Z&to=2023-01-03T00:00:00Z").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let payloads: Vec<&str> = body.as_array().unwrap().iter().map(|r| r["payload"].as_str().unwrap()).collect();
+        assert_eq!(payloads, vec!["1", "2", "3"]);
+        assert_eq!(body[1]["time"], "2023-01-02T12:00:00+00:00");
+
+        // Without a range the broken partition is read and fails the query.
+        let req = TestRequest::get().uri("/project/a/data").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+
+        // Hourly and monthly partitions are pruned by their own column.
+        for (column, partitions) in [("hour", ["2023-01-01T00", "2023-01-01T05", "2023-01-01T09"]), ("month", ["2022-12", "2023-01", "2023-03"])] {
+            let dir = root.join(column).join("default");
+            for (i, partition) in partitions.iter().enumerate() {
+                let time = if column == "hour" { format!("{}:30:00", partition.replace('T', " ")) } else { format!("{}-15 00:00:00", partition) };
+                std::fs::create_dir_all(dir.join(format!("{}={}", column, partition))).unwrap();
+                conn.execute_batch(&format!(
+                    "COPY (SELECT TIMESTAMP '{}' AS time, {}.0 AS f0) TO '{}' (FORMAT 'parquet');",
+                    time, i, dir.join(format!("{}={}", column, partition)).join("data_0.parquet").display(),
+                )).unwrap();
+            }
+            std::fs::create_dir_all(dir.join(format!("{}=9999", column))).unwrap();
+            std::fs::write(dir.join(format!("{}=9999", column)).join("data_0.parquet"), "not parquet").unwrap();
+            let range = if column == "hour" { "from=2023-01-01T05:00:00Z&to=2023-01-01T10:00:00Z" } else { "from=2023-01-01T00:00:00Z&to=2023-03-31T00:00:00Z" };
+            let resp = call_service(&app, TestRequest::get().uri(&format!("/project/{}/data?{}", column, range)).to_request()).await;
+            assert_eq!(resp.status(), 200, "{}", column);
+            let body: serde_json::Value = read_body_json(resp).await;
+            let payloads: Vec<&str> = body.as_array().unwrap().iter().map(|r| r["payload"].as_str().unwrap()).collect();
+            assert_eq!(payloads, vec!["1", "2"], "{}", column);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_fragments() {
+        let root = std::env::temp_dir().join(format!("zeta-querier-fragments-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        // A plain destination and one partitioned by date, as after merges
+        // of each, besides a lock file and a merge's temporary file.
+        duckdb::Connection::open_in_memory().unwrap().execute_batch(&format!(
+            "INSTALL parquet; LOAD parquet;
+             COPY (SELECT * FROM (VALUES (TIMESTAMP '2023-01-01 00:00:00', 1.0), (TIMESTAMP '2023-01-01 06:00:00', 2.0)) v(time, f0))
+                 TO '{a}/default' (FORMAT 'parquet');
+             COPY (SELECT TIMESTAMP '2023-01-02 00:00:00' AS time, 3.0 AS f0, DATE '2023-01-02' AS date)
+                 TO '{a}/daily' (FORMAT 'parquet', PARTITION_BY (date));",
+            a = root.join("a").display(),
+        )).unwrap();
+        std::fs::write(root.join("a").join("default.lock"), "").unwrap();
+        std::fs::write(root.join("a").join(".default.tmp"), "").unwrap();
+        std::fs::write(root.join("wal.sqlite"), "").unwrap();
+
+        let app = init_service(
+            test_app(test_pool().await)
+                .app_data(web::Data::new(ParquetArchive { root: Some(root.clone()) }))
+        ).await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+        let resp = call_service(&app, get("/project/a/fragments")).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let partition = "daily/date=2023-01-02/data_0.parquet";
+        assert_eq!(body.as_array().unwrap().iter().map(|f| f["name"].as_str().unwrap()).collect::<Vec<_>>(), vec![partition, "default"]);
+        assert_eq!(body[1]["rows"], 2);
+        assert_eq!(body[1]["from"], "2023-01-01T00:00:00+00:00");
+        assert_eq!(body[1]["to"], "2023-01-01T06:00:00+00:00");
+        assert_eq!(body[1]["size"], std::fs::metadata(root.join("a").join("default")).unwrap().len());
+        let body: serde_json::Value = read_body_json(call_service(&app, get("/project/b/fragments")).await).await;
+        assert_eq!(body, serde_json::json!([]));
+
+        let resp = call_service(&app, get(&format!("/project/a/fragments/{}", partition))).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("content-type").unwrap(), PARQUET_CONTENT_TYPE);
+        let downloaded = root.join("downloaded.parquet");
+        std::fs::write(&downloaded, actix_web::test::read_body(resp).await).unwrap();
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        let f0: f64 = conn.query_row(&format!("SELECT f0 FROM read_parquet('{}')", downloaded.display()), [], |row| row.get(0)).unwrap();
+        assert_eq!(f0, 3.0);
+
+        for name in ["..%2Fwal.sqlite", "../wal.sqlite", "daily/../default", ".default.tmp"] {
+            let status = call_service(&app, get(&format!("/project/a/fragments/{}", name))).await.status();
+            assert!(status == 400 || status == 404, "{}: {}", name, status);
+        }
+        assert_eq!(call_service(&app, get("/project/a/fragments/default.lock")).await.status(), 404);
+        assert_eq!(call_service(&app, get("/project/a/fragments/missing")).await.status(), 404);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_get_source() {
+        let root = std::env::temp_dir().join(format!("zeta-querier-source-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "INSTALL parquet; LOAD parquet;
+             COPY (SELECT TIMESTAMP '2023-01-01 00:00:00' AS time, 1.0 AS f0) TO '{}' (FORMAT 'parquet');",
+            root.join("a").join("default").display(),
+        )).unwrap();
+        let pool = test_pool().await;
+        let rows = vec![(Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap(), "2".to_string())];
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(ParquetArchive { root: Some(root.clone()) }))
+        ).await;
+
+        let payloads = |uri: String| {
+            let req = TestRequest::get().uri(&uri).to_request();
+            let app = &app;
+            async move {
+                let body: serde_json::Value = read_body_json(call_service(app, req).await).await;
+                body.as_array().unwrap().iter().map(|r| r["payload"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(payloads("/project/a/data".to_string()).await, vec!["1", "2"]);
+        for (source, expected) in [("unified", vec!["1", "2"]), ("wal", vec!["2"]), ("parquet", vec!["1"])] {
+            assert_eq!(payloads(format!("/project/a/data?source={}", source)).await, expected, "{}", source);
+        }
+
+        let status = |uri: &str| TestRequest::get().uri(uri).to_request();
+        assert_eq!(call_service(&app, status("/project/a/data?source=cache")).await.status(), 400);
+        assert_eq!(call_service(&app, status("/project/a/data?source=parquet&q=SELECT%20*%20FROM%20wal")).await.status(), 400);
+        let unarchived = init_service(test_app(pool)).await;
+        assert_eq!(call_service(&unarchived, status("/project/a/data?source=parquet")).await.status(), 400);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_get_parquet_export() {
+        let pool = test_pool().await;
+        let day = |d: u32| Utc.with_ymd_and_hms(2023, 1, d, 0, 0, 0).unwrap();
+        let rows = vec![(day(1), "1, 2".to_string()), (day(2), "3".to_string())];
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+        let app = init_service(test_app(pool)).await;
+
+        let req = TestRequest::get().uri("/project/a/data?format=parquet&from=2023-01-01T00:00:00Z&to=2023-01-02T00:00:00Z").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("content-type").unwrap(), PARQUET_CONTENT_TYPE);
+        let bytes = actix_web::test::read_body(resp).await;
+        assert!(bytes.starts_with(b"PAR1"));
+
+        let path = std::env::temp_dir().join(format!("zeta-querier-export-{}.parquet", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        let sql = format!("SELECT CAST(time AS VARCHAR), f0, f1 FROM read_parquet('{}')", path.display());
+        let exported: Vec<(String, f64, Option<f64>)> = conn.prepare(&sql).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(exported, vec![
+            ("2023-01-01 00:00:00".to_string(), 1.0, Some(2.0)),
+            ("2023-01-02 00:00:00".to_string(), 3.0, None),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+
+        let leftovers = std::fs::read_dir(std::env::temp_dir()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("zeta-export-"))
+            .count();
+        assert_eq!(leftovers, 0);
+        let req = TestRequest::get().uri("/project/a/data?format=csv").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_get_changes_only() {
+        let pool = test_pool().await;
+        let payloads = ["1, 5", "1, 5", "1, 5", "2, 5", "2, 5", "2, 6", "2, 6", "2, 6"];
+        let rows = payloads.iter().enumerate()
+            .map(|(i, p)| (Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, i as u32).unwrap(), p.to_string()))
+            .collect();
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+        let app = init_service(test_app(pool)).await;
+
+        let req = TestRequest::get().uri("/project/a/data?from=2023-01-01T00:00:00Z&changes_only=true").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        let seconds: Vec<(String, String)> = body.as_array().unwrap().iter()
+            .map(|r| (r["time"].as_str().unwrap()[17..19].to_string(), r["payload"].as_str().unwrap().to_string()))
+            .collect();
+        assert_eq!(seconds, [("00", "1, 5"), ("03", "2, 5"), ("05", "2, 6"), ("07", "2, 6")].iter()
+            .map(|(t, p)| (t.to_string(), p.to_string()))
+            .collect::<Vec<_>>());
+    }
+
+    #[actix_web::test]
+    async fn test_get_rate() {
+        let pool = test_pool().await;
+        // A counter sampled every ten seconds that wraps after 40.
+        let rows = ["10", "20", "40", "5", "15"].iter().enumerate()
+            .map(|(i, p)| (Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 10 * i as u32).unwrap(), p.to_string()))
+            .collect();
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+        let app = init_service(test_app(pool)).await;
+
+        let rates = |query: &str| TestRequest::get().uri(&format!("/project/a/data?from=2023-01-01T00:00:00Z&{}", query)).to_request();
+        let payloads = |body: serde_json::Value| -> Vec<String> {
+            body.as_array().unwrap().iter().map(|r| r["payload"].as_str().unwrap().to_string()).collect()
+        };
+        let body: serde_json::Value = read_body_json(call_service(&app, rates("transform=rate")).await).await;
+        assert_eq!(body[0]["time"], "2023-01-01T00:00:10+00:00");
+        assert_eq!(payloads(body), vec!["1", "2", "", "1"]);
+        let body: serde_json::Value = read_body_json(call_service(&app, rates("transform=rate&negative=reset")).await).await;
+        assert_eq!(payloads(body), vec!["1", "2", "0.5", "1"]);
+
+        assert_eq!(call_service(&app, rates("transform=rate&negative=clamp")).await.status(), 400);
+        assert_eq!(call_service(&app, rates("transform=integral")).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_get_explain() {
+        let root = std::env::temp_dir().join(format!("zeta-querier-explain-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        let file = root.join("a").join("default");
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!(
+            "INSTALL parquet; LOAD parquet;
+             COPY (SELECT TIMESTAMP '2023-01-01 00:00:00' + INTERVAL (i) SECOND AS time, i::DOUBLE AS f0 FROM range(3) t(i))
+                 TO '{}' (FORMAT 'parquet');",
+            file.display(),
+        )).unwrap();
+        let app = init_service(
+            test_app(test_pool().await)
+                .app_data(web::Data::new(ParquetArchive { root: Some(root.clone()) }))
+        ).await;
+
+        let req = TestRequest::get().uri("/project/a/data?explain=true").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 3);
+        let explain = &body["meta"]["explain"];
+        assert_eq!(explain["files"], serde_json::json!([file.to_str().unwrap()]));
+        assert_eq!(explain["row_groups"], 1);
+        // DuckDB's plan names the scanned file and estimates its rows.
+        let plan = explain["plans"][0].as_str().unwrap();
+        assert!(plan.contains(file.to_str().unwrap()) && plan.contains("READ_PARQUET"), "{}", plan);
+        assert!(plan.contains("EC:"), "{}", plan);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_get_with_relative_time() {
+        let app = init_service(test_app(test_pool().await)).await;
+
+        let req = TestRequest::get().uri("/project/a/data?from=now-15m&to=now").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+        let req = TestRequest::get().uri("/project/a/data?from=now-15x").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_post_source() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+        let req = TestRequest::post().uri("/project/a/data").insert_header(("X-Source", "sensor-a")).set_payload("1").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+        let req = TestRequest::post().uri("/project/a/data?source=sensor-b")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(r#"[{"time": "2023-01-01T00:00:00Z", "values": [2]}]"#)
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+        let req = TestRequest::post().uri("/project/a/data").set_payload("3").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+        let req = TestRequest::post().uri("/project/a/data").insert_header(("X-Source", "../b")).set_payload("4").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+
+        let sources: Vec<(String, Option<String>)> = sqlx::query("SELECT payload, source FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| (row.get("payload"), row.get("source"))).collect();
+        assert_eq!(sources, vec![
+            ("1".to_string(), Some("sensor-a".to_string())),
+            ("2".to_string(), Some("sensor-b".to_string())),
+            ("3".to_string(), None),
+        ]);
+        let req = TestRequest::get().uri("/project/a/data").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        let sources: Vec<serde_json::Value> = body.as_array().unwrap().iter().map(|r| r["source"].clone()).collect();
+        assert_eq!(sources, vec![serde_json::json!("sensor-b"), serde_json::json!("sensor-a"), serde_json::Value::Null]);
+
+        // Archived rows carry the `source` column the persister writes.
+        let root = std::env::temp_dir().join(format!("zeta-querier-post-source-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("c")).unwrap();
+        duckdb::Connection::open_in_memory().unwrap().execute_batch(&format!(
+            "INSTALL parquet; LOAD parquet;
+             COPY (SELECT * FROM (VALUES (TIMESTAMP '2023-01-01 00:00:00', 'sensor-a', 1.0), (TIMESTAMP '2023-01-02 00:00:00', NULL, 2.0)) v(time, source, f0))
+                 TO '{}' (FORMAT 'parquet');",
+            root.join("c").join("default").display(),
+        )).unwrap();
+        let app = init_service(test_app(pool).app_data(web::Data::new(ParquetArchive { root: Some(root.clone()) }))).await;
+        let req = TestRequest::get().uri("/project/c/data").to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body[0]["source"], "sensor-a");
+        assert_eq!(body[0]["payload"], "1");
+        assert!(body[1].get("source").is_none());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_wal_schema() {
+        let app = init_service(test_app(test_pool().await)).await;
+
+        let req = TestRequest::get().uri("/meta/wal-schema").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["table"], "wal");
+        let columns = body["columns"].as_array().unwrap();
+        let names: Vec<&str> = columns.iter().map(|c| c["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["project_id", "schema", "time", "created_at", "payload", "compressed", "attempts", "source"]);
+        assert_eq!(columns[2]["type"], "DATETIME");
+        assert_eq!(columns[2]["not_null"], true);
+    }
+
+    #[actix_web::test]
+    async fn test_pretty_json() {
+        let app = init_service(test_app(test_pool().await)).await;
+
+        for uri in ["/stats", "/meta/wal-schema"] {
+            let req = TestRequest::get().uri(uri).to_request();
+            let compact = call_and_read_body(&app, req).await;
+            let req = TestRequest::get().uri(&format!("{}?pretty=true", uri)).to_request();
+            let pretty = call_and_read_body(&app, req).await;
+
+            assert!(!compact.contains(&b'\n'));
+            assert!(std::str::from_utf8(&pretty).unwrap().contains("\n  \""));
+            let compact: serde_json::Value = serde_json::from_slice(&compact).unwrap();
+            let pretty: serde_json::Value = serde_json::from_slice(&pretty).unwrap();
+            assert_eq!(compact, pretty);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_api_keys() {
+        let keys = ApiKeys { admin_token: Some("admin".to_string()), required: true };
+        let app = init_service(
+            test_app(test_pool().await)
+                .app_data(web::Data::new(keys))
+        ).await;
+
+        let req = TestRequest::post().uri("/admin/projects/a/keys").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+        let req = TestRequest::post().uri("/admin/projects/a/keys")
+            .insert_header(("Authorization", "Bearer admin")).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let key = format!("Bearer {}", body["key"].as_str().unwrap());
+        let req = TestRequest::post().uri("/admin/projects/a;b/keys")
+            .insert_header(("Authorization", "Bearer admin")).to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+
+        let write = |project: &str, key: &str| TestRequest::post()
+            .uri(&format!("/project/{}/data", project))
+            .insert_header(("Authorization", key.to_string()))
+            .set_payload("1, 2")
+            .to_request();
+        assert_eq!(call_service(&app, write("a", &key)).await.status(), 201);
+        assert_eq!(call_service(&app, write("b", &key)).await.status(), 403);
+        assert_eq!(call_service(&app, write("a", "Bearer 1.forged")).await.status(), 401);
+        let req = TestRequest::get().uri("/project/a/data").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+
+        let req = TestRequest::delete().uri(&format!("/admin/keys/{}", body["id"]))
+            .insert_header(("Authorization", "Bearer admin")).to_request();
+        assert_eq!(call_service(&app, req).await.status(), 204);
+        assert_eq!(call_service(&app, write("a", &key)).await.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_signed_export() {
+        let pool = test_pool().await;
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, "1, 2".to_string(), &IngestOptions::default()).await.unwrap();
+        let keys = ApiKeys { admin_token: Some("admin".to_string()), required: true };
+        let signer = ExportSigner { key: Some(b"secret".to_vec()), ttl: Duration::from_secs(60) };
+        let app = init_service(
+            test_app(pool)
+                .app_data(web::Data::new(keys))
+                .app_data(web::Data::new(signer))
+        ).await;
+        let req = TestRequest::post().uri("/admin/projects/a/keys").insert_header(("Authorization", "Bearer admin")).to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        let key = format!("Bearer {}", body["key"].as_str().unwrap());
+
+        let sign = |project: &str, key: Option<&str>| {
+            let req = TestRequest::post().uri(&format!("/project/{}/export/sign", project));
+            match key {
+                Some(key) => req.insert_header(("Authorization", key.to_string())).to_request(),
+                None => req.to_request(),
+            }
+        };
+        assert_eq!(call_service(&app, sign("a", None)).await.status(), 401);
+        assert_eq!(call_service(&app, sign("b", Some(&key))).await.status(), 403);
+        let resp = call_service(&app, sign("a", Some(&key))).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let url = body["url"].as_str().unwrap().to_string();
+        assert!(url.starts_with("/project/a/data?format=parquet&expires="));
+
+        // The signed URL exports without a key, also with a range added.
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+        let resp = call_service(&app, get(&url)).await;
+        assert_eq!(resp.status(), 200);
+        assert!(actix_web::test::read_body(resp).await.starts_with(b"PAR1"));
+        assert_eq!(call_service(&app, get(&format!("{}&from=2000-01-01T00:00:00Z", url))).await.status(), 200);
+
+        // Tampering with the signature, expiry, project or format, or asking
+        // for JSON, is refused.
+        let (base, signature) = url.rsplit_once("&signature=").unwrap();
+        let flipped = format!("{}&signature={}{}", base, if signature.starts_with('0') { "1" } else { "0" }, &signature[1..]);
+        let expires: i64 = base.rsplit_once("expires=").unwrap().1.parse().unwrap();
+        for tampered in [
+            flipped,
+            url.replace(&format!("expires={}", expires), &format!("expires={}", expires + 3600)),
+            url.replace("/project/a/", "/project/b/"),
+            url.replace("format=parquet", "format=json"),
+            format!("{}&signature=", base),
+        ] {
+            assert_eq!(call_service(&app, get(&tampered)).await.status(), 403, "{}", tampered);
+        }
+        assert_eq!(call_service(&app, get(base)).await.status(), 401);
+
+        // An expired URL is refused, though its signature is genuine.
+        let signer = ExportSigner { key: Some(b"secret".to_vec()), ttl: Duration::from_secs(60) };
+        let past = Utc::now().timestamp() - 1;
+        let expired = format!("/project/a/data?format=parquet&expires={}&signature={}", past, signer.sign("/project/a/data", past).unwrap());
+        assert_eq!(call_service(&app, get(&expired)).await.status(), 403);
+        assert!(signer.verify("/project/a/data", past, &signer.sign("/project/a/data", past).unwrap(), Utc::now() - chrono::Duration::seconds(10)));
+
+        // Without a signing key nothing is signed.
+        let app = init_service(test_app(test_pool().await)).await;
+        assert_eq!(call_service(&app, sign("a", None)).await.status(), 404);
+        assert_eq!(call_service(&app, get(&url)).await.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_write_quota() {
+        let pool = test_pool().await;
+        let quota = WriteQuota {
+            rows: HashMap::from([("a".to_string(), 2)]),
+            bytes: HashMap::from([("b".to_string(), 10)]),
+            window: Duration::from_secs(3600),
+        };
+        let at = |h, m| Utc.with_ymd_and_hms(2023, 1, 1, h, m, 0).unwrap();
+
+        assert!(quota.consume(&pool, "a", 1, 100, at(0, 0)).await.unwrap());
+        assert!(quota.consume(&pool, "a", 1, 100, at(0, 30)).await.unwrap());
+        assert!(!quota.consume(&pool, "a", 1, 100, at(0, 59)).await.unwrap());
+        assert_eq!(quota.remaining_secs(at(0, 59)), 60);
+        assert!(quota.consume(&pool, "a", 1, 100, at(1, 0)).await.unwrap());
+
+        assert!(quota.consume(&pool, "b", 1, 6, at(0, 0)).await.unwrap());
+        assert!(!quota.consume(&pool, "b", 1, 6, at(0, 1)).await.unwrap());
+        assert!(quota.consume(&pool, "b", 1, 4, at(0, 2)).await.unwrap());
+        assert!(quota.consume(&pool, "c", 1, 1000, at(0, 0)).await.unwrap());
+
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(WriteQuota { rows: HashMap::from([("d".to_string(), 1)]), ..Default::default() }))
+        ).await;
+        let write = || TestRequest::post().uri("/project/d/data").set_payload("1, 2").to_request();
+        assert_eq!(call_service(&app, write()).await.status(), 201);
+        let resp = call_service(&app, write()).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key("Retry-After"));
+
+        // Concurrent writes never take more than the quota between them.
+        let quota = WriteQuota { rows: HashMap::from([("e".to_string(), 5)]), ..Default::default() };
+        let now = Utc::now();
+        let consumed = futures::future::join_all((0..20).map(|_| quota.consume(&pool, "e", 1, 1, now))).await;
+        assert_eq!(consumed.into_iter().filter(|c| *c.as_ref().unwrap()).count(), 5);
+        let used: i64 = sqlx::query("SELECT rows FROM write_quotas WHERE project_id = 'e'").fetch_one(&pool).await.unwrap().get("rows");
+        assert_eq!(used, 5);
+
+        // Writes that store nothing are not charged, singly or in batches.
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(WriteQuota { rows: HashMap::from([("f".to_string(), 2)]), ..Default::default() }))
+        ).await;
+        let single = || TestRequest::post().uri("/project/f/data").set_payload("1").to_request();
+        let batch = || TestRequest::post().uri("/project/f/data")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(r#"[{"values": [1]}, {"values": [2]}]"#)
+            .to_request();
+        sqlx::query("ALTER TABLE wal RENAME TO wal_away").execute(&pool).await.unwrap();
+        assert_eq!(call_service(&app, single()).await.status(), 500);
+        assert_eq!(call_service(&app, batch()).await.status(), 500);
+        sqlx::query("ALTER TABLE wal_away RENAME TO wal").execute(&pool).await.unwrap();
+        assert_eq!(call_service(&app, batch()).await.status(), 201);
+        assert_eq!(call_service(&app, single()).await.status(), 429);
+    }
+
+    #[actix_web::test]
+    async fn test_max_projects() {
+        let pool = test_pool().await;
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, "1".to_string(), &IngestOptions::default()).await.unwrap();
+        let options = || web::Data::new(IngestOptions { max_projects: Some(2), ..Default::default() });
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(options())
+                .app_data(web::Data::new(WriteQuota { rows: HashMap::from([("q".to_string(), 0)]), ..Default::default() }))
+        ).await;
+
+        let write = |project: &str| TestRequest::post()
+            .uri(&format!("/project/{}/data", project))
+            .set_payload("1, 2")
+            .to_request();
+        // A write refused by its quota does not take a slot.
+        assert_eq!(call_service(&app, write("q")).await.status(), 429);
+        assert_eq!(call_service(&app, write("b")).await.status(), 201);
+        assert_eq!(call_service(&app, write("c")).await.status(), 403);
+        assert_eq!(call_service(&app, write("a")).await.status(), 201);
+        assert_eq!(call_service(&app, write("b")).await.status(), 201);
+
+        // Projects stay registered once the persister has deleted their rows.
+        sqlx::query("DELETE FROM wal").execute(&pool).await.unwrap();
+        initialize_database(&pool).await.unwrap();
+        let app = init_service(test_app(pool).app_data(options())).await;
+        assert_eq!(call_service(&app, write("c")).await.status(), 403);
+        assert_eq!(call_service(&app, write("a")).await.status(), 201);
+        assert_eq!(call_service(&app, write("b")).await.status(), 201);
+    }
+
+    #[actix_web::test]
+    async fn test_immediate_persist() {
+        let pool = test_pool().await;
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(ImmediatePersist(HashSet::from(["a".to_string()]))))
+        ).await;
+
+        for project in ["a", "b"] {
+            let req = TestRequest::post().uri(&format!("/project/{}/data", project)).set_payload("1, 2").to_request();
+            assert_eq!(call_service(&app, req).await.status(), 201);
+        }
+        let req = TestRequest::post().uri("/project/a/data")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(r#"[{"values": [3, 4]}]"#)
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+
+        let requested: Vec<String> = sqlx::query("SELECT project_id FROM persist_requests").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| row.get("project_id")).collect();
+        assert_eq!(requested, vec!["a"]);
+    }
+
+    #[actix_web::test]
+    async fn test_idempotent_batch() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+        let batch = |key: &str| TestRequest::post().uri("/project/a/data")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("Idempotency-Key", key))
+            .set_payload(r#"[{"values": [1, 2]}, {"values": [3, 4]}]"#)
+            .to_request();
+        let rows = || sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool);
+
+        let first = call_service(&app, batch("k1")).await;
+        assert_eq!(first.status(), 201);
+        let first: serde_json::Value = read_body_json(first).await;
+        let retry = call_service(&app, batch("k1")).await;
+        assert_eq!(retry.status(), 201);
+        let retry: serde_json::Value = read_body_json(retry).await;
+        assert_eq!(first, serde_json::json!({ "accepted": 2, "dropped": 0 }));
+        assert_eq!(retry, first);
+        assert_eq!(rows().await.unwrap().get::<i64, _>(0), 2);
+
+        assert_eq!(call_service(&app, batch("k2")).await.status(), 201);
+        assert_eq!(rows().await.unwrap().get::<i64, _>(0), 4);
+    }
+
+    #[actix_web::test]
+    async fn test_best_effort_batch() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+        let req = TestRequest::post().uri("/project/a/data?mode=best_effort")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(r#"[{"values": [1]}, {"values": "x"}, {"time": "2024-01-01T00:00:00Z", "values": [2]}, {"time": "soon", "values": [3]}]"#)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let body: serde_json::Value = read_body_json(resp).await;
+
+        let ids: Vec<i64> = sqlx::query("SELECT rowid FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| row.get(0)).collect();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(body, serde_json::json!({
+            "accepted": 2,
+            "rejected": 2,
+            "dropped": 0,
+            "results": [
+                { "index": 0, "status": "accepted", "id": ids[0] },
+                { "index": 1, "status": "rejected", "reason": "record 1: values must be an array of numbers" },
+                { "index": 2, "status": "accepted", "id": ids[1] },
+                { "index": 3, "status": "rejected", "reason": "record 3: time must be an RFC 3339 string" },
+            ],
+        }));
+
+        // Without best-effort mode the same batch is rejected as a whole.
+        let req = TestRequest::post().uri("/project/a/data")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(r#"[{"values": [1]}, {"values": "x"}]"#)
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+        let req = TestRequest::post().uri("/project/a/data?mode=best_effort").set_payload("1").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_get_null_as() {
+        let root = std::env::temp_dir().join(format!("zeta-querier-null-as-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        std::fs::create_dir_all(root.join("b")).unwrap();
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        // The first rows were padded when later ones added a column.
+        conn.execute_batch(&format!(
+            "INSTALL parquet; LOAD parquet;
+             COPY (SELECT TIMESTAMP '2023-01-01 00:00:00' AS time, 1.0 AS f0, CAST(NULL AS DOUBLE) AS f1, 3.0 AS f2) TO '{}' (FORMAT 'parquet');
+             COPY (SELECT TIMESTAMP '2023-01-01 00:00:00' AS time, CAST(NULL AS DOUBLE) AS rh, 1.0 AS temp) TO '{}' (FORMAT 'parquet');",
+            root.join("a").join("default").display(),
+            root.join("b").join("default").display(),
+        )).unwrap();
+        let pool = test_pool().await;
+        let rows = vec![(Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap(), "4, , 6".to_string())];
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+        let req = TestRequest::post().uri("/project/b/data").set_payload(r#"{"rh": null, "temp": 2}"#).to_request();
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(ParquetArchive { root: Some(root.clone()) }))
+        ).await;
+        assert_eq!(call_service(&app, req).await.status(), 201);
+        let payloads = |uri: String| {
+            let req = TestRequest::get().uri(&uri).to_request();
+            let app = &app;
+            async move {
+                let body: serde_json::Value = read_body_json(call_service(app, req).await).await;
+                body.as_array().unwrap().iter().map(|r| r["payload"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+            }
+        };
+
+        for (null_as, expected) in [
+            ("", ["1, , 3", "4, , 6"]),
+            ("&null_as=null", ["1, , 3", "4, , 6"]),
+            ("&null_as=omit", ["1, , 3", "4, , 6"]),
+            ("&null_as=NA", ["1, NA, 3", "4, NA, 6"]),
+        ] {
+            for (source, expected) in [("parquet", &expected[..1]), ("wal", &expected[1..])] {
+                assert_eq!(payloads(format!("/project/a/data?source={}{}", source, null_as)).await, expected, "{} {}", source, null_as);
+            }
+        }
+        for (null_as, expected) in [
+            ("", [r#"{"rh":null,"temp":1.0}"#, r#"{"rh": null, "temp": 2}"#]),
+            ("&null_as=omit", [r#"{"temp":1.0}"#, r#"{"temp":2}"#]),
+            ("&null_as=NA", [r#"{"rh":"NA","temp":1.0}"#, r#"{"rh":"NA","temp":2}"#]),
+        ] {
+            for (source, expected) in [("parquet", &expected[..1]), ("wal", &expected[1..])] {
+                assert_eq!(payloads(format!("/project/b/data?source={}{}", source, null_as)).await, expected, "{} {}", source, null_as);
+            }
+        }
+
+        let req = TestRequest::get().uri("/project/a/data?format=parquet&null_as=NA").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_get_msgpack() {
+        let pool = test_pool().await;
+        let rows = vec![
+            (Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(), "1, 2".to_string()),
+            (Utc.with_ymd_and_hms(2023, 1, 2, 0, 0, 0).unwrap(), "3".to_string()),
+        ];
+        save_batch_to_db(&pool, "a", DEFAULT_SCHEMA, None, rows, &IngestOptions::default(), None).await.unwrap();
+        let app = init_service(test_app(pool)).await;
+        let get = |accept: Option<&str>| {
+            let req = TestRequest::get().uri("/project/a/data?include_stats=true");
+            match accept {
+                Some(accept) => req.insert_header(("Accept", accept)).to_request(),
+                None => req.to_request(),
+            }
+        };
+
+        let json: serde_json::Value = read_body_json(call_service(&app, get(None)).await).await;
+        assert_eq!(json["rows"].as_array().unwrap().len(), 2);
+        for accept in ["application/msgpack", "application/json;q=0.5, application/msgpack"] {
+            let resp = call_service(&app, get(Some(accept))).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get("content-type").unwrap(), MSGPACK_CONTENT_TYPE);
+            let body = actix_web::test::read_body(resp).await;
+            assert_eq!(rmp_serde::from_slice::<serde_json::Value>(&body).unwrap(), json, "{}", accept);
+        }
+        for accept in ["application/json", "*/*", "text/csv, */*;q=0.1"] {
+            let resp = call_service(&app, get(Some(accept))).await;
+            assert_eq!(resp.headers().get("content-type").unwrap(), "application/json", "{}", accept);
+        }
+        assert_eq!(call_service(&app, get(Some("text/csv"))).await.status(), 406);
+    }
+
+    #[actix_web::test]
+    async fn test_get_ts_format() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+        let req = TestRequest::post().uri("/project/a/data")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(r#"{"time": "2024-01-02T03:04:05.678901234Z", "values": [1]}"#)
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+
+        for (format, expected) in [
+            ("", serde_json::json!("2024-01-02T03:04:05.678901234+00:00")),
+            ("&ts_format=rfc3339", serde_json::json!("2024-01-02T03:04:05.678901234+00:00")),
+            ("&ts_format=epoch_ms", serde_json::json!(1704164645678i64)),
+            ("&ts_format=epoch_ns", serde_json::json!(1704164645678901234i64)),
+        ] {
+            let req = TestRequest::get().uri(&format!("/project/a/data?from=2024-01-01T00:00:00Z{}", format)).to_request();
+            let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+            assert_eq!(body[0]["time"], expected, "{}", format);
+        }
+
+        let req = TestRequest::get().uri("/project/a/data?ts_format=epoch_s").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_health_and_ready() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+
+        let req = TestRequest::get().uri("/health").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "status": "ok" }));
+        let req = TestRequest::get().uri("/ready").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        pool.close().await;
+        let req = TestRequest::get().uri("/ready").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 503);
+        let req = TestRequest::get().uri("/health").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_persister_health() {
+        let pool = test_pool().await;
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(PersisterHealth::default()))
+        ).await;
+        let health = || TestRequest::get().uri("/persister-health").to_request();
+        assert_eq!(call_service(&app, health()).await.status(), 503);
+
+        let beat = |at: DateTime<Utc>| sqlx::query(
+            "INSERT INTO heartbeat (id, last_cycle_at, last_batch_rows) VALUES (1, ?1, 4)
+             ON CONFLICT (id) DO UPDATE SET last_cycle_at = excluded.last_cycle_at"
+        ).bind(at.to_rfc3339());
+        beat(Utc::now()).execute(&pool).await.unwrap();
+        let resp = call_service(&app, health()).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["healthy"], true);
+        assert_eq!(body["last_batch_rows"], 4);
+
+        beat(Utc::now() - chrono::Duration::seconds(120)).execute(&pool).await.unwrap();
+        let resp = call_service(&app, health()).await;
+        assert_eq!(resp.status(), 503);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["healthy"], false);
+    }
+
+    #[actix_web::test]
+    async fn test_file_backed_wal() {
+        let root = std::env::temp_dir().join(format!("zeta-querier-wal-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let location = WalLocation::File(root.join("wal.sqlite"));
+        let pool = connect_wal(&location).await.unwrap();
+        initialize_database(&pool).await.unwrap();
+        let app = init_service(test_app(pool.clone())).await;
+        let req = TestRequest::post().uri("/project/a/data").set_payload("1, 2").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+
+        let reader = SqlitePool::connect(&format!("sqlite://{}", root.join("wal.sqlite").display())).await.unwrap();
+        let row = sqlx::query("SELECT project_id, payload FROM wal").fetch_one(&reader).await.unwrap();
+        assert_eq!(row.get::<String, _>("project_id"), "a");
+        assert_eq!(row.get::<String, _>("payload"), "1, 2");
+        let mode: String = sqlx::query("PRAGMA journal_mode").fetch_one(&reader).await.unwrap().get(0);
+        assert_eq!(mode, "wal");
+
+        reader.close().await;
+        pool.close().await;
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_post_schema() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+
+        for (uri, status) in [
+            ("/project/a/data", 201),
+            ("/project/a/data?schema=temps.parquet", 201),
+            ("/project/a/data?schema=..", 400),
+            ("/project/a/data?schema=x%2Fy", 400),
+        ] {
+            let req = TestRequest::post().uri(uri).set_payload("1").to_request();
+            assert_eq!(call_service(&app, req).await.status(), status, "{}", uri);
+        }
+        let schemas: Vec<String> = sqlx::query("SELECT schema FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap()
+            .iter().map(|row| row.get("schema")).collect();
+        assert_eq!(schemas, vec!["default", "temps.parquet"]);
+    }
+
+    #[actix_web::test]
+    async fn test_post_csv() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+        let post = |query: &str, body: &str| TestRequest::post().uri(&format!("/project/a/data/csv{}", query))
+            .insert_header(("Content-Type", "text/csv"))
+            .set_payload(body.to_string())
+            .to_request();
+        let rows = || async {
+            sqlx::query("SELECT time, payload FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap()
+                .iter().map(|row| (row.get::<String, _>("time"), row.get::<String, _>("payload"))).collect::<Vec<_>>()
+        };
+
+        let resp = call_service(&app, post("", "time,cpu,mem\n2023-01-01T00:00:00Z,0.5,2\n2023-01-01T00:01:00Z,,3\n")).await;
+        assert_eq!(resp.status(), 201);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "accepted": 2, "dropped": 0 }));
+        assert_eq!(rows().await, vec![
+            ("2023-01-01T00:00:00+00:00".to_string(), r#"{"cpu":0.5,"mem":2.0}"#.to_string()),
+            ("2023-01-01T00:01:00+00:00".to_string(), r#"{"mem":3.0}"#.to_string()),
+        ]);
+        // The persister reads the header's names as the field names.
+        let (values, names) = common::parse_fields(&rows().await[0].1, FieldOrder::AsReceived).unwrap();
+        assert_eq!(values, vec![0.5, 2.0]);
+        assert_eq!(names, Some(vec!["cpu".to_string(), "mem".to_string()]));
+
+        // Quoted header names and cells, with commas and quotes inside, under
+        // a time column of another name.
+        let csv = "\"cpu, total\",\"ts\",\"say \"\"hi\"\"\"\n\"1.5\",\"2023-01-02T00:00:00Z\",\" 7 \"\n";
+        assert_eq!(call_service(&app, post("?time_column=ts", csv)).await.status(), 201);
+        assert_eq!(rows().await[2], ("2023-01-02T00:00:00+00:00".to_string(), r#"{"cpu, total":1.5,"say \"hi\"":7.0}"#.to_string()));
+
+        // A row of the wrong arity rejects the batch, or only itself in
+        // best-effort mode.
+        let short = "time,cpu,mem\n2023-01-03T00:00:00Z,1,2\n2023-01-03T00:01:00Z,3\n";
+        let resp = call_service(&app, post("", short)).await;
+        assert_eq!(resp.status(), 400);
+        assert_eq!(actix_web::test::read_body(resp).await, "record 1: expected 3 fields, found 2");
+        assert_eq!(rows().await.len(), 3);
+        let resp = call_service(&app, post("?mode=best_effort", short)).await;
+        assert_eq!(resp.status(), 201);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!((body["accepted"].clone(), body["rejected"].clone()), (serde_json::json!(1), serde_json::json!(1)));
+        assert_eq!(rows().await.len(), 4);
+
+        for malformed in ["cpu,mem\n1,2\n", "time,cpu,cpu\n,1,2\n", "time,,mem\n,1,2\n", "time,cpu\nyesterday,1\n", "time,cpu\n,high\n", "time,cpu\n2023-01-03T00:00:00Z,\n"] {
+            assert_eq!(call_service(&app, post("", malformed)).await.status(), 400, "{}", malformed);
+        }
+        assert_eq!(rows().await.len(), 4);
+    }
+
+    #[actix_web::test]
+    async fn test_post_batch() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+        let post = |body: &str| TestRequest::post().uri("/project/a/data")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body.to_string())
+            .to_request();
+        let rows = || async {
+            sqlx::query("SELECT time, payload FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap()
+                .iter().map(|row| (row.get::<String, _>("time"), row.get::<String, _>("payload"))).collect::<Vec<_>>()
+        };
+
+        let resp = call_service(&app, post("[]")).await;
+        assert_eq!(resp.status(), 201);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "accepted": 0, "dropped": 0 }));
+        assert!(rows().await.is_empty());
+
+        let resp = call_service(&app, post(r#"[{"time": "2023-01-01T00:00:00Z", "values": [1.5, 2]}]"#)).await;
+        assert_eq!(resp.status(), 201);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "accepted": 1, "dropped": 0 }));
+        assert_eq!(rows().await, vec![("2023-01-01T00:00:00+00:00".to_string(), "1.5, 2".to_string())]);
+
+        for malformed in [
+            r#"[{"time": "2023-01-02T00:00:00Z", "values": [3]}, {"time": "yesterday", "values": [4]}]"#,
+            r#"[{"time": "2023-01-02T00:00:00Z", "values": ["3"]}]"#,
+            r#"[{"time": "2023-01-02T00:00:00Z", "values": []}]"#,
+            r#""1, 2""#,
+        ] {
+            let resp = call_service(&app, post(malformed)).await;
+            assert_eq!(resp.status(), 400, "{}", malformed);
+        }
+        assert_eq!(rows().await.len(), 1);
+
+        let req = TestRequest::post().uri("/project/a/data").insert_header(("Content-Type", "text/plain")).set_payload("5, 6").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+        assert_eq!(rows().await[1].1, "5, 6");
+    }
+
+    #[actix_web::test]
+    async fn test_post_field_count() {
+        let pool = test_pool().await;
+        let app = init_service(test_app(pool.clone())).await;
+
+        for (count, payload, status) in [
+            (Some("3"), "1, 2, 3", 201),
+            (Some("3"), "[1, 2, 3]", 201),
+            (Some("3"), "1, 2", 400),
+            (Some("2"), "[1, 2, 3]", 400),
+            (Some("two"), "1, 2", 400),
+            (None, "1, 2", 201),
+            (None, "1, 2, 3, 4", 201),
+        ] {
+            let mut req = TestRequest::post().uri("/project/a/data").set_payload(payload);
+            if let Some(count) = count {
+                req = req.insert_header(("X-Field-Count", count));
+            }
+            assert_eq!(call_service(&app, req.to_request()).await.status(), status, "{:?} {}", count, payload);
+        }
+
+        let req = TestRequest::post().uri("/project/a/data")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("X-Field-Count", "2"))
+            .set_payload(r#"[{"values": [1, 2]}, {"values": [3]}]"#)
+            .to_request();
+        assert_eq!(call_and_read_body(&app, req).await, "expected 2 values per record, found 1");
+
+        let stored = sqlx::query("SELECT COUNT(*) FROM wal").fetch_one(&pool).await.unwrap().get::<i64, _>(0);
+        assert_eq!(stored, 4);
+    }
+
+    #[test]
+    fn test_parse_batch_skew() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let body = |time: &str| format!(r#"{{"time": "{}", "values": [1]}}"#, time);
+        let times = ["2024-01-01T12:01:00Z", "2024-01-01T13:00:00Z", "2024-01-01T10:00:00Z"];
+
+        // Neither bound is checked by default.
+        for time in times {
+            assert!(parse_batch(body(time).as_bytes(), &IngestOptions::default(), now).unwrap()[0].is_ok());
+        }
+
+        let options = IngestOptions {
+            max_future_skew: Some(Duration::from_secs(300)),
+            max_past_skew: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let parsed: Vec<Result<BatchRow, String>> = times.iter()
+            .map(|time| parse_batch(body(time).as_bytes(), &options, now).unwrap().remove(0))
+            .collect();
+        assert_eq!(parsed[0], Ok((Utc.with_ymd_and_hms(2024, 1, 1, 12, 1, 0).unwrap(), "1".to_string())));
+        assert_eq!(parsed[1], Err("record 0: time 2024-01-01T13:00:00+00:00 is 3600s in the future, more than 300s".to_string()));
+        assert_eq!(parsed[2], Err("record 0: time 2024-01-01T10:00:00+00:00 is 7200s in the past, more than 3600s".to_string()));
+    }
+
+    #[actix_web::test]
+    async fn test_post_field_order() {
+        for (order, expected) in [(FieldOrder::Alphabetical, r#"{"cpu":2,"mem":1}"#), (FieldOrder::AsReceived, r#"{"mem": 1, "cpu": 2}"#)] {
+            let pool = test_pool().await;
+            let app = init_service(
+                test_app(pool.clone()).app_data(web::Data::new(IngestOptions { field_order: order, ..Default::default() }))
+            ).await;
+            for payload in [r#"{"mem": 1, "cpu": 2}"#, r#"{"cpu": 3, "mem": 4}"#] {
+                let req = TestRequest::post().uri("/project/a/data").set_payload(payload).to_request();
+                assert_eq!(call_service(&app, req).await.status(), 201);
+            }
+            let stored: Vec<String> = sqlx::query("SELECT payload FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap()
+                .iter().map(|row| row.get("payload")).collect();
+            assert_eq!(stored, vec![expected, r#"{"cpu": 3, "mem": 4}"#], "{:?}", order);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_field_aliases() {
+        let aliases = parse_field_aliases(r#"{"a": {"temperature": "temp", "humidity": "rh"}}"#).unwrap();
+        for (unknown, expected) in [
+            (UnknownFields::Keep, Some(r#"{"temp":20.5,"rh":0.4,"wind":3}"#)),
+            (UnknownFields::Ignore, Some(r#"{"temp":20.5,"rh":0.4}"#)),
+            (UnknownFields::Reject, None),
+        ] {
+            let pool = test_pool().await;
+            let options = IngestOptions { field_aliases: aliases.clone(), unknown_fields: unknown, field_order: FieldOrder::AsReceived, ..Default::default() };
+            let app = init_service(test_app(pool.clone()).app_data(web::Data::new(options))).await;
+            let post = |project: &str, payload: &str| TestRequest::post().uri(&format!("/project/{}/data", project)).set_payload(payload.to_string()).to_request();
+            let stored = || async {
+                sqlx::query("SELECT payload FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap()
+                    .iter().map(|row| row.get::<String, _>("payload")).collect::<Vec<_>>()
+            };
+
+            // A mapped name lands in the column of its schema name, and a
+            // schema name is known as it is.
+            assert_eq!(call_service(&app, post("a", r#"{"temperature": 20, "rh": 0.3}"#)).await.status(), 201);
+            assert_eq!(stored().await[0], r#"{"temp":20,"rh":0.3}"#);
+            let (_, names) = common::parse_fields(&stored().await[0], FieldOrder::AsReceived).unwrap();
+            assert_eq!(names, Some(vec!["temp".to_string(), "rh".to_string()]));
+
+            let resp = call_service(&app, post("a", r#"{"temperature": 20.5, "humidity": 0.4, "wind": 3}"#)).await;
+            match expected {
+                Some(expected) => {
+                    assert_eq!(resp.status(), 201, "{:?}", unknown);
+                    assert_eq!(stored().await[1], expected, "{:?}", unknown);
+                }
+                None => {
+                    assert_eq!(resp.status(), 400);
+                    assert_eq!(actix_web::test::read_body(resp).await, r#"unknown field "wind""#);
+                    assert_eq!(stored().await.len(), 1);
+                }
+            }
+
+            // A name given both as sent and through its alias is refused.
+            assert_eq!(call_service(&app, post("a", r#"{"temperature": 1, "temp": 2}"#)).await.status(), 400);
+            // CSV and JSON batch records are aliased too, and projects
+            // without aliases or payloads without names are left alone.
+            let csv = TestRequest::post().uri("/project/a/data/csv").set_payload("time,temperature\n2023-01-01T00:00:00Z,5\n").to_request();
+            assert_eq!(call_service(&app, csv).await.status(), 201);
+            assert_eq!(stored().await.last().unwrap(), r#"{"temp":5.0}"#);
+            assert_eq!(call_service(&app, post("b", r#"{"temperature": 1, "wind": 2}"#)).await.status(), 201);
+            assert_eq!(call_service(&app, post("a", "1, 2")).await.status(), 201);
+            assert_eq!(stored().await[stored().await.len() - 2..], [r#"{"temperature": 1, "wind": 2}"#, "1, 2"]);
+        }
+        assert!(parse_field_aliases(r#"{"a": {"temperature": ""}}"#).is_err());
+        assert!(parse_field_aliases(r#"{"a": ["temp"]}"#).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_post_record_time() {
+        let pool = test_pool().await;
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(IngestOptions { max_future_skew: Some(Duration::from_secs(60)), ..Default::default() }))
+        ).await;
+        let post = |body: String| TestRequest::post().uri("/project/a/data")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body)
+            .to_request();
+        let last_row = || async {
+            let row = sqlx::query("SELECT time, created_at FROM wal ORDER BY rowid DESC LIMIT 1").fetch_one(&pool).await.unwrap();
+            let parse = |column| DateTime::parse_from_rfc3339(&row.get::<String, _>(column)).unwrap().with_timezone(&Utc);
+            (parse("time"), parse("created_at"))
+        };
+
+        let before = Utc::now();
+        let resp = call_service(&app, post(r#"{"time": "2023-01-01T09:00:00+09:00", "values": [1]}"#.to_string())).await;
+        assert_eq!(resp.status(), 201);
+        let (time, created_at) = last_row().await;
+        assert_eq!(time, Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap());
+        assert!(created_at >= before);
+
+        let resp = call_service(&app, post(r#"{"values": [2]}"#.to_string())).await;
+        assert_eq!(resp.status(), 201);
+        let (time, created_at) = last_row().await;
+        assert!(time >= before && created_at >= before);
+
+        let soon = (Utc::now() + chrono::Duration::seconds(30)).to_rfc3339();
+        assert_eq!(call_service(&app, post(format!(r#"{{"time": "{}", "values": [3]}}"#, soon))).await.status(), 201);
+        let later = (Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let resp = call_service(&app, post(format!(r#"[{{"values": [4]}}, {{"time": "{}", "values": [5]}}]"#, later))).await;
+        assert_eq!(resp.status(), 400);
+        let count: i64 = sqlx::query("SELECT COUNT(*) AS n FROM wal").fetch_one(&pool).await.unwrap().get("n");
+        assert_eq!(count, 3);
+    }
+
+    #[actix_web::test]
+    async fn test_read_only() {
+        let app = init_service(
+            test_app(test_pool().await)
+                .app_data(web::Data::new(ApiKeys { admin_token: Some("admin".to_string()), required: false }))
+                .app_data(web::Data::new(ReadOnly(AtomicBool::new(true))))
+        ).await;
+        let write = || TestRequest::post().uri("/project/a/data").set_payload("1").to_request();
+        let read = || TestRequest::get().uri("/project/a/data").to_request();
+        let admin = |path: &str| TestRequest::default().uri(path).insert_header(("Authorization", "Bearer admin"));
+
+        assert_eq!(call_service(&app, write()).await.status(), 503);
+        for path in ["/admin/projects/a/keys", "/project/a/pause", "/project/a/resume"] {
+            assert_eq!(call_service(&app, admin(path).method(actix_web::http::Method::POST).to_request()).await.status(), 503, "{}", path);
+        }
+        assert_eq!(call_service(&app, read()).await.status(), 200);
+
+        let req = TestRequest::put().uri("/admin/read-only").set_payload("false").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+        let req = admin("/admin/read-only").method(actix_web::http::Method::PUT).set_payload("false").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+        assert_eq!(call_service(&app, write()).await.status(), 201);
+        assert_eq!(call_service(&app, read()).await.status(), 200);
+        let req = admin("/project/a/pause").method(actix_web::http::Method::POST).to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_wal_preview() {
+        let pool = test_pool().await;
+        for payload in ["1, x, 3", r#"{"mem": 2, "cpu": 0.5}"#] {
+            sqlx::query("INSERT INTO wal (project_id, schema, time, created_at, payload) VALUES ('a', 'default', ?1, ?1, ?2)")
+                .bind(Utc::now().to_rfc3339()).bind(payload)
+                .execute(&pool).await.unwrap();
+        }
+        let app = init_service(
+            test_app(pool)
+                .app_data(web::Data::new(ApiKeys { admin_token: Some("admin".to_string()), required: false }))
+        ).await;
+        let preview = |rowid: i64| TestRequest::get().uri(&format!("/wal/{}/preview", rowid)).insert_header(("Authorization", "Bearer admin")).to_request();
+
+        let resp = call_service(&app, preview(1)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["payload"], "1, x, 3");
+        assert_eq!(body["fields"], serde_json::json!([]));
+        assert_eq!(body["error"], "failed to parse value 'x'");
+
+        let body: serde_json::Value = read_body_json(call_service(&app, preview(2)).await).await;
+        assert_eq!(body["error"], serde_json::Value::Null);
+        assert_eq!(body["fields"], serde_json::json!([
+            { "name": "cpu", "type": "DOUBLE", "value": 0.5 },
+            { "name": "mem", "type": "DOUBLE", "value": 2.0 },
+        ]));
+
+        assert_eq!(call_service(&app, preview(3)).await.status(), 404);
+        let req = TestRequest::get().uri("/wal/1/preview").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_pause_project() {
+        let pool = test_pool().await;
+        let app = init_service(
+            test_app(pool.clone())
+                .app_data(web::Data::new(ApiKeys { admin_token: Some("admin".to_string()), required: false }))
+        ).await;
+        let admin = |path: &str| TestRequest::post().uri(path).insert_header(("Authorization", "Bearer admin")).to_request();
+        let paused = || async {
+            sqlx::query("SELECT project_id FROM paused_projects ORDER BY project_id").fetch_all(&pool).await.unwrap()
+                .iter().map(|row| row.get::<String, _>("project_id")).collect::<Vec<_>>()
+        };
+
+        let req = TestRequest::post().uri("/project/a/pause").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+        assert!(paused().await.is_empty());
+
+        for path in ["/project/a/pause", "/project/a/pause", "/project/b/pause"] {
+            let resp = call_service(&app, admin(path)).await;
+            assert_eq!(resp.status(), 200);
+        }
+        assert_eq!(paused().await, vec!["a", "b"]);
+        for path in ["/project/.a/pause", "/project/a;b/resume"] {
+            assert_eq!(call_service(&app, admin(path)).await.status(), 400);
+        }
+        assert_eq!(paused().await, vec!["a", "b"]);
+
+        let resp = call_service(&app, admin("/project/a/resume")).await;
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!({ "project_id": "a", "paused": false }));
+        assert_eq!(paused().await, vec!["b"]);
+    }
+
+    #[actix_web::test]
+    async fn test_payload_compression() {
+        let pool = test_pool().await;
+        let compression = IngestOptions { compress_threshold: Some(64), ..Default::default() };
+        let large = "1.25, 2.5, 3.75, ".repeat(50);
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, large.clone(), &compression).await.unwrap();
+        save_to_db(&pool, "a".to_string(), DEFAULT_SCHEMA, None, "1, 2".to_string(), &compression).await.unwrap();
+
+        let rows = sqlx::query("SELECT * FROM wal ORDER BY rowid").fetch_all(&pool).await.unwrap();
+        assert_eq!(rows[0].get::<i64, _>("compressed"), 1);
+        assert!(rows[0].get::<Vec<u8>, _>("payload").len() < large.len());
+        assert_eq!(read_payload(&rows[0]).unwrap(), large);
+        assert_eq!(rows[1].get::<i64, _>("compressed"), 0);
+        assert_eq!(read_payload(&rows[1]).unwrap(), "1, 2");
+    }
+
+    #[actix_web::test]
+    async fn test_empty_records() {
+        for (allow_empty_records, status) in [(false, 400), (true, 201)] {
+            let app = init_service(
+                test_app(test_pool().await)
+                    .app_data(web::Data::new(IngestOptions { allow_empty_records, ..Default::default() }))
+            ).await;
+
+            let req = TestRequest::post().uri("/project/a/data").set_payload("  ").to_request();
+            assert_eq!(call_service(&app, req).await.status(), status);
+            let req = TestRequest::post().uri("/project/a/data").set_payload("1").to_request();
+            assert_eq!(call_service(&app, req).await.status(), 201);
+        }
+    }
 }